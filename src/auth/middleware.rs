@@ -0,0 +1,231 @@
+//! The middleware protecting the API routes.
+
+use std::cell::RefCell;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use actix::Addr;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::http::Method;
+use actix_web::{Error, HttpMessage, ResponseError};
+use futures::future::{ok, Future, Ready};
+
+use crate::auth::session::SESSION_COOKIE;
+use crate::auth::AuthenticatedUser;
+use crate::error::ServiceError;
+use crate::services::{Authenticate, Authentication as AuthenticationResult, Credentials, UserService};
+
+/// Requires authentication (HTTP Basic, session cookie or API token) of one of the users for all
+/// `/api/` routes and the `/ws` WebSocket. If there are no users, all requests are allowed.
+///
+/// The [AuthenticatedUser] is stored in the extensions of the request (the unrestricted one if the
+/// authentication is disabled). Requests to the API without it are refused by the handlers.
+pub struct Authentication {
+    users: Addr<UserService>,
+}
+
+pub struct AuthenticationMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    users: Addr<UserService>,
+}
+
+impl Authentication {
+    pub fn new(users: Addr<UserService>) -> Self {
+        Self { users }
+    }
+}
+
+impl<S, B> Transform<S> for Authentication
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AuthenticationMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AuthenticationMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            users: self.users.clone(),
+        })
+    }
+}
+
+impl<S, B> Service for AuthenticationMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let users = self.users.clone();
+
+        Box::pin(async move {
+            if !requires_authentication(&req) {
+                let fut = service.borrow_mut().call(req);
+
+                return fut.await;
+            }
+
+            let authentication = match users.send(Authenticate(credentials(&req))).await {
+                Ok(authentication) => authentication,
+                Err(error) => return Ok(req.into_response(ServiceError::from(error).error_response().into_body())),
+            };
+
+            match authentication {
+                AuthenticationResult::Disabled => {
+                    req.extensions_mut().insert(AuthenticatedUser::unrestricted());
+                }
+                AuthenticationResult::User(user) => {
+                    req.extensions_mut().insert(user);
+                }
+                AuthenticationResult::Denied => {
+                    return Ok(req.into_response(ServiceError::Unauthorized.error_response().into_body()));
+                }
+            }
+
+            let fut = service.borrow_mut().call(req);
+
+            fut.await
+        })
+    }
+}
+
+/// All API routes and the WebSocket require authentication, except CORS preflight requests and
+/// the login.
+///
+/// The routes are matched on the path with percent-encoded characters decoded (`/%61pi/user` is
+/// routed to `/api/user`), so the same path has to be checked here.
+fn requires_authentication(req: &ServiceRequest) -> bool {
+    let path = req.match_info().path();
+    let login = path == "/api/session" && req.method() == Method::POST;
+    let protected = path == "/api" || path.starts_with("/api/") || path == "/ws";
+
+    protected && req.method() != Method::OPTIONS && !login
+}
+
+/// Extracts the credentials from the `Authorization` header or the session cookie
+fn credentials(req: &ServiceRequest) -> Credentials {
+    let header = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok());
+
+    if let Some(header) = header {
+        if let Some(token) = header.strip_prefix("Bearer ") {
+            return Credentials::Token(String::from(token.trim()));
+        }
+
+        if let Some((user, password)) = header.strip_prefix("Basic ").and_then(basic_credentials) {
+            return Credentials::Basic(user, password);
+        }
+    }
+
+    match req.cookie(SESSION_COOKIE) {
+        Some(cookie) => Credentials::Session(String::from(cookie.value())),
+        None => Credentials::None,
+    }
+}
+
+/// Decodes user and password of the `Authorization: Basic ...` header
+fn basic_credentials(encoded: &str) -> Option<(String, String)> {
+    let decoded = String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?;
+    let mut parts = decoded.splitn(2, ':');
+
+    Some((String::from(parts.next()?), String::from(parts.next()?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix::Actor;
+    use actix_web::rt::System;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::web::Bytes;
+    use actix_web::{web, App, HttpRequest, HttpResponse};
+
+    use super::*;
+    use crate::auth::Role;
+    use crate::services::UserData;
+    use crate::settings::AuthSettings;
+    use crate::storage::memory::MemoryStorage;
+    use crate::storage::{put_entry, USERS};
+
+    /// Answers with the name of the user attached to the request
+    async fn user_name(req: HttpRequest) -> HttpResponse {
+        match req.extensions().get::<AuthenticatedUser>() {
+            Some(user) => HttpResponse::Ok().body(user.name.clone()),
+            None => HttpResponse::InternalServerError().finish(),
+        }
+    }
+
+    /// The status and body of a request without credentials to an app with the given users
+    fn request(users: &'static [&'static str], method: Method, path: &'static str) -> (StatusCode, Bytes) {
+        System::new("authentication-test").block_on(async move {
+            let storage = Arc::new(MemoryStorage::new());
+
+            for user in users {
+                put_entry(&*storage, &USERS, user, &UserData::new(String::from("password"), Role::Admin)).unwrap();
+            }
+
+            let users = UserService::new(storage, AuthSettings::default()).start();
+            let mut app = init_service(
+                App::new()
+                    .wrap(Authentication::new(users))
+                    .route("/api/user", web::get().to(user_name))
+                    .route("/api/session", web::post().to(HttpResponse::Ok))
+                    .default_service(web::to(HttpResponse::Ok)),
+            )
+            .await;
+            let response = call_service(&mut app, TestRequest::with_uri(path).method(method).to_request()).await;
+
+            (response.status(), read_body(response).await)
+        })
+    }
+
+    fn status(method: Method, path: &'static str) -> StatusCode {
+        request(&["alice"], method, path).0
+    }
+
+    #[test]
+    fn requires_credentials_for_the_api() {
+        assert_eq!(status(Method::GET, "/api/user"), StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Method::GET, "/ws"), StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Method::POST, "/api/session"), StatusCode::OK);
+        assert_eq!(status(Method::OPTIONS, "/api/user"), StatusCode::OK);
+        assert_eq!(status(Method::GET, "/index.html"), StatusCode::OK);
+    }
+
+    #[test]
+    fn requires_credentials_for_percent_encoded_api_paths() {
+        assert_eq!(status(Method::GET, "/%61pi/user"), StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Method::GET, "/%61%70%69/user"), StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Method::GET, "/api/%75ser"), StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Method::GET, "/%77s"), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn attaches_the_unrestricted_user_while_disabled() {
+        assert_eq!(request(&[], Method::GET, "/api/user"), (StatusCode::OK, Bytes::new()));
+        assert_eq!(request(&["alice"], Method::GET, "/api/user").0, StatusCode::UNAUTHORIZED);
+    }
+}
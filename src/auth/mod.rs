@@ -0,0 +1,67 @@
+//! Authentication of requests to the API
+//!
+//! Requests are authenticated with HTTP Basic, a session cookie (see [session::SessionSigner]) or
+//! an API token (`Authorization: Bearer <token>`). Passwords are never stored in plain text, see
+//! [password::hash_password].
+//!
+//! What a user may do depends on its [Role]. Dashboards and shortcuts can additionally be
+//! restricted to a list of users, see [AuthenticatedUser::can_see].
+
+use serde::{Deserialize, Serialize};
+
+pub mod middleware;
+pub mod password;
+pub mod session;
+
+/// The role of a user
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Can do everything, including managing the users.
+    /// This is the default, as users created before roles existed had full access.
+    #[default]
+    Admin,
+
+    /// Can see and change all dashboards, groups, shortcuts and rules
+    Editor,
+
+    /// Can only see dashboards (with their groups) and run shortcuts visible to it
+    Viewer,
+}
+
+/// The user a request was authenticated as. It is stored in the extensions of the request.
+#[derive(Clone, Debug)]
+pub struct AuthenticatedUser {
+    pub name: String,
+    pub role: Role,
+}
+
+impl AuthenticatedUser {
+    /// Used when authentication is disabled (there are no users). It has no name, so it is not
+    /// mistaken for one of the users.
+    pub fn unrestricted() -> Self {
+        Self {
+            name: String::new(),
+            role: Role::Admin,
+        }
+    }
+
+    pub fn is_unrestricted(&self) -> bool {
+        self.name.is_empty()
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.role == Role::Admin
+    }
+
+    /// Editors and admins may change dashboards, groups, shortcuts and rules
+    pub fn can_edit(&self) -> bool {
+        self.role != Role::Viewer
+    }
+
+    /// Checks the visibility list of a dashboard or shortcut. An empty list is visible to
+    /// everyone, editors and admins see everything.
+    pub fn can_see(&self, visible_to: &[String]) -> bool {
+        self.can_edit() || visible_to.is_empty() || visible_to.contains(&self.name)
+    }
+}
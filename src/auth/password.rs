@@ -0,0 +1,101 @@
+//! Hashing and verification of passwords.
+//!
+//! Hashes are stored as `$pbkdf2-sha256$<iterations>$<salt>$<hash>` (salt and hash base64 encoded).
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use hmac::Hmac;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+const PREFIX: &str = "$pbkdf2-sha256$";
+const ITERATIONS: u32 = 10_000;
+
+/// Hashes the password with a random salt
+pub fn hash_password(password: &str) -> String {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+
+    let hash = derive(password, &salt, ITERATIONS);
+
+    format!("{}{}${}${}", PREFIX, ITERATIONS, base64::encode(salt), base64::encode(hash))
+}
+
+/// Checks if the string is a hash created by [hash_password]
+pub fn is_password_hash(value: &str) -> bool {
+    value.starts_with(PREFIX)
+}
+
+/// Checks the password against the hash
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    let mut parts = hash.trim_start_matches(PREFIX).split('$');
+
+    let (iterations, salt, expected) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(iterations), Some(salt), Some(expected)) => (iterations, salt, expected),
+        _ => return false,
+    };
+
+    let (iterations, salt, expected) = match (
+        iterations.parse::<u32>(),
+        base64::decode(salt),
+        base64::decode(expected),
+    ) {
+        (Ok(iterations), Ok(salt), Ok(expected)) => (iterations, salt, expected),
+        _ => return false,
+    };
+
+    constant_time_eq(&derive(password, &salt, iterations), &expected)
+}
+
+fn derive(password: &str, salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(password.as_bytes(), salt, iterations, &mut hash);
+
+    hash
+}
+
+/// Compares both slices without returning early
+pub fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    if left.len() != right.len() {
+        return false;
+    }
+
+    left.iter().zip(right).fold(0u8, |result, (left, right)| result | (left ^ right)) == 0
+}
+
+/// Remembers successfully verified credentials, so the (intentionally slow) hash only has to be
+/// computed once and not on every request.
+#[derive(Default)]
+pub struct CredentialCache {
+    verified: Mutex<HashSet<Vec<u8>>>,
+}
+
+impl CredentialCache {
+    /// Verifies the password against the hash
+    pub fn verify(&self, password: &str, hash: &str) -> bool {
+        let key = Sha256::new()
+            .chain(hash.as_bytes())
+            .chain([0u8])
+            .chain(password.as_bytes())
+            .finalize()
+            .to_vec();
+
+        if let Ok(verified) = self.verified.lock() {
+            if verified.contains(&key) {
+                return true;
+            }
+        }
+
+        if !verify_password(password, hash) {
+            return false;
+        }
+
+        if let Ok(mut verified) = self.verified.lock() {
+            verified.insert(key);
+        }
+
+        true
+    }
+}
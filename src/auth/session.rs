@@ -0,0 +1,197 @@
+//! Signed session cookies and API tokens.
+//!
+//! A session is stored completely in the cookie as `<payload>.<signature>`, where the payload
+//! contains the expiry, a random id (used for logging out) and the user name. The signature is a
+//! HMAC-SHA256 with the `auth.session_secret` of the `settings.yaml`.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac, NewMac};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+/// The name of the session cookie
+pub const SESSION_COOKIE: &str = "new_home_session";
+
+/// The prefix of all API tokens, so they can be told apart from other secrets
+const TOKEN_PREFIX: &str = "nh_";
+
+/// A verified session
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub user: String,
+    pub id: String,
+    pub expires: u64,
+}
+
+/// Signs and verifies the session cookies
+pub struct SessionSigner {
+    key: Vec<u8>,
+}
+
+impl SessionSigner {
+    pub fn new(secret: &str) -> Self {
+        Self { key: secret.as_bytes().to_vec() }
+    }
+
+    /// Creates a new session for the user, valid for `lifetime` seconds
+    pub fn create(&self, user: &str, lifetime: u64) -> (Session, String) {
+        let session = Session {
+            user: String::from(user),
+            id: random_string(16),
+            expires: now() + lifetime,
+        };
+        let payload = base64::encode_config(
+            format!("{}|{}|{}", session.expires, session.id, session.user),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let signature = base64::encode_config(self.mac(payload.as_bytes()).finalize().into_bytes(), base64::URL_SAFE_NO_PAD);
+
+        (session, format!("{}.{}", payload, signature))
+    }
+
+    /// Returns the session, if the signature is valid and it did not expire yet
+    pub fn verify(&self, value: &str) -> Option<Session> {
+        let mut parts = value.splitn(2, '.');
+        let payload = parts.next()?;
+        let signature = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+
+        self.mac(payload.as_bytes()).verify(&signature).ok()?;
+
+        let payload = String::from_utf8(base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?).ok()?;
+        let mut parts = payload.splitn(3, '|');
+        let session = Session {
+            expires: parts.next()?.parse().ok()?,
+            id: String::from(parts.next()?),
+            user: String::from(parts.next()?),
+        };
+
+        if session.expires <= now() {
+            return None;
+        }
+
+        Some(session)
+    }
+
+    fn mac(&self, data: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.key).expect("HMAC accepts keys of any size");
+        mac.update(data);
+
+        mac
+    }
+}
+
+/// Creates a new API token. Only its hash (see [token_hash]) is stored.
+pub fn generate_token() -> String {
+    format!("{}{}", TOKEN_PREFIX, random_string(32))
+}
+
+/// The hash of an API token as it is stored. Tokens are random, so a fast hash is sufficient.
+pub fn token_hash(token: &str) -> String {
+    base64::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// A random, url safe string from the given amount of random bytes
+pub fn random_string(bytes: usize) -> String {
+    let mut data = vec![0u8; bytes];
+    rand::thread_rng().fill_bytes(&mut data);
+
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+/// The current unix timestamp in seconds
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(data: impl AsRef<[u8]>) -> String {
+        base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+    }
+
+    #[test]
+    fn verifies_created_sessions() {
+        let signer = SessionSigner::new("secret");
+        let (created, value) = signer.create("alice|admin.x", 60);
+        let session = signer.verify(&value).unwrap();
+
+        assert_eq!(session.user, "alice|admin.x");
+        assert_eq!(session.id, created.id);
+        assert_eq!(session.expires, created.expires);
+    }
+
+    #[test]
+    fn rejects_changed_payloads() {
+        let signer = SessionSigner::new("secret");
+        let (session, value) = signer.create("viewer", 60);
+        let signature = value.split('.').nth(1).unwrap();
+        let changed = |payload: String| format!("{}.{}", encode(payload), signature);
+
+        assert!(signer.verify(&changed(format!("{}|{}|admin", session.expires, session.id))).is_none());
+        assert!(signer.verify(&changed(format!("{}|{}|viewer", session.expires + 3600, session.id))).is_none());
+        assert!(signer.verify(&changed(format!("{}|other|viewer", session.expires))).is_none());
+    }
+
+    #[test]
+    fn rejects_changed_signatures() {
+        let signer = SessionSigner::new("secret");
+        let (_, value) = signer.create("alice", 60);
+        let (payload, signature) = value.split_once('.').unwrap();
+        let mut flipped = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).unwrap();
+
+        flipped[0] ^= 0x01;
+
+        assert!(signer.verify(&format!("{}.{}", payload, encode(&flipped))).is_none());
+        assert!(signer.verify(&format!("{}.{}", payload, &signature[..signature.len() - 2])).is_none());
+        assert!(signer.verify(&format!("{}.", payload)).is_none());
+        assert!(signer.verify(payload).is_none());
+        assert!(signer.verify(&format!("{}.{}!", payload, signature)).is_none());
+    }
+
+    #[test]
+    fn rejects_sessions_of_other_secrets() {
+        let (_, value) = SessionSigner::new("old secret").create("alice", 60);
+
+        assert!(SessionSigner::new("new secret").verify(&value).is_none());
+    }
+
+    #[test]
+    fn rejects_expired_sessions() {
+        let signer = SessionSigner::new("secret");
+        let (_, value) = signer.create("alice", 0);
+
+        assert!(signer.verify(&value).is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_values() {
+        let signer = SessionSigner::new("secret");
+
+        for value in ["", ".", "..", "abc", "abc.def", "%%%.%%%"] {
+            assert!(signer.verify(value).is_none(), "{} should be rejected", value);
+        }
+
+        // Correctly signed, but not a session
+        let payload = encode("not a session");
+        let signature = encode(signer.mac(payload.as_bytes()).finalize().into_bytes());
+
+        assert!(signer.verify(&format!("{}.{}", payload, signature)).is_none());
+    }
+
+    #[test]
+    fn tokens_are_random_and_hashed() {
+        let token = generate_token();
+
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert_ne!(token, generate_token());
+        assert_eq!(token_hash(&token), token_hash(&token));
+        assert_ne!(token_hash(&token), token);
+        assert_ne!(token_hash(&token), token_hash(&generate_token()));
+    }
+}
@@ -0,0 +1,431 @@
+//! The command line interface
+//!
+//! Without a subcommand the server is started (`serve`). The other commands are meant for
+//! scripting and deployment: checking the configuration, exporting and importing all data and
+//! hashing passwords for the YAML files.
+
+use std::fs::read_to_string;
+use std::io::stdin;
+use std::path::{Path, PathBuf};
+
+use clap::{Args, Parser, Subcommand};
+use serde::de::DeserializeOwned;
+use serde_yaml::{Mapping, Value};
+
+use crate::auth::password::hash_password;
+use crate::error::ServiceError;
+use crate::paths::{config_path, data_path};
+use crate::persistence::write_atomic;
+use crate::services::validation::{configure_validation, Validate};
+use crate::services::{DashboardData, GroupData, RuleData, ShortcutEntry, UserData};
+use crate::settings::{AppSettings, ServerType, StorageBackend, StorageSettings, WebSettings};
+use crate::storage::sqlite::SqliteStorage;
+use crate::storage::yaml::YamlStorage;
+use crate::storage::{open_storage, Collection, DASHBOARDS, GROUPS, RULES, SHORTCUTS, USERS};
+
+/// Checks a single entry with its key, returns the error
+type EntryCheck = fn(&str, &Value) -> Option<String>;
+
+/// The collections with the check of their entries
+const CHECKED: [(&Collection, EntryCheck); 5] = [
+    (&DASHBOARDS, validation_error::<DashboardData>),
+    (&GROUPS, validation_error::<GroupData>),
+    (&SHORTCUTS, validation_error::<ShortcutEntry>),
+    (&RULES, validation_error::<RuleData>),
+    (&USERS, entry_error::<UserData>),
+];
+
+/// The server of New Home MQTT. Serves the frontend and its settings, dashboards, groups,
+/// shortcuts and rules.
+#[derive(Parser)]
+#[command(name = "new-home-mqtt-server", version)]
+pub struct Cli {
+    /// The directory of settings.yaml and web_settings.yaml (or NEW_HOME_CONFIG_DIR)
+    #[arg(long, global = true, value_name = "DIR")]
+    pub config: Option<PathBuf>,
+
+    /// The directory of the data files (or NEW_HOME_DATA_DIR)
+    #[arg(long, global = true, value_name = "DIR")]
+    pub data_dir: Option<PathBuf>,
+
+    #[command(flatten)]
+    serve: ServeArgs,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Clone)]
+pub enum Command {
+    /// Starts the server (the default)
+    Serve(ServeArgs),
+
+    /// Parses all settings and data files and validates the entries. Exits with 1 if one of them is invalid
+    CheckConfig,
+
+    /// Exports all dashboards, groups, shortcuts, rules and users into a single YAML file
+    Export {
+        file: PathBuf,
+    },
+
+    /// Replaces the data with an export. Collections missing in the file are not changed
+    Import {
+        file: PathBuf,
+    },
+
+    /// Prints the hash of the password for the YAML files. Reads it from stdin if not given
+    HashPassword {
+        password: Option<String>,
+    },
+}
+
+/// Overrides of the settings, which are not written to settings.yaml
+#[derive(Args, Clone, Default)]
+pub struct ServeArgs {
+    /// Do not read console commands from stdin
+    #[arg(long)]
+    pub no_console: bool,
+
+    /// The host to listen on
+    #[arg(long)]
+    pub host: Option<String>,
+
+    /// The port to listen on
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    /// Proxy the frontend to this URL
+    #[arg(long, value_name = "URL", conflicts_with = "public")]
+    pub proxy: Option<String>,
+
+    /// Serve the frontend from this directory
+    #[arg(long, value_name = "DIR")]
+    pub public: Option<String>,
+}
+
+impl Cli {
+    /// The subcommand to run. Without one, the server is started with the top level options.
+    pub fn command(&self) -> Command {
+        match &self.command {
+            Some(command) => command.clone(),
+            None => Command::Serve(self.serve.clone()),
+        }
+    }
+}
+
+impl ServeArgs {
+    pub fn apply(&self, settings: &mut AppSettings) {
+        if let Some(host) = &self.host {
+            settings.host = host.clone();
+        }
+
+        if let Some(port) = self.port {
+            settings.port = port;
+        }
+
+        if let Some(url) = &self.proxy {
+            settings.server_type = ServerType::Proxy(url.clone());
+        }
+
+        if let Some(directory) = &self.public {
+            settings.server_type = ServerType::File(directory.clone());
+        }
+    }
+}
+
+/// Parses all files, validates their entries like the API and prints the problems. The database
+/// is only read. Returns the exit code.
+pub fn check_config() -> i32 {
+    let mut valid = check_file::<AppSettings>(&config_path("settings.yaml"));
+    valid &= check_file::<WebSettings>(&config_path("web_settings.yaml"));
+
+    let settings: AppSettings = read_yaml(&config_path("settings.yaml")).unwrap_or_default();
+    configure_validation(settings.validation.clone());
+
+    let database = match open_database(&settings.storage) {
+        Ok(database) => database,
+        Err(error) => {
+            eprintln!("[ERROR] [Check]: {}: {}", settings.storage.sqlite_path, error);
+
+            return 1;
+        }
+    };
+
+    for (collection, check) in CHECKED.iter() {
+        let entries = match &database {
+            Some(database) => database_entries(database, collection),
+            None => read_entries(collection, &YamlStorage::path(collection)),
+        };
+        let entries = match entries {
+            Ok(entries) => entries,
+            Err(error) => {
+                eprintln!("[ERROR] [Check]: {}: {}", collection.name, error);
+                valid = false;
+
+                continue;
+            }
+        };
+
+        valid &= check_entries(collection, &entries, *check);
+    }
+
+    if valid {
+        println!("[Check]: All files are valid");
+
+        return 0;
+    }
+
+    1
+}
+
+/// Writes all collections into the file. Returns the exit code.
+pub fn export(settings: &AppSettings, file: &Path) -> i32 {
+    let storage = match open_storage(&settings.storage) {
+        Ok(storage) => storage,
+        Err(error) => {
+            eprintln!("[ERROR] [Export]: Could not open the database {}", settings.storage.sqlite_path);
+            eprintln!("{}", error);
+
+            return 1;
+        }
+    };
+    let mut content = Mapping::new();
+
+    for (collection, _) in CHECKED.iter() {
+        let entries = storage.load(collection);
+
+        content.insert(Value::String(String::from(collection.name)), collection.to_value(&entries));
+    }
+
+    let result = serde_yaml::to_string(&content)
+        .map_err(|error| error.to_string())
+        .and_then(|content| write_atomic(file, content.as_bytes()).map_err(|error| error.to_string()));
+
+    match result {
+        Ok(_) => {
+            println!("[Export]: Exported the data to {}", file.display());
+
+            0
+        }
+        Err(error) => {
+            eprintln!("[ERROR] [Export]: Could not write {}", file.display());
+            eprintln!("{}", error);
+
+            1
+        }
+    }
+}
+
+/// Replaces the collections contained in the file. Nothing is changed if any entry is invalid.
+/// Returns the exit code.
+pub fn import(settings: &AppSettings, file: &Path) -> i32 {
+    let content: Mapping = match read_yaml(file) {
+        Ok(content) => content,
+        Err(error) => {
+            eprintln!("[ERROR] [Import]: Could not read {}", file.display());
+            eprintln!("{}", error);
+
+            return 1;
+        }
+    };
+    let mut imports = Vec::new();
+    let mut valid = true;
+
+    for (collection, check) in CHECKED.iter() {
+        let value = match content.get(&Value::String(String::from(collection.name))) {
+            Some(value) => value.clone(),
+            None => continue,
+        };
+
+        match collection.entries(value) {
+            Some(entries) => {
+                valid &= check_entries(collection, &entries, *check);
+                imports.push((*collection, entries));
+            }
+            None => {
+                eprintln!("[ERROR] [Import]: {} has the wrong format", collection.name);
+                valid = false;
+            }
+        }
+    }
+
+    if !valid {
+        eprintln!("[ERROR] [Import]: Nothing was imported");
+
+        return 1;
+    }
+
+    let storage = match open_storage(&settings.storage) {
+        Ok(storage) => storage,
+        Err(error) => {
+            eprintln!("[ERROR] [Import]: Could not open the database {}", settings.storage.sqlite_path);
+            eprintln!("{}", error);
+
+            return 1;
+        }
+    };
+    let mut code = 0;
+
+    for (collection, entries) in imports {
+        let count = entries.len();
+
+        match storage.replace(collection, entries) {
+            Ok(_) => println!("[Import]: Imported {} {} entries", count, collection.name),
+            Err(error) => {
+                eprintln!("[ERROR] [Import]: Could not import {}", collection.name);
+                eprintln!("{}", error);
+                code = 1;
+            }
+        }
+    }
+
+    println!("[Import]: Reload the data in the console of a running server to apply it");
+
+    code
+}
+
+/// Prints the hash of the password. Returns the exit code.
+pub fn print_password_hash(password: Option<String>) -> i32 {
+    let password = match password {
+        Some(password) => password,
+        None => {
+            let mut line = String::new();
+
+            if let Err(error) = stdin().read_line(&mut line) {
+                eprintln!("[ERROR] [Hash Password]: Could not read the password");
+                eprintln!("{}", error);
+
+                return 1;
+            }
+
+            String::from(line.trim_end_matches(['\r', '\n']))
+        }
+    };
+
+    if password.is_empty() {
+        eprintln!("[ERROR] [Hash Password]: The password is empty");
+
+        return 1;
+    }
+
+    println!("{}", hash_password(&password));
+
+    0
+}
+
+fn read_yaml<T: DeserializeOwned>(path: &Path) -> Result<T, String> {
+    let content = read_to_string(path).map_err(|error| error.to_string())?;
+
+    serde_yaml::from_str(&content).map_err(|error| error.to_string())
+}
+
+/// Checks the file, if it exists
+fn check_file<T: DeserializeOwned>(path: &Path) -> bool {
+    if !path.exists() {
+        println!("[Check]: {} does not exist, the defaults are used", path.display());
+
+        return true;
+    }
+
+    match read_yaml::<T>(path) {
+        Ok(_) => {
+            println!("[Check]: {} is valid", path.display());
+
+            true
+        }
+        Err(error) => {
+            eprintln!("[ERROR] [Check]: {}: {}", path.display(), error);
+
+            false
+        }
+    }
+}
+
+/// Opens the database read only, if it is used and exists. Without it the YAML files are checked,
+/// as the server imports them when it creates the database.
+fn open_database(settings: &StorageSettings) -> Result<Option<SqliteStorage>, String> {
+    let path = data_path(&settings.sqlite_path);
+
+    match settings.backend {
+        StorageBackend::Yaml => Ok(None),
+        StorageBackend::Sqlite if !path.exists() => {
+            println!("[Check]: {} does not exist, the YAML files are imported on the first start", path.display());
+
+            Ok(None)
+        }
+        StorageBackend::Sqlite => SqliteStorage::open_read_only(&path)
+            .map(Some)
+            .map_err(|error| error.to_string()),
+    }
+}
+
+/// The entries of the collection in the database. The YAML file if it was not imported yet.
+fn database_entries(database: &SqliteStorage, collection: &Collection) -> Result<Vec<(String, Value)>, String> {
+    let rows = match database.stored(collection).map_err(|error| error.to_string())? {
+        Some(rows) => rows,
+        None => return read_entries(collection, &YamlStorage::path(collection)),
+    };
+
+    rows.into_iter()
+        .map(|(key, data)| match serde_yaml::from_str(&data) {
+            Ok(value) => Ok((key, value)),
+            Err(error) => Err(format!("{}: {}", key, error)),
+        })
+        .collect()
+}
+
+fn read_entries(collection: &Collection, path: &Path) -> Result<Vec<(String, Value)>, String> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let content: Value = read_yaml(path)?;
+
+    collection
+        .entries(content)
+        .ok_or_else(|| String::from("The file has the wrong format"))
+}
+
+fn check_entries(collection: &Collection, entries: &[(String, Value)], check: EntryCheck) -> bool {
+    let mut valid = true;
+
+    for (key, value) in entries {
+        if let Some(error) = check(key, value) {
+            eprintln!("[ERROR] [Check]: {} {}: {}", collection.label, key, error);
+            valid = false;
+        }
+    }
+
+    if valid {
+        println!("[Check]: {} entries of {} are valid", entries.len(), collection.name);
+    }
+
+    valid
+}
+
+fn entry_error<T: DeserializeOwned>(_: &str, value: &Value) -> Option<String> {
+    serde_yaml::from_value::<T>(value.clone()).err().map(|error| error.to_string())
+}
+
+/// Parses the entry and runs the checks of the API, as if it was new
+fn validation_error<T: DeserializeOwned + Validate>(key: &str, value: &Value) -> Option<String> {
+    let data = match serde_yaml::from_value::<T>(value.clone()) {
+        Ok(data) => data,
+        Err(error) => return Some(error.to_string()),
+    };
+
+    match data.validate(key, None) {
+        Ok(()) => None,
+        Err(ServiceError::Invalid { message, details }) => {
+            let problems: Vec<String> = details["errors"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|problem| format!("{}: {}", problem["field"].as_str().unwrap_or_default(), problem["message"].as_str().unwrap_or_default()))
+                .collect();
+
+            Some(format!("{} {}", message, problems.join(" ")))
+        }
+        Err(error) => Some(error.to_string()),
+    }
+}
@@ -2,15 +2,22 @@
 //!
 //! Currently it can
 //! - Show and reload the config
-//! - Show and reload the shortcuts
+//! - Show, reload and run the shortcuts
+//! - Show and reload the rules
+//! - Show, set (password and role), delete and reload the users
+//! - Show and restore backups
+//! - Show and revert the history of changes
 //!
 
+use std::collections::HashMap;
 use std::io::stdin;
 use std::time::Duration;
 
 use actix::{Actor, Addr, AsyncContext, Context, Handler, Message};
 
-use crate::services::{DashboardMessage, DashboardService, GroupService, ShortcutsMessage, ShortcutsService, WebSettingsCompiledMessage, WebSettingsMessage, WebSettingsService, GroupMessage};
+use crate::auth::Role;
+use crate::services::history::revert;
+use crate::services::{BackupMessage, BackupService, DashboardMessage, DashboardService, GroupService, ShortcutsMessage, ShortcutsService, WebSettingsCompiledMessage, WebSettingsMessage, WebSettingsService, GroupMessage, RuleMessage, RuleService, UserData, UserMessage, UserService, HistoryMessage, HistoryQuery, HistoryService};
 use crate::thread_helper::run_in_thread;
 
 pub struct ConsoleApp {
@@ -18,23 +25,36 @@ pub struct ConsoleApp {
     shortcuts: Addr<ShortcutsService>,
     dashboard: Addr<DashboardService>,
     group: Addr<GroupService>,
+    rules: Addr<RuleService>,
+    users: Addr<UserService>,
+    backup: Addr<BackupService>,
+    history: Addr<HistoryService>,
     on_stop: Option<Box<dyn Fn()>>,
 }
 
 pub struct ConsoleMessage(String);
 
 impl ConsoleApp {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         settings: Addr<WebSettingsService>,
         shortcuts: Addr<ShortcutsService>,
         dashboard: Addr<DashboardService>,
         group: Addr<GroupService>,
+        rules: Addr<RuleService>,
+        users: Addr<UserService>,
+        backup: Addr<BackupService>,
+        history: Addr<HistoryService>,
     ) -> Self {
         ConsoleApp {
             settings,
             shortcuts,
             dashboard,
             group,
+            rules,
+            users,
+            backup,
+            history,
             on_stop: None,
         }
     }
@@ -112,8 +132,23 @@ impl Handler<ConsoleMessage> for ConsoleApp {
         if msg.is("/show_shortcuts") {
             futures::executor::block_on(async {
                 match self.shortcuts.send(ShortcutsMessage::List).await {
-                    Ok(shortcuts) => println!("{:?}", shortcuts),
-                    _ => eprintln!("Could not get settings."),
+                    Ok(Ok(shortcuts)) => println!("{:?}", shortcuts),
+                    Ok(Err(error)) => eprintln!("Could not get shortcuts: {}", error),
+                    _ => eprintln!("Could not get shortcuts."),
+                }
+            });
+
+            return;
+        }
+
+        if msg.is("/run_shortcut") {
+            let name = msg.argument("/run_shortcut");
+
+            futures::executor::block_on(async {
+                match self.shortcuts.send(ShortcutsMessage::Run(name.clone())).await {
+                    Ok(Ok(_)) => println!("Running shortcut {}.", name),
+                    Ok(Err(error)) => eprintln!("{}", error),
+                    _ => eprintln!("Could not run shortcut."),
                 }
             });
 
@@ -138,6 +173,196 @@ impl Handler<ConsoleMessage> for ConsoleApp {
             return;
         }
 
+        if msg.is("/show_rules") {
+            futures::executor::block_on(async {
+                match self.rules.send(RuleMessage::List).await {
+                    Ok(Ok(rules)) => println!("{:?}", rules),
+                    Ok(Err(error)) => eprintln!("Could not get rules: {}", error),
+                    _ => eprintln!("Could not get rules."),
+                }
+            });
+
+            return;
+        }
+
+        if msg.is("/reload_rules") {
+            self.rules.do_send(RuleMessage::Reload);
+
+            return;
+        }
+
+        if msg.is("/show_users") {
+            futures::executor::block_on(async {
+                match self.users.send(UserMessage::List).await {
+                    Ok(Ok(users)) => {
+                        for (name, user) in users {
+                            println!("{} ({:?}, tokens: {:?})", name, user.role(), user.token_names());
+                        }
+                    }
+                    Ok(Err(error)) => eprintln!("Could not get users: {}", error),
+                    _ => eprintln!("Could not get users."),
+                }
+            });
+
+            return;
+        }
+
+        if msg.is("/set_user_role") {
+            let argument = msg.argument("/set_user_role");
+            let mut parts = argument.splitn(2, ' ');
+            let name = String::from(parts.next().unwrap_or_default());
+            let role = serde_yaml::from_str::<Role>(parts.next().unwrap_or_default());
+
+            match role {
+                Ok(role) if !name.is_empty() => futures::executor::block_on(async {
+                    let user = match self.users.send(UserMessage::Get(name.clone())).await {
+                        Ok(Ok(mut users)) => users.remove(&name),
+                        _ => None,
+                    };
+
+                    match user {
+                        Some(user) => match self.users.send(UserMessage::Set(name.clone(), user.with_role(role))).await {
+                            Ok(Ok(_)) => println!("Saved user {}.", name),
+                            Ok(Err(error)) => eprintln!("Could not save user: {}", error),
+                            _ => eprintln!("Could not save user."),
+                        },
+                        None => eprintln!("User {} not found.", name),
+                    }
+                }),
+                _ => eprintln!("Usage: /set_user_role <name> <admin|editor|viewer>"),
+            }
+
+            return;
+        }
+
+        if msg.is("/set_user") {
+            let argument = msg.argument("/set_user");
+            let mut parts = argument.splitn(2, ' ');
+
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(password)) if !name.is_empty() && !password.is_empty() => {
+                    let name = String::from(name);
+
+                    futures::executor::block_on(async {
+                        let users = match self.users.send(UserMessage::List).await {
+                            Ok(Ok(users)) => users,
+                            _ => HashMap::new(),
+                        };
+                        // The first user is the admin, all others can only view until changed
+                        let user = match users.get(&name) {
+                            Some(user) => user.clone().with_password(String::from(password)),
+                            None if users.is_empty() => UserData::new(String::from(password), Role::Admin),
+                            None => UserData::new(String::from(password), Role::Viewer),
+                        };
+
+                        match self.users.send(UserMessage::Set(name.clone(), user)).await {
+                            Ok(Ok(_)) => println!("Saved user {}.", name),
+                            Ok(Err(error)) => eprintln!("Could not save user: {}", error),
+                            _ => eprintln!("Could not save user."),
+                        }
+                    });
+                }
+                _ => eprintln!("Usage: /set_user <name> <password>"),
+            }
+
+            return;
+        }
+
+        if msg.is("/delete_user") {
+            self.users.do_send(UserMessage::Delete(msg.argument("/delete_user")));
+
+            return;
+        }
+
+        if msg.is("/reload_users") {
+            self.users.do_send(UserMessage::Reload);
+
+            return;
+        }
+
+        if msg.is("/show_backups") {
+            futures::executor::block_on(async {
+                match self.backup.send(BackupMessage::List).await {
+                    Ok(Ok(snapshots)) => {
+                        for snapshot in snapshots {
+                            println!("{} ({} from {})", snapshot.name, snapshot.file, snapshot.created);
+                        }
+                    }
+                    Ok(Err(error)) => eprintln!("Could not list backups: {}", error),
+                    _ => eprintln!("Could not get backups."),
+                }
+            });
+
+            return;
+        }
+
+        if msg.is("/restore_backup") {
+            let name = msg.argument("/restore_backup");
+
+            futures::executor::block_on(async {
+                match self.backup.send(BackupMessage::Restore(name)).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(error)) => eprintln!("Could not restore backup: {}", error),
+                    _ => eprintln!("Could not restore backup."),
+                }
+            });
+
+            return;
+        }
+
+        if msg.is("/show_history") {
+            let query = HistoryQuery {
+                limit: Some(msg.argument("/show_history").parse().unwrap_or(20)),
+                ..Default::default()
+            };
+
+            futures::executor::block_on(async {
+                match self.history.send(HistoryMessage::List(query)).await {
+                    Ok(Ok(entries)) => {
+                        for entry in entries {
+                            println!(
+                                "{}: {:?} {:?} {} by {} at {}",
+                                entry.id,
+                                entry.action,
+                                entry.kind,
+                                entry.name,
+                                entry.user.as_deref().unwrap_or("-"),
+                                entry.time,
+                            );
+                        }
+                    }
+                    Ok(Err(error)) => eprintln!("Could not list history: {}", error),
+                    _ => eprintln!("Could not get history."),
+                }
+            });
+
+            return;
+        }
+
+        if msg.is("/revert") {
+            let id = match msg.argument("/revert").parse() {
+                Ok(id) => id,
+                Err(_) => return eprintln!("Usage: /revert <id>"),
+            };
+
+            futures::executor::block_on(async {
+                let entry = match self.history.send(HistoryMessage::Get(id)).await {
+                    Ok(Ok(mut entries)) => entries.pop(),
+                    Ok(Err(error)) => return eprintln!("{}", error),
+                    _ => return eprintln!("Could not get history."),
+                };
+
+                if let Some(entry) = entry {
+                    match revert(entry, None, &self.dashboard, &self.group, &self.shortcuts).await {
+                        Ok(()) => println!("Reverted change {}.", id),
+                        Err(error) => eprintln!("Could not revert change: {}", error),
+                    }
+                }
+            });
+
+            return;
+        }
+
         eprintln!("Command not found.");
     }
 }
@@ -146,6 +371,11 @@ impl ConsoleMessage {
     pub fn is(&self, cmd: impl ToString) -> bool {
         self.0.starts_with(&cmd.to_string())
     }
+
+    /// Everything after the command (trimmed)
+    pub fn argument(&self, cmd: impl ToString) -> String {
+        String::from(self.0.trim_start_matches(&cmd.to_string()).trim())
+    }
 }
 
 impl Message for ConsoleMessage {
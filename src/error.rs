@@ -0,0 +1,186 @@
+//! The errors of the services and how the API answers them
+//!
+//! Every error is sent as JSON with a machine readable `code`, a `message` for the user and
+//! optional `details`:
+//!
+//! ```json
+//! {"code": "in_use", "message": "The group lights is still used by dashboard Home", "details": {"used_by": [{"kind": "dashboard", "name": "Home"}]}}
+//! ```
+
+use std::fmt::{Display, Formatter};
+
+use actix::MailboxError;
+use actix_web::http::header::WWW_AUTHENTICATE;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::persistence::PersistenceError;
+use crate::storage::StorageError;
+
+#[derive(Debug)]
+pub enum ServiceError {
+    /// There is no entity of this kind with the name
+    NotFound { kind: &'static str, name: String },
+
+    /// The entity can not be deleted, as other entities still use it
+    InUse { kind: &'static str, name: String, used_by: Vec<Reference> },
+
+    /// The request can not be processed, e.g. because a field is missing
+    Invalid { message: String, details: Value },
+
+    Unauthorized,
+
+    Forbidden,
+
+    /// The entity was changed since the revision the request is based on (`If-Match`)
+    PreconditionFailed { kind: &'static str, name: String },
+
+    /// The MQTT client is disabled or not connected to the broker
+    MqttUnavailable,
+
+    /// Writing the data failed
+    Storage(StorageError),
+
+    /// The service did not answer
+    Mailbox(MailboxError),
+}
+
+/// An entity using another one
+#[derive(Serialize, Clone, Debug)]
+pub struct Reference {
+    pub kind: &'static str,
+    pub name: String,
+}
+
+/// The body of an error response
+#[derive(Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+    details: Value,
+}
+
+impl ServiceError {
+    pub fn not_found(kind: &'static str, name: impl ToString) -> Self {
+        ServiceError::NotFound { kind, name: name.to_string() }
+    }
+
+    pub fn invalid(message: impl ToString) -> Self {
+        ServiceError::Invalid {
+            message: message.to_string(),
+            details: Value::Null,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ServiceError::NotFound { .. } => "not_found",
+            ServiceError::InUse { .. } => "in_use",
+            ServiceError::Invalid { .. } => "invalid",
+            ServiceError::Unauthorized => "unauthorized",
+            ServiceError::Forbidden => "forbidden",
+            ServiceError::PreconditionFailed { .. } => "precondition_failed",
+            ServiceError::MqttUnavailable => "mqtt_unavailable",
+            ServiceError::Storage(StorageError::Persistence(PersistenceError::Unparsed(_)) | StorageError::Unparsed(_)) => "unparsed_file",
+            ServiceError::Storage(_) | ServiceError::Mailbox(_) => "internal",
+        }
+    }
+
+    fn details(&self) -> Value {
+        match self {
+            ServiceError::NotFound { kind, name } | ServiceError::PreconditionFailed { kind, name } => json!({ "kind": kind, "name": name }),
+            ServiceError::InUse { kind, name, used_by } => json!({ "kind": kind, "name": name, "used_by": used_by }),
+            ServiceError::Invalid { details, .. } => details.clone(),
+            _ => Value::Null,
+        }
+    }
+}
+
+impl Display for ServiceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServiceError::NotFound { kind, name } => write!(f, "There is no {} {}", kind, name),
+            ServiceError::InUse { kind, name, used_by } => {
+                let users: Vec<String> = used_by
+                    .iter()
+                    .map(|reference| format!("{} {}", reference.kind, reference.name))
+                    .collect();
+
+                write!(f, "The {} {} is still used by {}", kind, name, users.join(", "))
+            }
+            ServiceError::Invalid { message, .. } => write!(f, "{}", message),
+            ServiceError::Unauthorized => write!(f, "Authentication required."),
+            ServiceError::Forbidden => write!(f, "Permission denied."),
+            ServiceError::PreconditionFailed { kind, name } => write!(f, "The {} {} was changed in the meantime", kind, name),
+            ServiceError::MqttUnavailable => write!(f, "The server is not connected to the MQTT broker."),
+            ServiceError::Storage(StorageError::Persistence(error @ PersistenceError::Unparsed(_))) => write!(f, "{}", error),
+            ServiceError::Storage(error @ StorageError::Unparsed(_)) => write!(f, "{}", error),
+            ServiceError::Storage(_) | ServiceError::Mailbox(_) => {
+                write!(f, "Server error occurred. For more information ask the system administrator")
+            }
+        }
+    }
+}
+
+impl ResponseError for ServiceError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ServiceError::NotFound { .. } => StatusCode::NOT_FOUND,
+            ServiceError::InUse { .. } => StatusCode::CONFLICT,
+            ServiceError::Invalid { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            ServiceError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ServiceError::Forbidden => StatusCode::FORBIDDEN,
+            ServiceError::PreconditionFailed { .. } => StatusCode::PRECONDITION_FAILED,
+            ServiceError::MqttUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ServiceError::Storage(StorageError::Persistence(PersistenceError::Unparsed(_)) | StorageError::Unparsed(_)) => StatusCode::CONFLICT,
+            ServiceError::Storage(_) | ServiceError::Mailbox(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            ServiceError::Storage(error) => eprintln!("[ERROR] [Web Server] {}", error),
+            ServiceError::Mailbox(error) => eprintln!("[ERROR] [Web Server] {:?}", error),
+            _ => {}
+        }
+
+        let mut response = HttpResponse::build(self.status_code());
+
+        if let ServiceError::Unauthorized = self {
+            response.header(WWW_AUTHENTICATE, "Basic realm=\"New Home\", charset=\"UTF-8\"");
+        }
+
+        response.json(ErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+            details: self.details(),
+        })
+    }
+}
+
+impl From<MailboxError> for ServiceError {
+    fn from(error: MailboxError) -> Self {
+        ServiceError::Mailbox(error)
+    }
+}
+
+impl From<StorageError> for ServiceError {
+    fn from(error: StorageError) -> Self {
+        ServiceError::Storage(error)
+    }
+}
+
+impl From<PersistenceError> for ServiceError {
+    fn from(error: PersistenceError) -> Self {
+        match error {
+            PersistenceError::UnknownSnapshot(name) => ServiceError::not_found("backup", name),
+            PersistenceError::InvalidSnapshot(error) => ServiceError::Invalid {
+                message: String::from("The backup is no valid YAML"),
+                details: json!({ "error": error.to_string() }),
+            },
+            error => ServiceError::Storage(StorageError::Persistence(error)),
+        }
+    }
+}
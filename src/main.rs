@@ -5,36 +5,104 @@
 //! Folder and handling the settings for the frontend.
 //!
 
+// Lints only raised by the code from before clippy was run on the project
+#![allow(clippy::needless_return, clippy::ptr_arg, clippy::single_match, clippy::unused_unit)]
+
 extern crate serde;
 
-use std::env::args;
+use std::process::exit;
 
 use actix::Actor;
 use actix_web::rt::{Arbiter, System};
+use clap::Parser;
 
+use crate::cli::{check_config, export, import, print_password_hash, Cli, Command, ServeArgs};
 use crate::console::ConsoleApp;
-use crate::services::{DashboardService, GroupService, ShortcutsService, WebSettingsCompiledMessage, WebSettingsService};
+use crate::mqtt::client::ConnectionState;
+use crate::paths::{configure_paths, data_path, migrate_data_files, Paths};
+use crate::persistence::configure_backups;
+use crate::services::chain::Chains;
+use crate::services::history::HISTORY_FILE;
+use crate::services::validation::configure_validation;
+use crate::services::{BackupService, BrokerService, DashboardService, EventService, GroupService, HistoryService, MqttService, RuleService, ShortcutsService, UserService, WebSettingsCompiledMessage, WebSettingsService};
 use crate::settings::AppSettings;
+use crate::storage::{open_storage, COLLECTIONS};
 use crate::web_handler::start_web_server;
 
+mod auth;
+mod cli;
 mod console;
+mod error;
+mod mqtt;
+mod paths;
+mod persistence;
+mod proxy;
 mod services;
 mod settings;
+mod static_files;
+mod storage;
 mod thread_helper;
 mod web_handler;
 mod mime_type_mapper;
 
 fn main() {
-    let mut console_enabled = true;
+    let cli = Cli::parse();
+    let command = cli.command();
 
-    for arg in args() {
-        if arg.eq("--no-console") {
-            console_enabled = false;
-        }
+    // Hashing a password needs no files, so only the hash is printed
+    if let Command::HashPassword { password } = command {
+        exit(print_password_hash(password));
     }
 
+    configure_paths(Paths::resolve(cli.config, cli.data_dir));
+
+    let code = match command {
+        Command::CheckConfig => check_config(),
+        Command::Export { file } => export(&load_settings(), &file),
+        Command::Import { file } => import(&load_settings(), &file),
+        Command::Serve(args) => serve(args),
+        Command::HashPassword { .. } => unreachable!(),
+    };
+
+    exit(code);
+}
+
+/// Loads the settings and configures the backups and the validation
+fn load_settings() -> AppSettings {
     let app_settings = AppSettings::load();
+    let mut backup_settings = app_settings.backup.clone();
+
+    backup_settings.directory = data_path(&backup_settings.directory).to_string_lossy().to_string();
+    configure_backups(backup_settings);
+    configure_validation(app_settings.validation.clone());
+
+    app_settings
+}
+
+/// Runs the server until it is stopped. Returns the exit code.
+fn serve(args: ServeArgs) -> i32 {
+    let console_enabled = !args.no_console;
+    let mut app_settings = load_settings();
+
+    // Before saving the settings, which takes the first snapshot in the backup directory
+    let mut data_files: Vec<String> = COLLECTIONS.iter().map(|collection| collection.file_name()).collect();
+    data_files.push(app_settings.storage.sqlite_path.clone());
+    data_files.push(String::from(HISTORY_FILE));
+    data_files.push(app_settings.backup.directory.clone());
+    migrate_data_files(&data_files);
+
     app_settings.save();
+    args.apply(&mut app_settings);
+
+    let storage = match open_storage(&app_settings.storage) {
+        Ok(storage) => storage,
+        Err(error) => {
+            eprintln!("[ERROR] [Storage]: Could not open the database {}", app_settings.storage.sqlite_path);
+            eprintln!("{}", error);
+
+            return 1;
+        }
+    };
 
     let mut server_system = System::new("sys_webserver");
     let mut web_settings_arbiter = Arbiter::new();
@@ -42,18 +110,94 @@ fn main() {
         WebSettingsService::start_in_arbiter(&web_settings_arbiter, |_| WebSettingsService::new());
     web_settings_addr.do_send(WebSettingsCompiledMessage::Reload);
 
-    let mut shortcuts_arbiter = Arbiter::new();
-    let shortcuts_addr =
-        ShortcutsService::start_in_arbiter(&shortcuts_arbiter, |_| ShortcutsService::new());
 
+    let mut events_arbiter = Arbiter::new();
+    let events_addr = EventService::start_in_arbiter(&events_arbiter, |_| EventService::new());
+
+    let history_settings = app_settings.history.clone();
+    let mut history_arbiter = Arbiter::new();
+    let history_addr =
+        HistoryService::start_in_arbiter(&history_arbiter, |_| HistoryService::new(history_settings));
+
+    let dashboard_storage = Clone::clone(&storage);
+    let dashboard_events_addr = Clone::clone(&events_addr);
+    let dashboard_history_addr = Clone::clone(&history_addr);
     let mut dashboard_arbiter = Arbiter::new();
-    let dashboard_addr =
-        DashboardService::start_in_arbiter(&dashboard_arbiter, |_| DashboardService::new());
+    let dashboard_addr = DashboardService::start_in_arbiter(&dashboard_arbiter, |_| {
+        DashboardService::new(dashboard_storage, dashboard_events_addr, dashboard_history_addr)
+    });
 
+    let group_storage = Clone::clone(&storage);
     let group_dashboard_addr = Clone::clone(&dashboard_addr);
+    let group_events_addr = Clone::clone(&events_addr);
+    let group_history_addr = Clone::clone(&history_addr);
     let mut group_arbiter = Arbiter::new();
-    let group_addr =
-        GroupService::start_in_arbiter(&group_arbiter, |_| GroupService::new(group_dashboard_addr));
+    let group_addr = GroupService::start_in_arbiter(&group_arbiter, |_| {
+        GroupService::new(group_storage, group_dashboard_addr, group_events_addr, group_history_addr)
+    });
+
+    let broker_settings = app_settings.broker.clone();
+    let mut broker_arbiter = Arbiter::new();
+    // The address is kept until the end, as the actor stops once all addresses are dropped
+    let _broker_addr =
+        BrokerService::start_in_arbiter(&broker_arbiter, |_| BrokerService::new(broker_settings));
+
+    let mqtt_settings = app_settings.mqtt.clone();
+    let mqtt_state = ConnectionState::default();
+    let mqtt_client_state = Clone::clone(&mqtt_state);
+    let mut mqtt_arbiter = Arbiter::new();
+    let mqtt_addr =
+        MqttService::start_in_arbiter(&mqtt_arbiter, |_| MqttService::new(mqtt_settings, mqtt_client_state));
+
+    // Shared by rules and shortcuts, as they can trigger each other
+    let chains = Chains::default();
+
+    let shortcuts_storage = Clone::clone(&storage);
+    let shortcuts_mqtt_addr = Clone::clone(&mqtt_addr);
+    let shortcuts_chains = Clone::clone(&chains);
+    let shortcuts_events_addr = Clone::clone(&events_addr);
+    let shortcuts_history_addr = Clone::clone(&history_addr);
+    let mut shortcuts_arbiter = Arbiter::new();
+    let shortcuts_addr = ShortcutsService::start_in_arbiter(&shortcuts_arbiter, |_| {
+        ShortcutsService::new(
+            shortcuts_storage,
+            shortcuts_mqtt_addr,
+            mqtt_state,
+            shortcuts_chains,
+            shortcuts_events_addr,
+            shortcuts_history_addr,
+        )
+    });
+
+    let rules_storage = Clone::clone(&storage);
+    let rules_mqtt_addr = Clone::clone(&mqtt_addr);
+    let mut rules_arbiter = Arbiter::new();
+    let rules_addr =
+        RuleService::start_in_arbiter(&rules_arbiter, |_| RuleService::new(rules_storage, rules_mqtt_addr, chains));
+
+    let users_storage = Clone::clone(&storage);
+    let auth_settings = app_settings.auth.clone();
+    let mut users_arbiter = Arbiter::new();
+    let users_addr =
+        UserService::start_in_arbiter(&users_arbiter, |_| UserService::new(users_storage, auth_settings));
+
+    let backup_web_settings = Clone::clone(&web_settings_addr);
+    let backup_dashboard = Clone::clone(&dashboard_addr);
+    let backup_group = Clone::clone(&group_addr);
+    let backup_shortcuts = Clone::clone(&shortcuts_addr);
+    let backup_rules = Clone::clone(&rules_addr);
+    let backup_users = Clone::clone(&users_addr);
+    let backup_storage = Clone::clone(&storage);
+    let mut backup_arbiter = Arbiter::new();
+    let backup_addr = BackupService::start_in_arbiter(&backup_arbiter, move |_| BackupService::new(
+        backup_storage,
+        backup_web_settings,
+        backup_dashboard,
+        backup_group,
+        backup_shortcuts,
+        backup_rules,
+        backup_users,
+    ));
 
     let mut console_arbiter = Arbiter::new();
     if console_enabled {
@@ -61,11 +205,19 @@ fn main() {
         let console_shortcuts = Clone::clone(&shortcuts_addr);
         let console_dashboard = Clone::clone(&dashboard_addr);
         let console_group = Clone::clone(&group_addr);
+        let console_rules = Clone::clone(&rules_addr);
+        let console_users = Clone::clone(&users_addr);
+        let console_backup = Clone::clone(&backup_addr);
+        let console_history = Clone::clone(&history_addr);
         ConsoleApp::start_in_arbiter(&console_arbiter, move |_| ConsoleApp::new(
             console_settings,
             console_shortcuts,
             console_dashboard,
             console_group,
+            console_rules,
+            console_users,
+            console_backup,
+            console_history,
         ));
     }
 
@@ -75,6 +227,11 @@ fn main() {
         shortcuts_addr,
         dashboard_addr,
         group_addr,
+        rules_addr,
+        users_addr,
+        events_addr,
+        backup_addr,
+        history_addr,
         app_settings.clone(),
     );
 
@@ -84,5 +241,14 @@ fn main() {
     console_arbiter.join().unwrap();
     shortcuts_arbiter.join().unwrap();
     dashboard_arbiter.join().unwrap();
+    events_arbiter.join().unwrap();
     group_arbiter.join().unwrap();
+    broker_arbiter.join().unwrap();
+    mqtt_arbiter.join().unwrap();
+    rules_arbiter.join().unwrap();
+    users_arbiter.join().unwrap();
+    backup_arbiter.join().unwrap();
+    history_arbiter.join().unwrap();
+
+    0
 }
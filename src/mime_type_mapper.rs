@@ -51,6 +51,28 @@ impl MimeTypeMapper {
 
         String::from("application/octet-stream")
     }
+
+    /// Checks if files of the mime type get smaller when compressed (text, JSON, JavaScript, SVG,
+    /// ...). Images, media and archives are compressed already.
+    pub fn is_compressible(mime_type: &str) -> bool {
+        let mime_type = mime_type.split(';').next().unwrap_or_default().trim();
+
+        mime_type.starts_with("text/")
+            || mime_type.ends_with("+json")
+            || mime_type.ends_with("+xml")
+            || [
+                "application/json",
+                "application/javascript",
+                "application/xml",
+                "application/wasm",
+                "application/vnd.ms-fontobject",
+                "font/otf",
+                "font/ttf",
+                "image/bmp",
+                "image/vnd.microsoft.icon",
+            ]
+            .contains(&mime_type)
+    }
 }
 
 impl Default for MimeTypeMapper {
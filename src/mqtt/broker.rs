@@ -0,0 +1,512 @@
+//! A small embedded MQTT 3.1.1 broker.
+//!
+//! It supports QoS 0 and 1 (QoS 2 subscriptions are granted as QoS 1), retained messages and
+//! wills. Sessions are not persisted, every connection starts with a clean session.
+//! Each listener and each connection runs in its own thread.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::HeaderValue;
+use tungstenite::protocol::WebSocketConfig;
+use tungstenite::{Message, WebSocket};
+
+use crate::auth::password::constant_time_eq;
+use crate::mqtt::packet::{Packet, Publish};
+use crate::mqtt::topic_matches;
+use crate::settings::BrokerSettings;
+use crate::thread_helper::{run_in_thread, StopFn};
+
+/// The time a new connection has to complete the WebSocket handshake and to send CONNECT
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// CONNACK return codes
+const CONNACK_ACCEPTED: u8 = 0;
+const CONNACK_IDENTIFIER_REJECTED: u8 = 2;
+const CONNACK_BAD_CREDENTIALS: u8 = 4;
+
+/// The broker state, which is shared between all connections
+#[derive(Clone)]
+pub struct Broker {
+    settings: BrokerSettings,
+    state: Arc<Mutex<BrokerState>>,
+}
+
+#[derive(Default)]
+struct BrokerState {
+    sessions: HashMap<String, Session>,
+    retained: HashMap<String, Publish>,
+    next_connection_id: u64,
+}
+
+/// A connected client
+struct Session {
+    connection_id: u64,
+    sender: Sender<Outgoing>,
+    subscriptions: Vec<(String, u8)>,
+    next_packet_id: u16,
+}
+
+/// Instructions for a connection thread
+enum Outgoing {
+    Packet(Packet),
+    Close,
+}
+
+/// The byte stream a client is connected through
+trait Transport {
+    /// Appends the received bytes to the buffer. Returns false if nothing was received (timeout)
+    fn receive(&mut self, buffer: &mut Vec<u8>) -> std::io::Result<bool>;
+
+    fn send(&mut self, bytes: &[u8]) -> std::io::Result<()>;
+}
+
+impl Broker {
+    pub fn new(settings: BrokerSettings) -> Self {
+        Self {
+            settings,
+            state: Arc::new(Mutex::new(BrokerState::default())),
+        }
+    }
+
+    /// Starts a thread accepting plain TCP connections
+    pub fn listen_tcp(&self, bind: &str) -> std::io::Result<StopFn> {
+        let listener = TcpListener::bind(bind)?;
+        let broker = self.clone();
+
+        println!("[MQTT Broker]: Listening on tcp://{}", bind);
+
+        self.listen(listener, "MQTT broker (TCP)", move |stream| {
+            broker.handle_connection(stream)
+        })
+    }
+
+    /// Starts a thread accepting WebSocket connections (as used by browsers)
+    pub fn listen_websocket(&self, bind: &str) -> std::io::Result<StopFn> {
+        let listener = TcpListener::bind(bind)?;
+        let broker = self.clone();
+
+        println!("[MQTT Broker]: Listening on ws://{}", bind);
+
+        self.listen(listener, "MQTT broker (WebSocket)", move |stream| {
+            // Larger messages are not even read, as they can not contain an allowed packet
+            let config = WebSocketConfig {
+                max_message_size: Some(broker.settings.max_packet_size),
+                max_frame_size: Some(broker.settings.max_packet_size),
+                ..Default::default()
+            };
+
+            // Otherwise a client which never finishes the handshake would keep the thread forever
+            stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+
+            match tungstenite::server::accept_hdr_with_config(stream, accept_mqtt_protocol, Some(config)) {
+                Ok(mut socket) => {
+                    socket.get_mut().set_read_timeout(None)?;
+
+                    broker.handle_connection(socket)
+                }
+                Err(error) => Err(Error::new(ErrorKind::InvalidData, error.to_string())),
+            }
+        })
+    }
+
+    /// Closes all connections
+    pub fn shutdown(&self) {
+        for session in self.lock().sessions.values() {
+            session.sender.send(Outgoing::Close).unwrap_or_default();
+        }
+    }
+
+    fn listen<F>(&self, listener: TcpListener, name: &str, handler: F) -> std::io::Result<StopFn>
+    where
+        F: Fn(TcpStream) -> std::io::Result<()> + Clone + Send + 'static,
+    {
+        listener.set_nonblocking(true)?;
+
+        let (stop, _) = run_in_thread(
+            move |stop| loop {
+                if stop.try_recv().unwrap_or(false) {
+                    break;
+                }
+
+                match listener.accept() {
+                    Ok((stream, address)) => {
+                        let handler = handler.clone();
+
+                        thread::spawn(move || {
+                            if let Err(error) = stream.set_nonblocking(false).and_then(|_| handler(stream)) {
+                                eprintln!("[WARN] [MQTT Broker]: Connection from {} closed with an error", address);
+                                eprintln!("{}", error);
+                            }
+                        });
+                    }
+                    Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(100));
+                    }
+                    Err(error) => {
+                        eprintln!("[ERROR] [MQTT Broker]: Could not accept connection");
+                        eprintln!("{}", error);
+                    }
+                }
+            },
+            String::from(name),
+        );
+
+        Ok(stop)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BrokerState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn handle_connection<T: Transport + ReadTimeout>(&self, mut transport: T) -> std::io::Result<()> {
+        let mut buffer = Vec::new();
+
+        transport.set_timeout(CONNECT_TIMEOUT)?;
+
+        let connect = match receive_packet(&mut transport, &mut buffer, self.settings.max_packet_size)? {
+            Some(Packet::Connect(connect)) => connect,
+            _ => return Err(Error::new(ErrorKind::InvalidData, "Expected CONNECT as first packet")),
+        };
+
+        // Both are compared in constant time, so the time does not tell which of them was wrong
+        let credentials_valid = self.settings.username.is_empty()
+            || (connect.username.is_some()
+                & connect.password.is_some()
+                & constant_time_eq(connect.username.as_deref().unwrap_or_default().as_bytes(), self.settings.username.as_bytes())
+                & constant_time_eq(connect.password.as_deref().unwrap_or_default(), self.settings.password.as_bytes()));
+
+        if !credentials_valid {
+            transport.send(&Packet::ConnAck { session_present: false, code: CONNACK_BAD_CREDENTIALS }.encode())?;
+
+            return Ok(());
+        }
+
+        if connect.client_id.is_empty() && !connect.clean_session {
+            transport.send(&Packet::ConnAck { session_present: false, code: CONNACK_IDENTIFIER_REJECTED }.encode())?;
+
+            return Ok(());
+        }
+
+        let (sender, receiver) = channel();
+        let (client_id, connection_id) = self.register(connect.client_id.clone(), sender);
+
+        transport.send(&Packet::ConnAck { session_present: false, code: CONNACK_ACCEPTED }.encode())?;
+        transport.set_timeout(Duration::from_millis(50))?;
+
+        let result = self.run_session(&mut transport, &mut buffer, &receiver, &client_id, connect.keep_alive);
+
+        if self.unregister(&client_id, connection_id) && !matches!(result, Ok(true)) {
+            if let Some(will) = connect.will {
+                self.publish(will);
+            }
+        }
+
+        result.map(|_| ())
+    }
+
+    /// Handles all packets of a connected client.
+    /// Returns true if the client disconnected properly (with a DISCONNECT packet)
+    fn run_session<T: Transport>(
+        &self,
+        transport: &mut T,
+        buffer: &mut Vec<u8>,
+        receiver: &Receiver<Outgoing>,
+        client_id: &str,
+        keep_alive: u16,
+    ) -> std::io::Result<bool> {
+        let mut last_received = Instant::now();
+        let mut pending_qos2: HashSet<u16> = HashSet::new();
+        let timeout = Duration::from_millis(keep_alive as u64 * 1500);
+
+        loop {
+            loop {
+                match receiver.try_recv() {
+                    Ok(Outgoing::Packet(packet)) => transport.send(&packet.encode())?,
+                    Ok(Outgoing::Close) | Err(TryRecvError::Disconnected) => return Ok(true),
+                    Err(TryRecvError::Empty) => break,
+                }
+            }
+
+            if keep_alive > 0 && last_received.elapsed() > timeout {
+                return Err(Error::new(ErrorKind::TimedOut, format!("Client {} exceeded its keep alive", client_id)));
+            }
+
+            if !transport.receive(buffer)? {
+                continue;
+            }
+
+            last_received = Instant::now();
+
+            while let Some(packet) = Packet::decode_limited(buffer, self.settings.max_packet_size)? {
+                match packet {
+                    Packet::Publish(publish) => {
+                        if publish.topic.contains('+') || publish.topic.contains('#') {
+                            return Err(Error::new(ErrorKind::InvalidData, "Wildcards are not allowed in topic names"));
+                        }
+
+                        match (publish.qos, publish.packet_id) {
+                            (1, Some(packet_id)) => transport.send(&Packet::PubAck(packet_id).encode())?,
+                            (2, Some(packet_id)) => {
+                                transport.send(&Packet::PubRec(packet_id).encode())?;
+
+                                if !pending_qos2.insert(packet_id) {
+                                    continue;
+                                }
+                            }
+                            _ => {}
+                        }
+
+                        self.publish(publish);
+                    }
+                    Packet::PubRel(packet_id) => {
+                        pending_qos2.remove(&packet_id);
+                        transport.send(&Packet::PubComp(packet_id).encode())?;
+                    }
+                    Packet::Subscribe { packet_id, filters } => {
+                        let codes = filters.iter().map(|(_, qos)| (*qos).min(1)).collect();
+
+                        transport.send(&Packet::SubAck { packet_id, codes }.encode())?;
+                        self.subscribe(client_id, filters);
+                    }
+                    Packet::Unsubscribe { packet_id, filters } => {
+                        self.unsubscribe(client_id, &filters);
+                        transport.send(&Packet::UnsubAck(packet_id).encode())?;
+                    }
+                    Packet::PingReq => transport.send(&Packet::PingResp.encode())?,
+                    Packet::Disconnect => return Ok(true),
+                    Packet::Connect(_) => {
+                        return Err(Error::new(ErrorKind::InvalidData, "Received a second CONNECT"));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Adds the session. An existing session with the same client id is closed.
+    /// Clients without an id get a generated one.
+    fn register(&self, client_id: String, sender: Sender<Outgoing>) -> (String, u64) {
+        let mut state = self.lock();
+
+        state.next_connection_id += 1;
+
+        let connection_id = state.next_connection_id;
+        let client_id = match client_id.is_empty() {
+            true => format!("new-home-{}", connection_id),
+            false => client_id,
+        };
+
+        let session = Session {
+            connection_id,
+            sender,
+            subscriptions: Vec::new(),
+            next_packet_id: 0,
+        };
+
+        if let Some(previous) = state.sessions.insert(client_id.clone(), session) {
+            previous.sender.send(Outgoing::Close).unwrap_or_default();
+        }
+
+        (client_id, connection_id)
+    }
+
+    /// Removes the session, if it was not taken over by another connection.
+    fn unregister(&self, client_id: &str, connection_id: u64) -> bool {
+        let mut state = self.lock();
+
+        match state.sessions.get(client_id) {
+            Some(session) if session.connection_id == connection_id => {
+                state.sessions.remove(client_id);
+
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn subscribe(&self, client_id: &str, filters: Vec<(String, u8)>) {
+        let mut state = self.lock();
+        let state = &mut *state;
+        let session = match state.sessions.get_mut(client_id) {
+            Some(session) => session,
+            None => return,
+        };
+
+        for (filter, qos) in filters {
+            let qos = qos.min(1);
+
+            session.subscriptions.retain(|(existing, _)| existing.ne(&filter));
+
+            for retained in state.retained.values() {
+                if topic_matches(&filter, &retained.topic) {
+                    let packet = session.packet(retained, qos, true);
+
+                    session.sender.send(Outgoing::Packet(packet)).unwrap_or_default();
+                }
+            }
+
+            session.subscriptions.push((filter, qos));
+        }
+    }
+
+    fn unsubscribe(&self, client_id: &str, filters: &[String]) {
+        if let Some(session) = self.lock().sessions.get_mut(client_id) {
+            session.subscriptions.retain(|(filter, _)| !filters.contains(filter));
+        }
+    }
+
+    /// Delivers the message to all matching subscriptions and updates the retained messages
+    fn publish(&self, publish: Publish) {
+        let mut state = self.lock();
+
+        if publish.retain {
+            match publish.payload.is_empty() {
+                true => state.retained.remove(&publish.topic),
+                false => state.retained.insert(publish.topic.clone(), publish.clone()),
+            };
+        }
+
+        for session in state.sessions.values_mut() {
+            let qos = session
+                .subscriptions
+                .iter()
+                .filter(|(filter, _)| topic_matches(filter, &publish.topic))
+                .map(|(_, qos)| *qos)
+                .max();
+
+            if let Some(qos) = qos {
+                let packet = session.packet(&publish, qos, false);
+
+                session.sender.send(Outgoing::Packet(packet)).unwrap_or_default();
+            }
+        }
+    }
+}
+
+impl Session {
+    /// Builds the PUBLISH packet for this session
+    fn packet(&mut self, publish: &Publish, subscription_qos: u8, retain: bool) -> Packet {
+        let qos = publish.qos.min(subscription_qos);
+        let packet_id = match qos {
+            0 => None,
+            _ => {
+                self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+
+                Some(self.next_packet_id)
+            }
+        };
+
+        Packet::Publish(Publish {
+            topic: publish.topic.clone(),
+            payload: publish.payload.clone(),
+            qos,
+            retain,
+            dup: false,
+            packet_id,
+        })
+    }
+}
+
+/// Answers the WebSocket handshake with the "mqtt" sub protocol, if the client requested it
+#[allow(clippy::result_large_err)]
+fn accept_mqtt_protocol(request: &Request, mut response: Response) -> Result<Response, ErrorResponse> {
+    let requests_mqtt = request
+        .headers()
+        .get_all("Sec-WebSocket-Protocol")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|protocol| protocol.trim().eq("mqtt"));
+
+    if requests_mqtt {
+        response.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static("mqtt"));
+    }
+
+    Ok(response)
+}
+
+/// Waits (until the transports timeout) for the first full packet
+fn receive_packet<T: Transport>(transport: &mut T, buffer: &mut Vec<u8>, max_size: usize) -> std::io::Result<Option<Packet>> {
+    loop {
+        if let Some(packet) = Packet::decode_limited(buffer, max_size)? {
+            return Ok(Some(packet));
+        }
+
+        if !transport.receive(buffer)? {
+            return Ok(None);
+        }
+    }
+}
+
+/// Sets the read timeout on the underlying socket
+trait ReadTimeout {
+    fn set_timeout(&mut self, timeout: Duration) -> std::io::Result<()>;
+}
+
+impl ReadTimeout for TcpStream {
+    fn set_timeout(&mut self, timeout: Duration) -> std::io::Result<()> {
+        self.set_read_timeout(Some(timeout))
+    }
+}
+
+impl ReadTimeout for WebSocket<TcpStream> {
+    fn set_timeout(&mut self, timeout: Duration) -> std::io::Result<()> {
+        self.get_mut().set_read_timeout(Some(timeout))
+    }
+}
+
+impl Transport for TcpStream {
+    fn receive(&mut self, buffer: &mut Vec<u8>) -> std::io::Result<bool> {
+        let mut chunk = [0u8; 4096];
+
+        match self.read(&mut chunk) {
+            Ok(0) => Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed by client")),
+            Ok(read) => {
+                buffer.extend_from_slice(&chunk[..read]);
+
+                Ok(true)
+            }
+            Err(error) if error.kind() == ErrorKind::WouldBlock || error.kind() == ErrorKind::TimedOut => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn send(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.write_all(bytes)
+    }
+}
+
+impl Transport for WebSocket<TcpStream> {
+    fn receive(&mut self, buffer: &mut Vec<u8>) -> std::io::Result<bool> {
+        match self.read_message() {
+            Ok(Message::Binary(data)) => {
+                buffer.extend_from_slice(&data);
+
+                Ok(true)
+            }
+            Ok(Message::Close(_)) => Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed by client")),
+            Ok(_) => Ok(false),
+            Err(tungstenite::Error::Io(error))
+                if error.kind() == ErrorKind::WouldBlock || error.kind() == ErrorKind::TimedOut =>
+            {
+                Ok(false)
+            }
+            Err(error) => Err(Error::other(error.to_string())),
+        }
+    }
+
+    fn send(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.write_message(Message::Binary(bytes.to_vec()))
+            .map_err(|error| Error::other(error.to_string()))
+    }
+}
@@ -0,0 +1,558 @@
+//! The MQTT client connection.
+//!
+//! The client runs in its own thread and reconnects automatically. Commands (publish, subscribe,
+//! ...) are sent through a channel, incoming messages are passed to a callback.
+//!
+//! Messages are published with the QoS they ask for. Messages with QoS 1 or 2 are kept until the
+//! broker acknowledged them and are sent again (as duplicate) if there is no answer within
+//! [RETRY_INTERVAL] or the connection was lost before. Publishes sent while the client is
+//! disconnected are dropped instead of being sent (outdated) after the reconnect.
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::mqtt::packet::{Connect, Packet, Publish};
+use crate::settings::MqttSettings;
+use crate::thread_helper::{run_in_thread, StopFn};
+
+/// The time the broker has to acknowledge a message with QoS 1 or 2 before it is sent again
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The most messages waiting for their acknowledgement. Beyond that, the oldest one is dropped.
+const MAX_IN_FLIGHT: usize = 100;
+
+/// Commands the client thread is responding to
+pub enum ClientCommand {
+    /// Publishes the message on the broker
+    Publish(Publish),
+
+    /// Subscribes to the given topic filter. The subscription is renewed on reconnects.
+    Subscribe(String),
+
+    /// Removes the subscription for the given topic filter
+    Unsubscribe(String),
+}
+
+/// Whether the client is connected to the broker, shared with the services which publish
+#[derive(Clone, Default)]
+pub struct ConnectionState(Arc<AtomicBool>);
+
+/// Why a connection ended
+enum Disconnect {
+    /// The connection was lost or refused and should be opened again
+    Lost,
+
+    /// The client was stopped
+    Stopped,
+}
+
+/// The state which is kept when the connection is opened again
+#[derive(Default)]
+struct Session {
+    /// The topic filters, which are subscribed to again on reconnects
+    subscriptions: Vec<String>,
+
+    /// The messages with QoS 1 or 2 which were not acknowledged yet
+    in_flight: Vec<InFlight>,
+
+    next_packet_id: u16,
+}
+
+/// A message with QoS 1 or 2 waiting for its acknowledgement
+struct InFlight {
+    publish: Publish,
+
+    /// The broker received the QoS 2 message (PUBREC), so only the release (PUBREL) is sent again
+    received: bool,
+
+    sent: Instant,
+}
+
+/// Holds the state of a single connection to the broker
+struct Connection {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    last_sent: Instant,
+}
+
+/// Starts the client thread.
+/// The callback is called (in the client thread) for every message received on a subscription.
+pub fn start_client<F: Fn(Publish) + Send + 'static>(
+    settings: MqttSettings,
+    commands: Receiver<ClientCommand>,
+    state: ConnectionState,
+    on_message: F,
+) -> (StopFn, JoinHandle<()>) {
+    run_in_thread(
+        move |stop| {
+            let mut session = Session::default();
+
+            loop {
+                let disconnect = run_connection(&settings, &commands, &stop, &state, &on_message, &mut session);
+                state.set(false);
+
+                match disconnect {
+                    Disconnect::Stopped => break,
+                    Disconnect::Lost => {
+                        if stop
+                            .recv_timeout(Duration::from_secs(settings.reconnect_interval))
+                            .unwrap_or(false)
+                        {
+                            break;
+                        }
+                    }
+                }
+            }
+        },
+        String::from("MQTT client"),
+    )
+}
+
+fn run_connection<F: Fn(Publish)>(
+    settings: &MqttSettings,
+    commands: &Receiver<ClientCommand>,
+    stop: &Receiver<bool>,
+    state: &ConnectionState,
+    on_message: &F,
+    session: &mut Session,
+) -> Disconnect {
+    let mut connection = match Connection::open(settings) {
+        Ok(connection) => connection,
+        Err(error) => {
+            eprintln!("[WARN] [MQTT Client]: Could not connect to {}:{}", settings.host, settings.port);
+            eprintln!("{}", error);
+
+            return Disconnect::Lost;
+        }
+    };
+
+    println!("[MQTT Client]: Connected to {}:{}", settings.host, settings.port);
+
+    // Commands sent while disconnected: the subscriptions are sent below, the messages are outdated
+    loop {
+        match commands.try_recv() {
+            Ok(ClientCommand::Publish(publish)) => {
+                eprintln!("[WARN] [MQTT Client]: Dropping message to {} sent while disconnected", publish.topic);
+            }
+            Ok(ClientCommand::Subscribe(filter)) => {
+                if !session.subscriptions.contains(&filter) {
+                    session.subscriptions.push(filter);
+                }
+            }
+            Ok(ClientCommand::Unsubscribe(filter)) => session.subscriptions.retain(|subscription| subscription.ne(&filter)),
+            Err(TryRecvError::Empty) => break,
+            Err(TryRecvError::Disconnected) => {
+                connection.send(Packet::Disconnect).unwrap_or_default();
+
+                return Disconnect::Stopped;
+            }
+        }
+    }
+
+    state.set(true);
+
+    if !session.subscriptions.is_empty() {
+        let filters = session.subscriptions.iter().map(|filter| (filter.clone(), 1)).collect();
+
+        if connection.send_subscribe(session, filters).is_err() {
+            return Disconnect::Lost;
+        }
+    }
+
+    // The messages which were not acknowledged before the connection was lost
+    if !session.in_flight.is_empty() {
+        println!("[MQTT Client]: Sending {} unacknowledged messages again", session.in_flight.len());
+
+        if connection.resend(session, Duration::from_secs(0)).is_err() {
+            return Disconnect::Lost;
+        }
+    }
+
+    let keep_alive = Duration::from_secs(settings.keep_alive as u64);
+
+    loop {
+        if stop.try_recv().unwrap_or(false) {
+            connection.send(Packet::Disconnect).unwrap_or_default();
+
+            return Disconnect::Stopped;
+        }
+
+        loop {
+            let result = match commands.try_recv() {
+                Ok(ClientCommand::Publish(publish)) => connection.send_publish(session, publish),
+                Ok(ClientCommand::Subscribe(filter)) => {
+                    if session.subscriptions.contains(&filter) {
+                        continue;
+                    }
+
+                    session.subscriptions.push(filter.clone());
+                    connection.send_subscribe(session, vec![(filter, 1)])
+                }
+                Ok(ClientCommand::Unsubscribe(filter)) => {
+                    session.subscriptions.retain(|subscription| subscription.ne(&filter));
+                    let packet_id = session.packet_id();
+
+                    connection.send(Packet::Unsubscribe { packet_id, filters: vec![filter] })
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    connection.send(Packet::Disconnect).unwrap_or_default();
+
+                    return Disconnect::Stopped;
+                }
+            };
+
+            if let Err(error) = result {
+                eprintln!("[ERROR] [MQTT Client]: Could not send to broker");
+                eprintln!("{}", error);
+
+                return Disconnect::Lost;
+            }
+        }
+
+        if connection.resend(session, RETRY_INTERVAL).is_err() {
+            return Disconnect::Lost;
+        }
+
+        if keep_alive.as_secs() > 0
+            && connection.last_sent.elapsed() >= keep_alive / 2
+            && connection.send(Packet::PingReq).is_err()
+        {
+            return Disconnect::Lost;
+        }
+
+        let packets = match connection.receive() {
+            Ok(packets) => packets,
+            Err(error) => {
+                eprintln!("[WARN] [MQTT Client]: Connection to broker lost");
+                eprintln!("{}", error);
+
+                return Disconnect::Lost;
+            }
+        };
+
+        for packet in packets {
+            let result = match packet {
+                Packet::Publish(publish) => {
+                    let ack = match (publish.qos, publish.packet_id) {
+                        (1, Some(packet_id)) => connection.send(Packet::PubAck(packet_id)),
+                        (2, Some(packet_id)) => connection.send(Packet::PubRec(packet_id)),
+                        _ => Ok(()),
+                    };
+
+                    on_message(publish);
+
+                    ack
+                }
+                Packet::PubAck(packet_id) | Packet::PubComp(packet_id) => {
+                    session.acknowledge(packet_id);
+
+                    Ok(())
+                }
+                Packet::PubRec(packet_id) => {
+                    session.received(packet_id);
+
+                    connection.send(Packet::PubRel(packet_id))
+                }
+                Packet::PubRel(packet_id) => connection.send(Packet::PubComp(packet_id)),
+                _ => Ok(()),
+            };
+
+            if result.is_err() {
+                return Disconnect::Lost;
+            }
+        }
+    }
+}
+
+impl ConnectionState {
+    pub fn is_connected(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    fn set(&self, connected: bool) {
+        self.0.store(connected, Ordering::SeqCst);
+    }
+}
+
+impl Session {
+    /// The next packet id, which is not used by a message in flight
+    fn packet_id(&mut self) -> u16 {
+        loop {
+            self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+
+            if !self.in_flight.iter().any(|message| message.publish.packet_id == Some(self.next_packet_id)) {
+                return self.next_packet_id;
+            }
+        }
+    }
+
+    /// The message is complete (PUBACK for QoS 1, PUBCOMP for QoS 2)
+    fn acknowledge(&mut self, packet_id: u16) {
+        self.in_flight.retain(|message| message.publish.packet_id != Some(packet_id));
+    }
+
+    /// The broker received the QoS 2 message (PUBREC) and the release is sent
+    fn received(&mut self, packet_id: u16) {
+        let message = self
+            .in_flight
+            .iter_mut()
+            .find(|message| message.publish.qos == 2 && message.publish.packet_id == Some(packet_id));
+
+        if let Some(message) = message {
+            message.received = true;
+            message.sent = Instant::now();
+        }
+    }
+}
+
+impl Connection {
+    fn open(settings: &MqttSettings) -> std::io::Result<Self> {
+        let stream = TcpStream::connect((settings.host.as_str(), settings.port))?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+
+        let mut connection = Connection {
+            stream,
+            buffer: Vec::new(),
+            last_sent: Instant::now(),
+        };
+
+        connection.send(Packet::Connect(Connect {
+            client_id: settings.client_id.clone(),
+            keep_alive: settings.keep_alive,
+            clean_session: true,
+            username: Some(settings.username.clone()).filter(|username| !username.is_empty()),
+            password: Some(settings.password.clone().into_bytes()).filter(|password| !password.is_empty()),
+            will: None,
+        }))?;
+
+        loop {
+            match connection.receive()?.into_iter().next() {
+                Some(Packet::ConnAck { code: 0, .. }) => break,
+                Some(Packet::ConnAck { code, .. }) => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::ConnectionRefused,
+                        format!("Broker refused the connection with code {}", code),
+                    ));
+                }
+                Some(_) => {}
+                None => {
+                    return Err(std::io::Error::new(ErrorKind::TimedOut, "No CONNACK received"));
+                }
+            }
+        }
+
+        connection.stream.set_read_timeout(Some(Duration::from_millis(50)))?;
+
+        Ok(connection)
+    }
+
+    fn send(&mut self, packet: Packet) -> std::io::Result<()> {
+        self.stream.write_all(&packet.encode())?;
+        self.last_sent = Instant::now();
+
+        Ok(())
+    }
+
+    /// Publishes the message. Messages with QoS 1 or 2 are kept until they are acknowledged.
+    fn send_publish(&mut self, session: &mut Session, mut publish: Publish) -> std::io::Result<()> {
+        publish.dup = false;
+        publish.packet_id = match publish.qos {
+            0 => None,
+            _ => Some(session.packet_id()),
+        };
+
+        self.send(Packet::Publish(publish.clone()))?;
+
+        if publish.qos == 0 {
+            return Ok(());
+        }
+
+        if session.in_flight.len() >= MAX_IN_FLIGHT {
+            let dropped = session.in_flight.remove(0);
+
+            eprintln!("[WARN] [MQTT Client]: Dropping unacknowledged message to {}", dropped.publish.topic);
+        }
+
+        session.in_flight.push(InFlight {
+            publish,
+            received: false,
+            sent: Instant::now(),
+        });
+
+        Ok(())
+    }
+
+    /// Sends the messages which were not acknowledged within the interval again
+    fn resend(&mut self, session: &mut Session, interval: Duration) -> std::io::Result<()> {
+        for message in session.in_flight.iter_mut().filter(|message| message.sent.elapsed() >= interval) {
+            match (message.received, message.publish.packet_id) {
+                (true, Some(packet_id)) => self.send(Packet::PubRel(packet_id))?,
+                _ => {
+                    message.publish.dup = true;
+                    self.send(Packet::Publish(message.publish.clone()))?;
+                }
+            }
+
+            message.sent = Instant::now();
+        }
+
+        Ok(())
+    }
+
+    fn send_subscribe(&mut self, session: &mut Session, filters: Vec<(String, u8)>) -> std::io::Result<()> {
+        let packet_id = session.packet_id();
+
+        self.send(Packet::Subscribe { packet_id, filters })
+    }
+
+    /// Reads from the socket (until the read timeout) and returns all complete packets
+    fn receive(&mut self) -> std::io::Result<Vec<Packet>> {
+        let mut chunk = [0u8; 4096];
+
+        match self.stream.read(&mut chunk) {
+            Ok(0) => return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "Connection closed by broker")),
+            Ok(read) => self.buffer.extend_from_slice(&chunk[..read]),
+            Err(error) if error.kind() == ErrorKind::WouldBlock || error.kind() == ErrorKind::TimedOut => {}
+            Err(error) => return Err(error),
+        }
+
+        let mut packets = Vec::new();
+
+        while let Some(packet) = Packet::decode(&mut self.buffer)? {
+            packets.push(packet);
+        }
+
+        Ok(packets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+
+    use super::*;
+
+    /// The broker side of a connection of the client
+    struct Broker {
+        stream: TcpStream,
+        buffer: Vec<u8>,
+    }
+
+    impl Broker {
+        /// Accepts the connection of the client and its CONNECT
+        fn accept(listener: &TcpListener) -> Self {
+            let (stream, _) = listener.accept().unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+            let mut broker = Broker { stream, buffer: Vec::new() };
+
+            assert!(matches!(broker.receive(), Packet::Connect(_)));
+            broker.send(Packet::ConnAck { session_present: false, code: 0 });
+
+            broker
+        }
+
+        fn send(&mut self, packet: Packet) {
+            self.stream.write_all(&packet.encode()).unwrap();
+        }
+
+        fn receive(&mut self) -> Packet {
+            loop {
+                if let Some(packet) = Packet::decode(&mut self.buffer).unwrap() {
+                    return packet;
+                }
+
+                let mut chunk = [0u8; 1024];
+                let read = self.stream.read(&mut chunk).unwrap();
+
+                assert!(read > 0, "The client closed the connection");
+                self.buffer.extend_from_slice(&chunk[..read]);
+            }
+        }
+
+        fn receive_publish(&mut self) -> Publish {
+            match self.receive() {
+                Packet::Publish(publish) => publish,
+                packet => panic!("Expected a PUBLISH, got {:?}", packet),
+            }
+        }
+    }
+
+    fn publish(topic: &str, qos: u8) -> ClientCommand {
+        ClientCommand::Publish(Publish {
+            topic: String::from(topic),
+            payload: b"ON".to_vec(),
+            qos,
+            ..Default::default()
+        })
+    }
+
+    fn wait_until_connected(state: &ConnectionState) {
+        let start = Instant::now();
+
+        while !state.is_connected() {
+            assert!(start.elapsed() < Duration::from_secs(5), "The client did not connect");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn publishes_with_the_requested_qos_until_acknowledged() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let settings = MqttSettings {
+            port: listener.local_addr().unwrap().port(),
+            host: String::from("127.0.0.1"),
+            keep_alive: 0,
+            reconnect_interval: 0,
+            ..Default::default()
+        };
+        let (commands, receiver) = channel();
+        let state = ConnectionState::default();
+        let (stop, handle) = start_client(settings, receiver, state.clone(), |_| {});
+
+        let mut broker = Broker::accept(&listener);
+        wait_until_connected(&state);
+
+        commands.send(publish("light/set", 0)).unwrap();
+        let message = broker.receive_publish();
+        assert_eq!((message.qos, message.packet_id), (0, None));
+
+        // Not acknowledged before the connection is lost, so it is sent again after the reconnect
+        commands.send(publish("light/set", 1)).unwrap();
+        let message = broker.receive_publish();
+        assert_eq!(message.qos, 1);
+        assert!(!message.dup);
+        drop(broker);
+
+        let mut broker = Broker::accept(&listener);
+        let again = broker.receive_publish();
+        assert!(again.dup);
+        assert_eq!((again.qos, again.packet_id, again.payload), (1, message.packet_id, message.payload));
+        broker.send(Packet::PubAck(again.packet_id.unwrap()));
+
+        commands.send(publish("scene/set", 2)).unwrap();
+        let message = broker.receive_publish();
+        let packet_id = message.packet_id.unwrap();
+        assert_eq!(message.qos, 2);
+        assert_ne!(Some(packet_id), again.packet_id);
+        broker.send(Packet::PubRec(packet_id));
+        assert!(matches!(broker.receive(), Packet::PubRel(id) if id == packet_id));
+        drop(broker);
+
+        // Only the release of the received QoS 2 message is sent again, the others are complete
+        let mut broker = Broker::accept(&listener);
+        assert!(matches!(broker.receive(), Packet::PubRel(id) if id == packet_id));
+        broker.send(Packet::PubComp(packet_id));
+
+        stop();
+        handle.join().unwrap();
+        assert!(matches!(broker.receive(), Packet::Disconnect));
+    }
+}
@@ -0,0 +1,80 @@
+//! A small MQTT 3.1.1 implementation used by the server to talk to the broker
+//!
+//! The connection is handled in its own thread (see [client::start_client]) so the actors never
+//! block on the network. Optionally the server runs its own broker (see [broker::Broker]).
+
+pub mod broker;
+pub mod client;
+pub mod packet;
+
+/// Checks if the topic matches the given subscription filter.
+/// Supports the single level (`+`) and multi level (`#`) wildcards.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+
+    // Topics starting with "$" are reserved and not matched by leading wildcards
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(filter_level), Some(topic_level)) => {
+                if filter_level != topic_level {
+                    return false;
+                }
+            }
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::topic_matches;
+
+    #[test]
+    fn exact_topics() {
+        assert!(topic_matches("home/kitchen/light", "home/kitchen/light"));
+        assert!(!topic_matches("home/kitchen/light", "home/kitchen"));
+        assert!(!topic_matches("home/kitchen", "home/kitchen/light"));
+        assert!(!topic_matches("home/kitchen", "home/Kitchen"));
+        assert!(!topic_matches("home/kitchen", "home/kitchen/"));
+    }
+
+    #[test]
+    fn single_level_wildcard() {
+        assert!(topic_matches("home/+/light", "home/kitchen/light"));
+        assert!(topic_matches("+/+", "home/kitchen"));
+        assert!(topic_matches("home/+", "home/"));
+        assert!(topic_matches("+", ""));
+        assert!(topic_matches("+/kitchen", "/kitchen"));
+        assert!(!topic_matches("home/+", "home/kitchen/light"));
+        assert!(!topic_matches("home/+", "home"));
+        assert!(!topic_matches("home/+/light", "home/light"));
+    }
+
+    #[test]
+    fn multi_level_wildcard() {
+        assert!(topic_matches("#", "home/kitchen/light"));
+        assert!(topic_matches("#", "/"));
+        assert!(topic_matches("home/#", "home/kitchen/light"));
+        assert!(topic_matches("home/+/#", "home/kitchen/light/state"));
+        // Also matches the parent level itself
+        assert!(topic_matches("home/#", "home"));
+        assert!(!topic_matches("home/#", "garden/light"));
+        assert!(!topic_matches("home/kitchen/#", "home"));
+    }
+
+    #[test]
+    fn reserved_topics() {
+        assert!(!topic_matches("#", "$SYS/broker/uptime"));
+        assert!(!topic_matches("+/broker/uptime", "$SYS/broker/uptime"));
+        assert!(topic_matches("$SYS/#", "$SYS/broker/uptime"));
+        assert!(topic_matches("$SYS/+/uptime", "$SYS/broker/uptime"));
+    }
+}
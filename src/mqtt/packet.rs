@@ -0,0 +1,591 @@
+//! Encoding and decoding of MQTT 3.1.1 control packets.
+//!
+//! Only the parts of the protocol that are needed by the server are implemented. Packets are
+//! decoded from a growing byte buffer, so partial reads from a socket can simply be appended
+//! until a full packet is available.
+
+use std::io::{Error, ErrorKind};
+
+/// All control packets of MQTT 3.1.1
+#[derive(Clone, Debug)]
+pub enum Packet {
+    Connect(Connect),
+    ConnAck { session_present: bool, code: u8 },
+    Publish(Publish),
+    PubAck(u16),
+    PubRec(u16),
+    PubRel(u16),
+    PubComp(u16),
+    Subscribe { packet_id: u16, filters: Vec<(String, u8)> },
+    SubAck { packet_id: u16, codes: Vec<u8> },
+    Unsubscribe { packet_id: u16, filters: Vec<String> },
+    UnsubAck(u16),
+    PingReq,
+    PingResp,
+    Disconnect,
+}
+
+/// The CONNECT packet sent by a client to open a session
+#[derive(Clone, Debug, Default)]
+pub struct Connect {
+    pub client_id: String,
+    pub keep_alive: u16,
+    pub clean_session: bool,
+    pub username: Option<String>,
+    pub password: Option<Vec<u8>>,
+    pub will: Option<Publish>,
+}
+
+/// A single application message
+#[derive(Clone, Debug, Default)]
+pub struct Publish {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: u8,
+    pub retain: bool,
+    pub dup: bool,
+    pub packet_id: Option<u16>,
+}
+
+impl Packet {
+    /// Encodes the packet including its fixed header
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+
+        let header = match self {
+            Packet::Connect(connect) => {
+                write_string(&mut body, "MQTT");
+                body.push(4);
+
+                let mut flags = 0u8;
+
+                if connect.clean_session {
+                    flags |= 0x02;
+                }
+
+                if let Some(will) = &connect.will {
+                    flags |= 0x04 | (will.qos.min(2) << 3);
+
+                    if will.retain {
+                        flags |= 0x20;
+                    }
+                }
+
+                if connect.password.is_some() {
+                    flags |= 0x40;
+                }
+
+                if connect.username.is_some() {
+                    flags |= 0x80;
+                }
+
+                body.push(flags);
+                body.extend_from_slice(&connect.keep_alive.to_be_bytes());
+                write_string(&mut body, &connect.client_id);
+
+                if let Some(will) = &connect.will {
+                    write_string(&mut body, &will.topic);
+                    write_bytes(&mut body, &will.payload);
+                }
+
+                if let Some(username) = &connect.username {
+                    write_string(&mut body, username);
+                }
+
+                if let Some(password) = &connect.password {
+                    write_bytes(&mut body, password);
+                }
+
+                0x10
+            }
+            Packet::ConnAck { session_present, code } => {
+                body.push(*session_present as u8);
+                body.push(*code);
+
+                0x20
+            }
+            Packet::Publish(publish) => {
+                write_string(&mut body, &publish.topic);
+
+                if publish.qos > 0 {
+                    body.extend_from_slice(&publish.packet_id.unwrap_or(1).to_be_bytes());
+                }
+
+                body.extend_from_slice(&publish.payload);
+
+                let mut header = 0x30 | (publish.qos.min(2) << 1);
+
+                if publish.dup {
+                    header |= 0x08;
+                }
+
+                if publish.retain {
+                    header |= 0x01;
+                }
+
+                header
+            }
+            Packet::PubAck(packet_id) => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+
+                0x40
+            }
+            Packet::PubRec(packet_id) => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+
+                0x50
+            }
+            Packet::PubRel(packet_id) => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+
+                0x62
+            }
+            Packet::PubComp(packet_id) => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+
+                0x70
+            }
+            Packet::Subscribe { packet_id, filters } => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+
+                for (filter, qos) in filters {
+                    write_string(&mut body, filter);
+                    body.push(*qos);
+                }
+
+                0x82
+            }
+            Packet::SubAck { packet_id, codes } => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                body.extend_from_slice(codes);
+
+                0x90
+            }
+            Packet::Unsubscribe { packet_id, filters } => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+
+                for filter in filters {
+                    write_string(&mut body, filter);
+                }
+
+                0xa2
+            }
+            Packet::UnsubAck(packet_id) => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+
+                0xb0
+            }
+            Packet::PingReq => 0xc0,
+            Packet::PingResp => 0xd0,
+            Packet::Disconnect => 0xe0,
+        };
+
+        let mut packet = vec![header];
+        let mut length = body.len();
+
+        loop {
+            let mut byte = (length % 128) as u8;
+            length /= 128;
+
+            if length > 0 {
+                byte |= 0x80;
+            }
+
+            packet.push(byte);
+
+            if length == 0 {
+                break;
+            }
+        }
+
+        packet.extend(body);
+
+        packet
+    }
+
+    /// Takes the first complete packet from the buffer.
+    ///
+    /// Returns `Ok(None)` if the buffer does not contain a complete packet yet.
+    pub fn decode(buffer: &mut Vec<u8>) -> Result<Option<Packet>, Error> {
+        Self::decode_limited(buffer, usize::MAX)
+    }
+
+    /// Takes the first complete packet from the buffer like [Packet::decode]. Packets larger than
+    /// `max_size` bytes (including the fixed header) are rejected as soon as their length is
+    /// known, before they are received completely.
+    pub fn decode_limited(buffer: &mut Vec<u8>, max_size: usize) -> Result<Option<Packet>, Error> {
+        if buffer.len() < 2 {
+            return Ok(None);
+        }
+
+        let mut length = 0usize;
+        let mut multiplier = 1usize;
+        let mut offset = 1usize;
+
+        loop {
+            let byte = match buffer.get(offset) {
+                Some(byte) => *byte,
+                None => return Ok(None),
+            };
+
+            length += (byte & 0x7f) as usize * multiplier;
+            multiplier *= 128;
+            offset += 1;
+
+            if byte & 0x80 == 0 {
+                break;
+            }
+
+            if offset > 4 {
+                return Err(malformed("remaining length exceeds four bytes"));
+            }
+        }
+
+        if offset + length > max_size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("MQTT packet of {} bytes exceeds the maximum of {} bytes", offset + length, max_size),
+            ));
+        }
+
+        if buffer.len() < offset + length {
+            return Ok(None);
+        }
+
+        let header = buffer[0];
+        let body: Vec<u8> = buffer.drain(..offset + length).skip(offset).collect();
+        let mut reader = Reader { data: &body, position: 0 };
+
+        let packet = match header >> 4 {
+            1 => {
+                if reader.string()? != "MQTT" || reader.byte()? != 4 {
+                    return Err(malformed("unsupported protocol"));
+                }
+
+                let flags = reader.byte()?;
+                let keep_alive = reader.u16()?;
+                let client_id = reader.string()?;
+                let will = if flags & 0x04 != 0 {
+                    Some(Publish {
+                        topic: reader.string()?,
+                        payload: reader.bytes()?,
+                        qos: (flags >> 3) & 0x03,
+                        retain: flags & 0x20 != 0,
+                        ..Default::default()
+                    })
+                } else {
+                    None
+                };
+                let username = if flags & 0x80 != 0 { Some(reader.string()?) } else { None };
+                let password = if flags & 0x40 != 0 { Some(reader.bytes()?) } else { None };
+
+                Packet::Connect(Connect {
+                    client_id,
+                    keep_alive,
+                    clean_session: flags & 0x02 != 0,
+                    username,
+                    password,
+                    will,
+                })
+            }
+            2 => Packet::ConnAck {
+                session_present: reader.byte()? & 0x01 != 0,
+                code: reader.byte()?,
+            },
+            3 => {
+                let qos = (header >> 1) & 0x03;
+                let topic = reader.string()?;
+                let packet_id = if qos > 0 { Some(reader.u16()?) } else { None };
+
+                Packet::Publish(Publish {
+                    topic,
+                    payload: reader.rest(),
+                    qos,
+                    retain: header & 0x01 != 0,
+                    dup: header & 0x08 != 0,
+                    packet_id,
+                })
+            }
+            4 => Packet::PubAck(reader.u16()?),
+            5 => Packet::PubRec(reader.u16()?),
+            6 => Packet::PubRel(reader.u16()?),
+            7 => Packet::PubComp(reader.u16()?),
+            8 => {
+                let packet_id = reader.u16()?;
+                let mut filters = Vec::new();
+
+                while !reader.is_empty() {
+                    filters.push((reader.string()?, reader.byte()?));
+                }
+
+                Packet::Subscribe { packet_id, filters }
+            }
+            9 => Packet::SubAck {
+                packet_id: reader.u16()?,
+                codes: reader.rest(),
+            },
+            10 => {
+                let packet_id = reader.u16()?;
+                let mut filters = Vec::new();
+
+                while !reader.is_empty() {
+                    filters.push(reader.string()?);
+                }
+
+                Packet::Unsubscribe { packet_id, filters }
+            }
+            11 => Packet::UnsubAck(reader.u16()?),
+            12 => Packet::PingReq,
+            13 => Packet::PingResp,
+            14 => Packet::Disconnect,
+            other => return Err(malformed(format!("unknown packet type {}", other))),
+        };
+
+        Ok(Some(packet))
+    }
+}
+
+/// Sequential reader over the variable header and payload of a packet
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, Error> {
+        let byte = *self.data.get(self.position).ok_or_else(|| malformed("unexpected end of packet"))?;
+        self.position += 1;
+
+        Ok(byte)
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_be_bytes([self.byte()?, self.byte()?]))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, Error> {
+        let length = self.u16()? as usize;
+
+        if self.position + length > self.data.len() {
+            return Err(malformed("unexpected end of packet"));
+        }
+
+        let bytes = self.data[self.position..self.position + length].to_vec();
+        self.position += length;
+
+        Ok(bytes)
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        String::from_utf8(self.bytes()?).map_err(|_| malformed("invalid utf-8 string"))
+    }
+
+    fn rest(&mut self) -> Vec<u8> {
+        let rest = self.data[self.position.min(self.data.len())..].to_vec();
+        self.position = self.data.len();
+
+        rest
+    }
+
+    fn is_empty(&self) -> bool {
+        self.position >= self.data.len()
+    }
+}
+
+fn write_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    buffer.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(bytes);
+}
+
+fn write_string(buffer: &mut Vec<u8>, string: &str) {
+    write_bytes(buffer, string.as_bytes());
+}
+
+fn malformed(reason: impl ToString) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Malformed MQTT packet: {}", reason.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes the encoded packet, checks that it used the whole buffer and encodes it again
+    fn round_trip(packet: &Packet) -> Packet {
+        let mut buffer = packet.encode();
+        let decoded = Packet::decode(&mut buffer).unwrap().unwrap();
+
+        assert!(buffer.is_empty());
+        assert_eq!(decoded.encode(), packet.encode());
+
+        decoded
+    }
+
+    #[test]
+    fn connect_round_trip() {
+        let connect = Packet::Connect(Connect {
+            client_id: String::from("kitchen"),
+            keep_alive: 30,
+            clean_session: true,
+            username: Some(String::from("user")),
+            password: Some(b"secret".to_vec()),
+            will: Some(Publish {
+                topic: String::from("home/kitchen/status"),
+                payload: b"offline".to_vec(),
+                qos: 1,
+                retain: true,
+                ..Default::default()
+            }),
+        });
+
+        match round_trip(&connect) {
+            Packet::Connect(connect) => {
+                assert_eq!(connect.client_id, "kitchen");
+                assert_eq!(connect.keep_alive, 30);
+                assert!(connect.clean_session);
+                assert_eq!(connect.username.as_deref(), Some("user"));
+                assert_eq!(connect.password.as_deref(), Some(&b"secret"[..]));
+
+                let will = connect.will.unwrap();
+
+                assert_eq!(will.topic, "home/kitchen/status");
+                assert_eq!(will.payload, b"offline");
+                assert_eq!(will.qos, 1);
+                assert!(will.retain);
+            }
+            other => panic!("Expected CONNECT, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn publish_round_trip() {
+        let publish = Packet::Publish(Publish {
+            topic: String::from("home/light/set"),
+            payload: b"ON".to_vec(),
+            qos: 2,
+            retain: true,
+            dup: true,
+            packet_id: Some(513),
+        });
+
+        match round_trip(&publish) {
+            Packet::Publish(publish) => {
+                assert_eq!(publish.topic, "home/light/set");
+                assert_eq!(publish.payload, b"ON");
+                assert_eq!(publish.qos, 2);
+                assert!(publish.retain);
+                assert!(publish.dup);
+                assert_eq!(publish.packet_id, Some(513));
+            }
+            other => panic!("Expected PUBLISH, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn other_packets_round_trip() {
+        let packets = vec![
+            Packet::ConnAck { session_present: true, code: 5 },
+            Packet::PubAck(1),
+            Packet::PubRec(2),
+            Packet::PubRel(3),
+            Packet::PubComp(4),
+            Packet::Subscribe { packet_id: 5, filters: vec![(String::from("a/+"), 1), (String::from("b/#"), 0)] },
+            Packet::SubAck { packet_id: 5, codes: vec![1, 0x80] },
+            Packet::Unsubscribe { packet_id: 6, filters: vec![String::from("a/+")] },
+            Packet::UnsubAck(6),
+            Packet::PingReq,
+            Packet::PingResp,
+            Packet::Disconnect,
+        ];
+
+        for packet in &packets {
+            round_trip(packet);
+        }
+    }
+
+    #[test]
+    fn long_remaining_length() {
+        let publish = Packet::Publish(Publish {
+            topic: String::from("camera/snapshot"),
+            payload: vec![7; 20_000],
+            ..Default::default()
+        });
+        let encoded = publish.encode();
+
+        // 20017 bytes need three bytes for the remaining length
+        assert_eq!(&encoded[1..4], &[0xb1, 0x9c, 0x01]);
+
+        match round_trip(&publish) {
+            Packet::Publish(publish) => assert_eq!(publish.payload.len(), 20_000),
+            other => panic!("Expected PUBLISH, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn partial_packets_wait_for_more_data() {
+        let encoded = Packet::Subscribe { packet_id: 1, filters: vec![(String::from("home/#"), 1)] }.encode();
+
+        for length in 0..encoded.len() {
+            let mut buffer = encoded[..length].to_vec();
+
+            assert!(Packet::decode(&mut buffer).unwrap().is_none());
+            assert_eq!(buffer.len(), length);
+        }
+    }
+
+    #[test]
+    fn decodes_consecutive_packets() {
+        let mut buffer = Packet::PingReq.encode();
+        buffer.extend(Packet::PubAck(9).encode());
+        buffer.extend(&Packet::Disconnect.encode()[..1]);
+
+        assert!(matches!(Packet::decode(&mut buffer), Ok(Some(Packet::PingReq))));
+        assert!(matches!(Packet::decode(&mut buffer), Ok(Some(Packet::PubAck(9)))));
+        assert!(matches!(Packet::decode(&mut buffer), Ok(None)));
+        assert_eq!(buffer, vec![0xe0]);
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        let malformed: Vec<Vec<u8>> = vec![
+            // Remaining length with more than four bytes
+            vec![0x30, 0xff, 0xff, 0xff, 0xff, 0x01],
+            // Unknown packet type
+            vec![0xf0, 0x00],
+            // Topic length longer than the packet
+            vec![0x30, 0x03, 0x00, 0x05, b'a'],
+            // Topic which is no valid UTF-8
+            vec![0x30, 0x04, 0x00, 0x02, 0xc3, 0x28],
+            // PUBACK without packet id
+            vec![0x40, 0x00],
+            // CONNECT of another protocol
+            vec![0x10, 0x07, 0x00, 0x04, b'M', b'Q', b'I', b's', 0x03],
+            // SUBSCRIBE with a filter but without its QoS
+            vec![0x82, 0x05, 0x00, 0x01, 0x00, 0x01, b'a'],
+        ];
+
+        for packet in malformed {
+            let mut buffer = packet.clone();
+            let error = Packet::decode(&mut buffer).expect_err(&format!("{:x?} should be rejected", packet));
+
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn rejects_packets_larger_than_the_limit() {
+        let encoded = Packet::Publish(Publish {
+            topic: String::from("a"),
+            payload: vec![0; 100],
+            ..Default::default()
+        })
+        .encode();
+
+        assert!(Packet::decode_limited(&mut encoded.clone(), encoded.len()).unwrap().is_some());
+        assert!(Packet::decode_limited(&mut encoded.clone(), encoded.len() - 1).is_err());
+
+        // Only the fixed header of a huge packet has to be received to reject it
+        let mut header = vec![0x30, 0xff, 0xff, 0xff, 0x7f];
+
+        assert_eq!(Packet::decode_limited(&mut header, 1024 * 1024).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
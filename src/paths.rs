@@ -0,0 +1,183 @@
+//! The directories the server reads its files from
+//!
+//! The configuration (`settings.yaml`, `web_settings.yaml`) and the state (dashboards, groups,
+//! shortcuts, rules, users, history, backups and the database) are kept in separate directories.
+//! They are taken from the command line (`--config <dir>`, `--data-dir <dir>`), the environment
+//! (`NEW_HOME_CONFIG_DIR`, `NEW_HOME_DATA_DIR`) or default to the XDG directories
+//! (`~/.config/new-home-mqtt-server`, `~/.local/share/new-home-mqtt-server`).
+//!
+//! If there is a `settings.yaml` in the working directory and nothing is configured, the working
+//! directory is used for both, as in older versions.
+
+use std::env::{current_dir, var_os};
+use std::fs::{copy, create_dir_all, read_dir, remove_dir_all, remove_file, rename};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+const APP_NAME: &str = "new-home-mqtt-server";
+
+/// The files which are part of the configuration. All others are state.
+const CONFIG_FILES: [&str; 2] = ["settings.yaml", "web_settings.yaml"];
+
+/// The directories, set once on startup
+static PATHS: OnceLock<Paths> = OnceLock::new();
+
+#[derive(Clone, Debug)]
+pub struct Paths {
+    pub config_dir: PathBuf,
+    pub data_dir: PathBuf,
+}
+
+impl Paths {
+    /// Resolves the directories. Explicitly given directories take precedence over the
+    /// environment, which takes precedence over the defaults.
+    pub fn resolve(config_dir: Option<PathBuf>, data_dir: Option<PathBuf>) -> Self {
+        let config_dir = config_dir.or_else(|| env_dir("NEW_HOME_CONFIG_DIR"));
+        let data_dir = data_dir.or_else(|| env_dir("NEW_HOME_DATA_DIR"));
+        let working_dir = current_dir().unwrap();
+
+        if config_dir.is_none() && data_dir.is_none() && working_dir.join("settings.yaml").exists() {
+            return Self {
+                config_dir: working_dir.clone(),
+                data_dir: working_dir,
+            };
+        }
+
+        Self {
+            config_dir: config_dir.unwrap_or_else(|| xdg_dir("XDG_CONFIG_HOME", ".config")),
+            data_dir: data_dir.unwrap_or_else(|| xdg_dir("XDG_DATA_HOME", ".local/share")),
+        }
+    }
+}
+
+fn env_dir(name: &str) -> Option<PathBuf> {
+    var_os(name).filter(|value| !value.is_empty()).map(PathBuf::from)
+}
+
+/// `$XDG_*_HOME/new-home-mqtt-server` or `$HOME/<fallback>/new-home-mqtt-server`. Without a home
+/// directory the working directory is used.
+fn xdg_dir(variable: &str, fallback: &str) -> PathBuf {
+    if let Some(directory) = env_dir(variable) {
+        return directory.join(APP_NAME);
+    }
+
+    match env_dir("HOME") {
+        Some(home) => home.join(fallback).join(APP_NAME),
+        None => current_dir().unwrap(),
+    }
+}
+
+/// Sets the directories and creates them. Has to be called before any file is read.
+pub fn configure_paths(paths: Paths) {
+    for directory in [&paths.config_dir, &paths.data_dir] {
+        if let Err(error) = create_dir_all(directory) {
+            eprintln!("[ERROR] [Paths]: Could not create {}", directory.display());
+            eprintln!("{}", error);
+        }
+    }
+
+    println!("[Paths]: Config in {}, data in {}", paths.config_dir.display(), paths.data_dir.display());
+
+    if PATHS.set(paths).is_err() {
+        eprintln!("[WARN] [Paths]: The paths are already configured");
+    }
+}
+
+pub fn paths() -> &'static Paths {
+    PATHS.get_or_init(default_paths)
+}
+
+#[cfg(not(test))]
+fn default_paths() -> Paths {
+    Paths::resolve(None, None)
+}
+
+/// The tests use a temporary directory, so they never touch the real files
+#[cfg(test)]
+fn default_paths() -> Paths {
+    let directory = std::env::temp_dir().join(format!("new-home-test-{}", std::process::id()));
+
+    create_dir_all(&directory).unwrap();
+
+    Paths {
+        config_dir: directory.clone(),
+        data_dir: directory,
+    }
+}
+
+/// A file in the config directory. Absolute paths are kept.
+pub fn config_path<P: AsRef<Path>>(name: P) -> PathBuf {
+    paths().config_dir.join(name)
+}
+
+/// A file in the data directory. Absolute paths are kept.
+pub fn data_path<P: AsRef<Path>>(name: P) -> PathBuf {
+    paths().data_dir.join(name)
+}
+
+/// The path of a config or data file by its name
+pub fn file_path(name: &str) -> PathBuf {
+    if CONFIG_FILES.contains(&name) {
+        return config_path(name);
+    }
+
+    data_path(name)
+}
+
+/// Moves the data files and directories, which older versions kept next to the settings, into the
+/// data directory
+pub fn migrate_data_files(names: &[String]) {
+    let paths = paths();
+
+    if paths.config_dir.eq(&paths.data_dir) {
+        return;
+    }
+
+    for name in names {
+        let old_path = paths.config_dir.join(name);
+        let new_path = paths.data_dir.join(name);
+
+        if !old_path.exists() || new_path.exists() {
+            continue;
+        }
+
+        println!("[Paths]: Moving {} to {}", old_path.display(), new_path.display());
+
+        if let Err(error) = move_path(&old_path, &new_path) {
+            eprintln!("[ERROR] [Paths]: Could not move {}", old_path.display());
+            eprintln!("{}", error);
+        }
+    }
+}
+
+/// Moves the file or directory. Renaming fails across filesystems, so it is copied then.
+fn move_path(old_path: &Path, new_path: &Path) -> std::io::Result<()> {
+    if rename(old_path, new_path).is_ok() {
+        return Ok(());
+    }
+
+    if !old_path.is_dir() {
+        return copy(old_path, new_path).and_then(|_| remove_file(old_path));
+    }
+
+    copy_dir(old_path, new_path)?;
+
+    remove_dir_all(old_path)
+}
+
+fn copy_dir(old_path: &Path, new_path: &Path) -> std::io::Result<()> {
+    create_dir_all(new_path)?;
+
+    for entry in read_dir(old_path)? {
+        let entry = entry?;
+        let target = new_path.join(entry.file_name());
+
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            copy(entry.path(), target)?;
+        }
+    }
+
+    Ok(())
+}
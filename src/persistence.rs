@@ -0,0 +1,297 @@
+//! Crash safe persistence of the YAML files
+//!
+//! Files are never written in place. The data is written to a temporary file next to the target,
+//! synced to disk and then renamed over the target. A crash or power cut leaves either the old or
+//! the new file, but never a partially written one.
+//!
+//! Before a file is changed, a timestamped snapshot of it is put into the backup directory (see
+//! [configure_backups]). Files which could not be parsed when loading are never overwritten, so a
+//! bad hand edit does not destroy the data. It can be fixed and reloaded or a snapshot restored.
+
+use std::fmt::{Display, Formatter};
+use std::fs::{create_dir_all, read, read_dir, remove_file, rename, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::settings::BackupSettings;
+
+/// The backup settings, set once on startup
+static BACKUPS: OnceLock<BackupSettings> = OnceLock::new();
+
+/// Files which could not be parsed when they were loaded
+static UNPARSED: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Why loading or saving a file failed
+#[derive(Debug)]
+pub enum PersistenceError {
+    Serialize(serde_yaml::Error),
+    Io(std::io::Error),
+
+    /// The file could not be parsed when it was loaded, so it is not overwritten
+    Unparsed(PathBuf),
+
+    /// There is no snapshot with this name
+    UnknownSnapshot(String),
+
+    /// The snapshot is no valid YAML
+    InvalidSnapshot(serde_yaml::Error),
+}
+
+/// A backup of a file
+#[derive(Serialize, Clone, Debug)]
+pub struct Snapshot {
+    /// The name of the snapshot (used to restore it)
+    pub name: String,
+
+    /// The file it is a backup of
+    pub file: String,
+
+    /// When the snapshot was taken (`YYYYMMDD-HHMMSS-mmm`, UTC)
+    pub created: String,
+}
+
+/// Sets where and how many snapshots are kept. Has to be called before anything is saved,
+/// otherwise the defaults are used.
+pub fn configure_backups(settings: BackupSettings) {
+    if BACKUPS.set(settings).is_err() {
+        eprintln!("[WARN] [Persistence]: Backups are already configured");
+    }
+}
+
+#[cfg(not(test))]
+fn backup_settings() -> &'static BackupSettings {
+    BACKUPS.get_or_init(BackupSettings::default)
+}
+
+/// The tests keep their snapshots in their temporary data directory
+#[cfg(test)]
+fn backup_settings() -> &'static BackupSettings {
+    BACKUPS.get_or_init(|| BackupSettings {
+        directory: crate::paths::data_path("backups").to_string_lossy().to_string(),
+        ..BackupSettings::default()
+    })
+}
+
+/// Loads the YAML file. If it does not exist or can not be parsed, the default is returned.
+/// Files which can not be parsed are not overwritten until they were loaded successfully.
+pub fn load_yaml<T: DeserializeOwned + Default>(path: &Path, label: &str) -> T {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(error) => {
+            eprintln!("[WARN] [{}]: Could not open file", label);
+            eprintln!("{}", error);
+
+            if error.kind() != ErrorKind::NotFound {
+                mark_unparsed(path, true);
+            }
+
+            return T::default();
+        }
+    };
+
+    match serde_yaml::from_reader(file) {
+        Ok(data) => {
+            mark_unparsed(path, false);
+
+            data
+        }
+        Err(error) => {
+            eprintln!("[ERROR] [{}]: Could not parse file. It will not be changed until it is fixed and reloaded", label);
+            eprintln!("{}", error);
+
+            mark_unparsed(path, true);
+
+            T::default()
+        }
+    }
+}
+
+/// Checks if the file could not be parsed when it was loaded the last time
+pub fn is_unparsed(path: &Path) -> bool {
+    UNPARSED
+        .lock()
+        .map(|unparsed| unparsed.iter().any(|unparsed| unparsed.eq(path)))
+        .unwrap_or(false)
+}
+
+/// Sets whether the file could not be parsed, e.g. because its content does not match the data
+pub fn mark_unparsed(path: &Path, unparsed: bool) {
+    if let Ok(mut list) = UNPARSED.lock() {
+        list.retain(|entry| entry.ne(path));
+
+        if unparsed {
+            list.push(path.to_path_buf());
+        }
+    }
+}
+
+/// Serializes the data as YAML and writes it atomically to the file (after taking a snapshot)
+pub fn save_yaml<T: Serialize + ?Sized>(path: &Path, data: &T) -> Result<(), PersistenceError> {
+    if is_unparsed(path) {
+        return Err(PersistenceError::Unparsed(path.to_path_buf()));
+    }
+
+    let content = serde_yaml::to_string(data).map_err(PersistenceError::Serialize)?;
+
+    take_snapshot(path, content.as_bytes()).map_err(PersistenceError::Io)?;
+    write_atomic(path, content.as_bytes()).map_err(PersistenceError::Io)
+}
+
+/// Writes the content to a temporary file, syncs it and renames it to the target path
+pub fn write_atomic(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let temp_path = temp_path(path);
+    let result = (|| {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_path)?;
+
+        file.write_all(content)?;
+        file.sync_all()?;
+
+        rename(&temp_path, path)
+    })();
+
+    if result.is_err() {
+        remove_file(&temp_path).unwrap_or_default();
+    }
+
+    result?;
+    sync_directory(path);
+
+    Ok(())
+}
+
+/// The temporary file is in the same directory, as renaming only is atomic on the same filesystem
+fn temp_path(path: &Path) -> PathBuf {
+    path.with_file_name(format!(".{}.tmp", file_name(path)))
+}
+
+/// Syncs the directory, so the rename itself survives a power cut. Not possible on all platforms,
+/// so errors are ignored.
+fn sync_directory(path: &Path) {
+    let directory = match path.parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => directory,
+        _ => Path::new("."),
+    };
+
+    if let Ok(directory) = File::open(directory) {
+        directory.sync_all().unwrap_or_default();
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Copies the current file into the backup directory, unless it does not exist or already has
+/// the new content
+fn take_snapshot(path: &Path, new_content: &[u8]) -> std::io::Result<()> {
+    match read(path) {
+        Ok(content) => snapshot(&file_name(path), &content, new_content),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(()),
+        Err(error) => Err(error),
+    }
+}
+
+/// Stores the current content of the file with the name in the backup directory, unless it
+/// already has the new content. Only the configured amount of snapshots is kept per file.
+pub fn snapshot(file: &str, content: &[u8], new_content: &[u8]) -> std::io::Result<()> {
+    let settings = backup_settings();
+
+    if settings.keep == 0 || content.eq(new_content) {
+        return Ok(());
+    }
+
+    let directory = PathBuf::from(&settings.directory);
+    let now = OffsetDateTime::now_utc();
+    let name = format!("{}.{}-{:03}", file, now.format("%Y%m%d-%H%M%S"), now.millisecond());
+
+    create_dir_all(&directory)?;
+    write_atomic(&directory.join(name), content)?;
+
+    for old in list_snapshots()?
+        .into_iter()
+        .filter(|snapshot| snapshot.file.eq(file))
+        .skip(settings.keep)
+    {
+        remove_file(directory.join(old.name)).unwrap_or_default();
+    }
+
+    Ok(())
+}
+
+/// Lists all snapshots, newest first
+pub fn list_snapshots() -> std::io::Result<Vec<Snapshot>> {
+    let entries = match read_dir(&backup_settings().directory) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error),
+    };
+    let mut snapshots = Vec::new();
+
+    for entry in entries {
+        let name = entry?.file_name().to_string_lossy().to_string();
+
+        if let Some((file, created)) = name.rsplit_once('.') {
+            snapshots.push(Snapshot {
+                name: name.clone(),
+                file: String::from(file),
+                created: String::from(created),
+            });
+        }
+    }
+
+    snapshots.sort_by(|left, right| right.created.cmp(&left.created));
+
+    Ok(snapshots)
+}
+
+/// Reads the snapshot with the name. Returns it with its content, which is valid YAML.
+pub fn read_snapshot(name: &str) -> Result<(Snapshot, Vec<u8>), PersistenceError> {
+    let snapshot = list_snapshots()
+        .map_err(PersistenceError::Io)?
+        .into_iter()
+        .find(|snapshot| snapshot.name.eq(name))
+        .ok_or_else(|| PersistenceError::UnknownSnapshot(String::from(name)))?;
+    let content = read(PathBuf::from(&backup_settings().directory).join(&snapshot.name)).map_err(PersistenceError::Io)?;
+
+    serde_yaml::from_slice::<serde_yaml::Value>(&content).map_err(PersistenceError::InvalidSnapshot)?;
+
+    Ok((snapshot, content))
+}
+
+/// Writes the content of a snapshot to the file. The current file is backed up before (also if
+/// it could not be parsed) and is not protected anymore afterwards.
+pub fn restore_file(path: &Path, content: &[u8]) -> Result<(), PersistenceError> {
+    // Broken files are backed up as well, so restoring can be undone
+    take_snapshot(path, content).map_err(PersistenceError::Io)?;
+    write_atomic(path, content).map_err(PersistenceError::Io)?;
+    mark_unparsed(path, false);
+
+    Ok(())
+}
+
+impl Display for PersistenceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PersistenceError::Serialize(error) => write!(f, "Could not serialize: {}", error),
+            PersistenceError::Io(error) => write!(f, "Could not write: {}", error),
+            PersistenceError::Unparsed(path) => write!(
+                f,
+                "{} could not be parsed when it was loaded. Changes are not saved until it is fixed and reloaded (or a backup is restored)",
+                path.display()
+            ),
+            PersistenceError::UnknownSnapshot(name) => write!(f, "There is no backup {}", name),
+            PersistenceError::InvalidSnapshot(error) => write!(f, "The backup is no valid YAML: {}", error),
+        }
+    }
+}
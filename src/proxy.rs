@@ -0,0 +1,274 @@
+//! This module implements the reverse proxy of the proxy server type. Requests which are not
+//! handled by the server itself are forwarded to the configured base url.
+//!
+//! Request and response bodies are streamed, so neither is held in memory. WebSocket connections
+//! are upgraded on both sides and the frames are passed through unchanged.
+
+use std::time::Duration;
+
+use actix_codec::Decoder;
+use actix_http::ws::{self, Codec, Frame};
+use actix_web::body::{Body, SizedStream};
+use actix_web::client::Client;
+use actix_web::dev::BodyEncoding;
+use actix_web::http::header::{ContentEncoding, HeaderMap, HeaderName, HeaderValue, CONNECTION, CONTENT_LENGTH, HOST, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION, UPGRADE};
+use actix_web::http::{Method, StatusCode, Uri};
+use actix_web::web::{Bytes, BytesMut, Data, Payload};
+use actix_web::{Error, HttpRequest, HttpResponse};
+use futures::channel::mpsc::channel;
+use futures::{stream, SinkExt, StreamExt, TryStreamExt};
+
+use crate::services::events::encode_frame;
+
+/// The time the upstream server has to answer, including the upload of the request body
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The largest WebSocket frame which is passed through
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// The frames from the upstream server which wait for a slow client. Reading from the server
+/// pauses when they are full.
+const TUNNEL_BUFFER: usize = 16;
+
+/// Headers which only apply to a single connection (RFC 7230, section 6.1)
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Forwards the request to the server at the base url and streams its response back
+pub async fn proxy(req: HttpRequest, payload: Payload, client: Data<Client>, base_url: &str) -> Result<HttpResponse, Error> {
+    let url = format!("{}{}", base_url.trim_end_matches('/'), req.uri());
+
+    if is_websocket(&req) {
+        return tunnel(req, payload, client, url).await;
+    }
+
+    let mut request = client.request(req.method().clone(), url.as_str()).no_decompress().timeout(TIMEOUT);
+
+    for (name, value) in end_to_end(req.headers()) {
+        if name != HOST && name != CONTENT_LENGTH {
+            request = request.header(name.clone(), value.clone());
+        }
+    }
+
+    for (name, value) in forwarded(&req) {
+        request = request.set_header(name, value);
+    }
+
+    let result = match content_length(req.headers()) {
+        Some(length) => {
+            request
+                .send_body(Body::from_message(SizedStream::new(length, payload.map_err(Error::from))))
+                .await
+        }
+        None if req.headers().contains_key("transfer-encoding") => request.send_stream(payload).await,
+        None => request.send().await,
+    };
+
+    let response = match result {
+        Ok(response) => response,
+        Err(error) => {
+            eprintln!("[ERROR] [Proxy]: Could not forward the request to {}", url);
+            eprintln!("{}", error);
+
+            return Ok(HttpResponse::BadGateway().finish());
+        }
+    };
+
+    let mut client_response = HttpResponse::build(response.status());
+
+    // The body is passed through as it is, so the server must not encode it again
+    client_response.encoding(ContentEncoding::Identity);
+
+    for (name, value) in end_to_end(response.headers()) {
+        if name != CONTENT_LENGTH {
+            client_response.header(name.clone(), value.clone());
+        }
+    }
+
+    let length = content_length(response.headers());
+
+    if req.method() == Method::HEAD {
+        return Ok(match length {
+            Some(length) => client_response.body(Body::from_message(SizedStream::new(length, stream::empty()))),
+            None => client_response.finish(),
+        });
+    }
+
+    Ok(match (response.status(), length) {
+        (StatusCode::NO_CONTENT, _) | (StatusCode::NOT_MODIFIED, _) => client_response.finish(),
+        (_, Some(length)) => client_response.body(Body::from_message(SizedStream::new(length, response.map_err(Error::from)))),
+        (_, None) => client_response.streaming(response),
+    })
+}
+
+/// Upgrades the connection to the client and to the server and passes the frames between them
+async fn tunnel(req: HttpRequest, mut payload: Payload, client: Data<Client>, url: String) -> Result<HttpResponse, Error> {
+    let mut response = ws::handshake(req.head())?;
+    let mut request = client.ws(url.as_str()).max_frame_size(MAX_FRAME_SIZE);
+
+    // The key, version and extensions are negotiated by the client of the proxy itself
+    for (name, value) in end_to_end(req.headers()) {
+        if ![HOST, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_ACCEPT].contains(name) {
+            request = request.header(name.clone(), value.clone());
+        }
+    }
+
+    for (name, value) in forwarded(&req) {
+        request = request.set_header(name, value);
+    }
+
+    // Without it, only the host name (without the port) would be sent
+    if let Some(authority) = url.parse::<Uri>().ok().and_then(|uri| uri.authority().cloned()) {
+        request = request.set_header(HOST, authority.as_str());
+    }
+
+    let (upstream_response, framed) = match request.connect().await {
+        Ok(connection) => connection,
+        Err(error) => {
+            eprintln!("[ERROR] [Proxy]: Could not open the WebSocket connection to {}", url);
+            eprintln!("{}", error);
+
+            return Ok(HttpResponse::BadGateway().finish());
+        }
+    };
+
+    if let Some(protocol) = upstream_response.headers().get(SEC_WEBSOCKET_PROTOCOL) {
+        response.header(SEC_WEBSOCKET_PROTOCOL, protocol.clone());
+    }
+
+    let (mut upstream_sink, mut upstream_stream) = framed.split();
+    let (mut sender, receiver) = channel::<Bytes>(TUNNEL_BUFFER);
+
+    actix_web::rt::spawn(async move {
+        let mut codec = Codec::new().max_size(MAX_FRAME_SIZE);
+        let mut buffer = BytesMut::new();
+
+        'connection: while let Some(Ok(chunk)) = payload.next().await {
+            buffer.extend_from_slice(&chunk);
+
+            loop {
+                let message = match codec.decode(&mut buffer) {
+                    Ok(Some(frame)) => match message(frame) {
+                        Some(message) => message,
+                        None => break 'connection,
+                    },
+                    Ok(None) => break,
+                    Err(error) => {
+                        eprintln!("[WARN] [Proxy]: Invalid WebSocket frame from the client {:?}", error);
+
+                        break 'connection;
+                    }
+                };
+
+                if upstream_sink.send(message).await.is_err() {
+                    break 'connection;
+                }
+            }
+        }
+
+        upstream_sink.close().await.unwrap_or_default();
+    });
+
+    actix_web::rt::spawn(async move {
+        while let Some(result) = upstream_stream.next().await {
+            let message = match result {
+                Ok(frame) => match message(frame) {
+                    Some(message) => message,
+                    None => break,
+                },
+                Err(error) => {
+                    eprintln!("[WARN] [Proxy]: Invalid WebSocket frame from the server {:?}", error);
+
+                    break;
+                }
+            };
+
+            if sender.send(encode_frame(message)).await.is_err() {
+                break;
+            }
+        }
+
+        sender.close_channel();
+    });
+
+    Ok(response.streaming(receiver.map(Ok::<Bytes, Error>)))
+}
+
+/// Converts a received frame into the message to send it on. Text which is not valid UTF-8 ends
+/// the connection.
+fn message(frame: Frame) -> Option<ws::Message> {
+    Some(match frame {
+        Frame::Text(data) => match String::from_utf8(data.to_vec()) {
+            Ok(text) => ws::Message::Text(text),
+            Err(_) => {
+                eprintln!("[WARN] [Proxy]: WebSocket text frame is not valid UTF-8");
+
+                return None;
+            }
+        },
+        Frame::Binary(data) => ws::Message::Binary(data),
+        Frame::Continuation(item) => ws::Message::Continuation(item),
+        Frame::Ping(data) => ws::Message::Ping(data),
+        Frame::Pong(data) => ws::Message::Pong(data),
+        Frame::Close(reason) => ws::Message::Close(reason),
+    })
+}
+
+fn is_websocket(req: &HttpRequest) -> bool {
+    req.method() == Method::GET
+        && req
+            .headers()
+            .get(UPGRADE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+}
+
+/// The headers without the hop-by-hop headers and the headers listed in `Connection`
+fn end_to_end(headers: &HeaderMap) -> impl Iterator<Item = (&HeaderName, &HeaderValue)> {
+    let listed: Vec<String> = headers
+        .get_all(CONNECTION)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect();
+
+    headers
+        .iter()
+        .filter(move |(name, _)| !HOP_BY_HOP.contains(&name.as_str()) && !listed.iter().any(|listed| listed == name.as_str()))
+}
+
+/// The `X-Forwarded-*` headers describing the request of the client. Values from proxies in front
+/// of this server are kept.
+fn forwarded(req: &HttpRequest) -> Vec<(&'static str, String)> {
+    let info = req.connection_info();
+    let mut headers = vec![
+        ("x-forwarded-proto", String::from(info.scheme())),
+        ("x-forwarded-host", String::from(info.host())),
+    ];
+
+    if let Some(peer) = req.peer_addr() {
+        let forwarded_for = match req.headers().get("x-forwarded-for").and_then(|value| value.to_str().ok()) {
+            Some(previous) => format!("{}, {}", previous, peer.ip()),
+            None => peer.ip().to_string(),
+        };
+
+        headers.push(("x-forwarded-for", forwarded_for));
+    }
+
+    headers
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
@@ -0,0 +1,99 @@
+//! This module implements the backup service, which lists and restores the snapshots of the data
+//! files (see [crate::persistence]).
+//!
+//! Snapshots of the collections (dashboards, groups, ...) are restored through the storage, so
+//! they end up in the database if it is used. Other files are written directly. Dashboards are
+//! only restored if all of their groups exist.
+
+use actix::{Actor, Addr, Context, Handler, Message, MessageResult};
+
+use crate::error::ServiceError;
+use crate::paths::file_path;
+use crate::persistence::{list_snapshots, read_snapshot, restore_file, PersistenceError, Snapshot};
+use crate::services::{BackupMessage, BackupService, DashboardMessage, DashboardService, GroupMessage, GroupService, RuleMessage, RuleService, ShortcutsMessage, ShortcutsService, UserMessage, UserService, WebSettingsCompiledMessage, WebSettingsMessage, WebSettingsService};
+use crate::storage::{SharedStorage, COLLECTIONS, DASHBOARDS};
+
+impl BackupService {
+    pub fn new(
+        storage: SharedStorage,
+        web_settings: Addr<WebSettingsService>,
+        dashboard: Addr<DashboardService>,
+        group: Addr<GroupService>,
+        shortcuts: Addr<ShortcutsService>,
+        rules: Addr<RuleService>,
+        users: Addr<UserService>,
+    ) -> Self {
+        Self {
+            storage,
+            web_settings,
+            dashboard,
+            group,
+            shortcuts,
+            rules,
+            users,
+        }
+    }
+
+    /// Writes the snapshot to the storage of its collection or to its file
+    fn restore(&self, name: &str) -> Result<Snapshot, ServiceError> {
+        let (snapshot, content) = read_snapshot(name)?;
+
+        if snapshot.file.eq(&DASHBOARDS.file_name()) {
+            let dashboards = serde_yaml::from_slice(&content).map_err(PersistenceError::InvalidSnapshot)?;
+
+            futures::executor::block_on(self.dashboard.send(DashboardMessage::Check(dashboards)))??;
+        }
+
+        match COLLECTIONS.iter().find(|collection| collection.file_name().eq(&snapshot.file)) {
+            Some(collection) => self.storage.restore(collection, &content)?,
+            None => restore_file(&file_path(&snapshot.file), &content)?,
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Reloads the service using the restored file
+    fn reload(&self, file: &str) {
+        match file {
+            "dashboard.yaml" => self.dashboard.do_send(DashboardMessage::Reload),
+            "group.yaml" => self.group.do_send(GroupMessage::Reload),
+            "shortcuts.yaml" => self.shortcuts.do_send(ShortcutsMessage::Reload),
+            "rules.yaml" => self.rules.do_send(RuleMessage::Reload),
+            "users.yaml" => self.users.do_send(UserMessage::Reload),
+            "web_settings.yaml" => {
+                self.web_settings.do_send(WebSettingsMessage::Reload);
+                self.web_settings.do_send(WebSettingsCompiledMessage::Reload);
+            }
+            _ => println!("[Backup]: Restored {}. Restart the server to apply it", file),
+        }
+    }
+}
+
+impl Actor for BackupService {
+    type Context = Context<Self>;
+}
+
+impl Handler<BackupMessage> for BackupService {
+    type Result = MessageResult<BackupMessage>;
+
+    fn handle(&mut self, msg: BackupMessage, _: &mut Self::Context) -> Self::Result {
+        match msg {
+            BackupMessage::List => MessageResult(list_snapshots().map_err(|error| PersistenceError::Io(error).into())),
+            BackupMessage::Restore(name) => {
+                let snapshot = match self.restore(&name) {
+                    Ok(snapshot) => snapshot,
+                    Err(error) => return MessageResult(Err(error)),
+                };
+
+                println!("[Backup]: Restored {} from {}", snapshot.file, snapshot.created);
+                self.reload(&snapshot.file);
+
+                MessageResult(Ok(vec![snapshot]))
+            }
+        }
+    }
+}
+
+impl Message for BackupMessage {
+    type Result = Result<Vec<Snapshot>, ServiceError>;
+}
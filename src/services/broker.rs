@@ -0,0 +1,61 @@
+//! This module implements the service running the embedded MQTT broker.
+
+use actix::{Actor, Context};
+
+use crate::mqtt::broker::Broker;
+use crate::services::BrokerService;
+use crate::settings::BrokerSettings;
+
+impl BrokerService {
+    pub fn new(settings: BrokerSettings) -> Self {
+        Self {
+            settings,
+            broker: None,
+            on_stop: Vec::new(),
+        }
+    }
+}
+
+impl Actor for BrokerService {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _: &mut Self::Context) {
+        if !self.settings.enabled {
+            return;
+        }
+
+        let broker = Broker::new(self.settings.clone());
+
+        if let Some(bind) = &self.settings.tcp_bind {
+            match broker.listen_tcp(bind) {
+                Ok(stop) => self.on_stop.push(stop),
+                Err(error) => {
+                    eprintln!("[ERROR] [Broker]: Could not listen on {}", bind);
+                    eprintln!("{}", error);
+                }
+            }
+        }
+
+        if let Some(bind) = &self.settings.websocket_bind {
+            match broker.listen_websocket(bind) {
+                Ok(stop) => self.on_stop.push(stop),
+                Err(error) => {
+                    eprintln!("[ERROR] [Broker]: Could not listen on {}", bind);
+                    eprintln!("{}", error);
+                }
+            }
+        }
+
+        self.broker = Some(broker);
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        for stop in &self.on_stop {
+            stop();
+        }
+
+        if let Some(broker) = &self.broker {
+            broker.shutdown();
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! Rules and shortcuts can trigger each other (rule A publishes what shortcut B listens to, which
+//! publishes what rule A listens to). To stop them when they trigger each other in a circle, the
+//! messages they publish are remembered with the number of rules and shortcuts which led to them.
+//! Messages at the end of a chain of [MAX_CHAIN] are not passed on.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::services::{MqttIncoming, MqttPublish};
+
+/// The most rules and shortcuts which can trigger each other in a row
+pub const MAX_CHAIN: usize = 8;
+
+/// How long a published message is remembered, waiting for the broker to send it back
+const PUBLISHED_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The messages recently published by rules and shortcuts, shared by both services
+#[derive(Clone, Default)]
+pub struct Chains(Arc<Mutex<VecDeque<Published>>>);
+
+/// A message published by a rule or shortcut
+struct Published {
+    topic: String,
+    payload: String,
+
+    /// The number of rules and shortcuts which led to the message
+    length: usize,
+
+    time: Instant,
+
+    /// The services which received the message back from the broker
+    received_by: Vec<&'static str>,
+}
+
+impl Chains {
+    /// The number of rules and shortcuts which led to the incoming message. 0 if it was not
+    /// published by one of them. Every service receives a published message once, so it is only
+    /// counted once for each of them.
+    pub fn length(&self, service: &'static str, msg: &MqttIncoming) -> usize {
+        let mut published = self.0.lock().unwrap();
+        let now = Instant::now();
+
+        while published
+            .front()
+            .is_some_and(|message| now.duration_since(message.time) > PUBLISHED_TIMEOUT)
+        {
+            published.pop_front();
+        }
+
+        let message = published.iter_mut().find(|message| {
+            message.topic.eq(&msg.topic) && message.payload.eq(&msg.payload) && !message.received_by.contains(&service)
+        });
+
+        match message {
+            Some(message) => {
+                message.received_by.push(service);
+
+                message.length
+            }
+            None => 0,
+        }
+    }
+
+    /// Remembers the message, which was published at the end of a chain of the given length
+    pub fn published(&self, publish: &MqttPublish, length: usize) {
+        self.0.lock().unwrap().push_back(Published {
+            topic: publish.topic.clone(),
+            payload: publish.payload.clone(),
+            length,
+            time: Instant::now(),
+            received_by: Vec::new(),
+        });
+    }
+}
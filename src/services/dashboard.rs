@@ -1,62 +1,22 @@
-use std::env::current_dir;
-use std::fs::OpenOptions;
-use std::io::Write;
+use actix::{Actor, Addr, Context, Handler, Message, MessageResult};
+use serde_json::json;
 
-use actix::{Actor, Context, Handler, Message, MessageResult};
-
-use crate::services::{DashboardData, DashboardMessage, DashboardService, DataReadWrite, IndexOf};
+use crate::error::ServiceError;
+use crate::services::history::record;
+use crate::services::revision::next_revision;
+use crate::services::validation::{check_name, Validate};
+use crate::services::{ChangeAction, ChangeEvent, ChangeKind, DashboardData, DashboardMessage, DashboardService, DataReadWrite, Edit, EventMessage, EventService, HistoryService, IndexOf};
+use crate::storage::{delete_entry, load_list, put_entry, SharedStorage, Storage, DASHBOARDS};
 
 impl DataReadWrite for Vec<DashboardData> {
-    fn load() -> Self {
-        let dashboards_path = current_dir().unwrap().join("dashboard.yaml");
-        let file = OpenOptions::new().read(true).open(dashboards_path);
-
-        match file {
-            Ok(file) => match serde_yaml::from_reader(file) {
-                Ok(dashboards) => dashboards,
-                Err(error) => {
-                    eprintln!("[ERROR] [Dashboard]: Could not parse file");
-                    eprintln!("{}", error);
-
-                    Vec::new()
-                }
-            },
-            Err(error) => {
-                eprintln!("[WARN] [Dashboard]: Could not open file");
-                eprintln!("{}", error);
+    fn load(storage: &dyn Storage) -> Self {
+        let mut dashboards: Self = load_list(storage, &DASHBOARDS);
 
-                Vec::new()
-            }
+        for dashboard in &mut dashboards {
+            dashboard.revision = next_revision();
         }
-    }
-
-    fn save(&self) {
-        let dashboards_path = current_dir().unwrap().join("dashboard.yaml");
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(dashboards_path);
 
-        match file {
-            Ok(mut file) => {
-                if let Err(error) = serde_yaml::to_writer(&file, &self) {
-                    eprintln!("[ERROR] [Dashboard]: Could not write dashboards");
-                    eprintln!("{}", error);
-
-                    return;
-                }
-
-                if let Err(error) = file.flush() {
-                    eprintln!("[WARN] [Dashboard]: Could not fully write dashboards");
-                    eprintln!("{}", error);
-                }
-            }
-            Err(error) => {
-                eprintln!("[ERROR] [Dashboard]: Could not open/create file");
-                eprintln!("{}", error);
-            }
-        }
+        dashboards
     }
 
     fn single(&self, which: String) -> Self {
@@ -83,11 +43,149 @@ impl IndexOf<usize, String> for Vec<DashboardData> {
 }
 
 impl DashboardService {
-    pub fn new() -> Self {
+    pub fn new(storage: SharedStorage, events: Addr<EventService>, history: Addr<HistoryService>) -> Self {
         Self {
-            dashboards: Default::default()
+            dashboards: Default::default(),
+            groups: Vec::new(),
+            storage,
+            events,
+            history,
+        }
+    }
+
+    /// Handles the message. Changes are recorded in the history with the user.
+    fn apply(&mut self, msg: DashboardMessage, user: Option<String>) -> Result<Vec<DashboardData>, ServiceError> {
+        match msg {
+            DashboardMessage::List => Ok(self.dashboards.clone()),
+            DashboardMessage::Reload => {
+                self.dashboards = Vec::<DashboardData>::load(&*self.storage);
+                self.events.do_send(EventMessage::Publish(ChangeEvent::reload(ChangeKind::Dashboard)));
+
+                Ok(self.dashboards.clone())
+            }
+            DashboardMessage::Get(name) => {
+                let dashboard = self.dashboards.single(name.clone());
+
+                if dashboard.is_empty() {
+                    return Err(ServiceError::not_found("dashboard", name));
+                }
+
+                Ok(dashboard)
+            }
+            DashboardMessage::Set(name, data) => self.set(name, data, user),
+            DashboardMessage::Patch(name, change) => {
+                let data = match self.dashboards.index_of(name.clone()) {
+                    Some(index) => change.apply(&self.dashboards[index])?,
+                    None => return Err(ServiceError::not_found("dashboard", &name)),
+                };
+
+                self.set(name, data, user)
+            }
+            DashboardMessage::Delete(name) => {
+                let index = match self.dashboards.index_of(name.clone()) {
+                    Some(index) => index,
+                    None => return Err(ServiceError::not_found("dashboard", name)),
+                };
+
+                delete_entry(&*self.storage, &DASHBOARDS, &name)?;
+
+                let dashboard = self.dashboards.remove(index);
+
+                record(&self.history, ChangeKind::Dashboard, &name, user, Some(&dashboard), None);
+                self.events.do_send(EventMessage::Publish(
+                    ChangeEvent::new(ChangeKind::Dashboard, ChangeAction::Delete, name).visible_to(&dashboard.visible_to),
+                ));
+
+                Ok(self.dashboards.clone())
+            }
+            DashboardMessage::Check(dashboards) => {
+                for dashboard in &dashboards {
+                    self.check_groups(&dashboard.name, dashboard)?;
+                }
+
+                Ok(dashboards)
+            }
         }
     }
+
+    /// Validates and stores the dashboard. A different name in the dashboard renames it.
+    fn set(&mut self, name: String, mut data: DashboardData, user: Option<String>) -> Result<Vec<DashboardData>, ServiceError> {
+        data.validate(&name, self.dashboards.index_of(name.clone()).map(|index| &self.dashboards[index]))?;
+        check_name("dashboard", &name, &data.name, |name| self.dashboards.index_of(String::from(name)).is_some())?;
+        self.check_groups(&name, &data)?;
+        put_entry(&*self.storage, &DASHBOARDS, &name, &data)?;
+        data.revision = next_revision();
+
+        self.events.do_send(EventMessage::Publish(
+            ChangeEvent::new(ChangeKind::Dashboard, ChangeAction::Set, &name).visible_to(&data.visible_to),
+        ));
+
+        let before = match self.dashboards.index_of(name.clone()) {
+            Some(index) => Some(std::mem::replace(&mut self.dashboards[index], data.clone())),
+            None => {
+                self.dashboards.push(data.clone());
+
+                None
+            }
+        };
+
+        record(&self.history, ChangeKind::Dashboard, &name, user, before.as_ref(), Some(&data));
+
+        Ok(self.dashboards.clone())
+    }
+}
+
+impl DashboardService {
+    /// Dashboards can only contain existing groups
+    fn check_groups(&self, name: &str, dashboard: &DashboardData) -> Result<(), ServiceError> {
+        let missing: Vec<&String> = dashboard.groups.iter().filter(|group| !self.groups.contains(group)).collect();
+
+        if !missing.is_empty() {
+            let names: Vec<&str> = missing.iter().map(|group| group.as_str()).collect();
+
+            return Err(ServiceError::Invalid {
+                message: format!("The dashboard {} contains unknown groups: {}", name, names.join(", ")),
+                details: json!({ "errors": [{ "field": "groups", "message": "The groups do not exist." }], "missing": missing }),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl DashboardMessage {
+    /// The dashboard the message is about
+    fn target(&self) -> Option<&str> {
+        match self {
+            DashboardMessage::List | DashboardMessage::Reload | DashboardMessage::Check(_) => None,
+            DashboardMessage::Get(name)
+            | DashboardMessage::Set(name, _)
+            | DashboardMessage::Patch(name, _)
+            | DashboardMessage::Delete(name) => Some(name),
+        }
+    }
+}
+
+impl DashboardData {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn visible_to(&self) -> &[String] {
+        &self.visible_to
+    }
+
+    pub fn groups(&self) -> &[String] {
+        &self.groups
+    }
+
+    pub fn has_group(&self, group: &str) -> bool {
+        self.groups.iter().any(|name| name.eq(group))
+    }
 }
 
 impl Actor for DashboardService {
@@ -96,13 +194,7 @@ impl Actor for DashboardService {
     fn started(&mut self, _: &mut Self::Context) {
         println!("Started dashboard");
 
-        self.dashboards = Vec::<DashboardData>::load();
-    }
-
-    fn stopped(&mut self, _: &mut Self::Context) {
-        println!("Stopped Dashboard");
-
-        self.dashboards.save();
+        self.dashboards = Vec::<DashboardData>::load(&*self.storage);
     }
 }
 
@@ -110,40 +202,31 @@ impl Handler<DashboardMessage> for DashboardService {
     type Result = MessageResult<DashboardMessage>;
 
     fn handle(&mut self, msg: DashboardMessage, _: &mut Self::Context) -> Self::Result {
-        match msg {
-            DashboardMessage::List => MessageResult(self.dashboards.clone()),
-            DashboardMessage::Reload => {
-                self.dashboards = Vec::<DashboardData>::load();
-
-                MessageResult(self.dashboards.clone())
-            }
-            DashboardMessage::Get(name) => MessageResult(self.dashboards.single(name)),
-            DashboardMessage::Set(name, data) => {
-                if let Some(index) = self.dashboards.index_of(name) {
-                    self.dashboards[index] = data.clone();
-                    self.dashboards.save();
+        MessageResult(self.apply(msg, None))
+    }
+}
 
-                    return MessageResult(self.dashboards.clone());
-                }
+impl Handler<Edit<DashboardMessage>> for DashboardService {
+    type Result = MessageResult<Edit<DashboardMessage>>;
 
-                self.dashboards.push(data.clone());
-                self.dashboards.save();
+    fn handle(&mut self, msg: Edit<DashboardMessage>, _: &mut Self::Context) -> Self::Result {
+        let Edit { user, precondition, message } = msg;
 
-                MessageResult(self.dashboards.clone())
-            }
-            DashboardMessage::Delete(name) => {
-                if let Some(index) = self.dashboards.index_of(name) {
-                    self.dashboards.remove(index);
-                }
+        if let Some(name) = message.target() {
+            let revision = self
+                .dashboards
+                .index_of(String::from(name))
+                .map(|index| self.dashboards[index].revision);
 
-                self.dashboards.save();
-
-                MessageResult(self.dashboards.clone())
+            if !precondition.matches(revision) {
+                return MessageResult(Err(ServiceError::PreconditionFailed { kind: "dashboard", name: String::from(name) }));
             }
         }
+
+        MessageResult(self.apply(message, user))
     }
 }
 
 impl Message for DashboardMessage {
-    type Result = Vec<DashboardData>;
+    type Result = Result<Vec<DashboardData>, ServiceError>;
 }
@@ -0,0 +1,87 @@
+//! This module implements the event service, which notifies the WebSocket clients about changes.
+
+use actix::{Actor, Context, Handler, Message};
+use actix_codec::Encoder;
+use actix_http::ws::{Codec, Message as WsMessage};
+use actix_web::web::{Bytes, BytesMut};
+
+use crate::services::{ChangeAction, ChangeEvent, ChangeKind, EventMessage, EventService};
+
+impl EventService {
+    pub fn new() -> Self {
+        Self { subscribers: Vec::new() }
+    }
+}
+
+impl Actor for EventService {
+    type Context = Context<Self>;
+}
+
+impl Handler<EventMessage> for EventService {
+    type Result = ();
+
+    fn handle(&mut self, msg: EventMessage, _: &mut Self::Context) -> Self::Result {
+        match msg {
+            EventMessage::Subscribe(user, sender) => self.subscribers.push((user, sender)),
+            EventMessage::Publish(event) => {
+                let frame = match serde_json::to_string(&event) {
+                    Ok(json) => encode_frame(WsMessage::Text(json)),
+                    Err(error) => {
+                        eprintln!("[ERROR] [Events]: Could not serialize event");
+                        eprintln!("{}", error);
+
+                        return;
+                    }
+                };
+
+                // Closed connections are removed as soon as sending to them fails
+                self.subscribers.retain(|(user, sender)| {
+                    !user.can_see(&event.visible_to) || sender.unbounded_send(frame.clone()).is_ok()
+                });
+            }
+        }
+    }
+}
+
+impl Message for EventMessage {
+    type Result = ();
+}
+
+impl ChangeEvent {
+    pub fn new(kind: ChangeKind, action: ChangeAction, name: impl ToString) -> Self {
+        Self {
+            kind,
+            action,
+            name: Some(name.to_string()),
+            visible_to: Vec::new(),
+        }
+    }
+
+    /// All entries of the kind were reloaded
+    pub fn reload(kind: ChangeKind) -> Self {
+        Self {
+            kind,
+            action: ChangeAction::Reload,
+            name: None,
+            visible_to: Vec::new(),
+        }
+    }
+
+    pub fn visible_to(mut self, visible_to: &[String]) -> Self {
+        self.visible_to = visible_to.to_vec();
+
+        self
+    }
+}
+
+/// Encodes the message as (unmasked) server frame
+pub fn encode_frame(message: WsMessage) -> Bytes {
+    let mut buffer = BytesMut::new();
+
+    if let Err(error) = Codec::new().encode(message, &mut buffer) {
+        eprintln!("[ERROR] [Events]: Could not encode frame");
+        eprintln!("{}", error);
+    }
+
+    buffer.freeze()
+}
@@ -1,85 +1,108 @@
-use std::env::current_dir;
-use std::fs::OpenOptions;
-use std::io::Write;
-
 use actix::{Actor, Context, Handler, Message, MessageResult, Addr};
 
-use crate::services::{DashboardService, DataReadWrite, GroupData, GroupMessage, GroupService, IndexOf};
-use crate::services::group::group_dashboard_messages::AnyDashboardUsesGroup;
+use crate::error::{Reference, ServiceError};
+use crate::services::history::record;
+use crate::services::revision::next_revision;
+use crate::services::validation::{check_name, Validate};
+use crate::services::{ChangeAction, ChangeEvent, ChangeKind, DashboardMessage, DashboardService, DataReadWrite, Edit, EventMessage, EventService, GroupData, GroupItemData, GroupMessage, GroupService, GroupUsage, HistoryService, IndexOf};
+use crate::services::group::group_dashboard_messages::{DashboardsUsingGroup, KnownGroups, ReplaceGroup};
+use crate::storage::{delete_entry, load_list, put_entry, SharedStorage, Storage, DASHBOARDS, GROUPS};
 
 mod group_dashboard_messages {
-    pub struct AnyDashboardUsesGroup(pub String);
-}
+    /// Gets the names of all dashboards containing the group
+    pub struct DashboardsUsingGroup(pub String);
+
+    /// Renames the group in all dashboards containing it. Without a new name the group is removed
+    /// from them. The changes are recorded for the user.
+    pub struct ReplaceGroup(pub String, pub Option<String>, pub Option<String>);
 
-impl Message for AnyDashboardUsesGroup {
-    type Result = bool;
+    /// The names of all groups, sent whenever they change. Dashboards can only contain these.
+    pub struct KnownGroups(pub Vec<String>);
 }
 
-impl Handler<AnyDashboardUsesGroup> for DashboardService {
-    type Result = MessageResult<AnyDashboardUsesGroup>;
+impl Message for DashboardsUsingGroup {
+    type Result = Vec<String>;
+}
 
-    fn handle(&mut self, msg: AnyDashboardUsesGroup, _: &mut Self::Context) -> Self::Result {
-        for dashboard in &self.dashboards {
-            for group in &dashboard.groups {
-                if group.eq(msg.0.as_str()) { return MessageResult(true); }
-            }
-        }
+impl Message for ReplaceGroup {
+    type Result = Result<(), ServiceError>;
+}
 
-        MessageResult(false)
-    }
+impl Message for KnownGroups {
+    type Result = ();
 }
 
-impl DataReadWrite for Vec<GroupData> {
-    fn load() -> Self {
-        let groups_path = current_dir().unwrap().join("group.yaml");
-        let file = OpenOptions::new().read(true).open(groups_path);
-
-        match file {
-            Ok(file) => match serde_yaml::from_reader(file) {
-                Ok(groups) => groups,
-                Err(error) => {
-                    eprintln!("[ERROR] [Group]: Could not parse file");
-                    eprintln!("{}", error);
-
-                    Vec::new()
-                }
-            },
-            Err(error) => {
-                eprintln!("[WARN] [Group]: Could not open file");
-                eprintln!("{}", error);
+impl Handler<KnownGroups> for DashboardService {
+    type Result = ();
 
-                Vec::new()
-            }
-        }
+    fn handle(&mut self, msg: KnownGroups, _: &mut Self::Context) -> Self::Result {
+        self.groups = msg.0;
     }
+}
 
-    fn save(&self) {
-        let groups_path = current_dir().unwrap().join("group.yaml");
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(groups_path);
+impl Handler<DashboardsUsingGroup> for DashboardService {
+    type Result = MessageResult<DashboardsUsingGroup>;
+
+    fn handle(&mut self, msg: DashboardsUsingGroup, _: &mut Self::Context) -> Self::Result {
+        MessageResult(
+            self.dashboards
+                .iter()
+                .filter(|dashboard| dashboard.has_group(&msg.0))
+                .map(|dashboard| dashboard.name.clone())
+                .collect(),
+        )
+    }
+}
 
-        match file {
-            Ok(mut file) => {
-                if let Err(error) = serde_yaml::to_writer(&file, &self) {
-                    eprintln!("[ERROR] [Group]: Could not write groups");
-                    eprintln!("{}", error);
+impl Handler<ReplaceGroup> for DashboardService {
+    type Result = MessageResult<ReplaceGroup>;
 
-                    return;
-                }
+    fn handle(&mut self, msg: ReplaceGroup, _: &mut Self::Context) -> Self::Result {
+        let ReplaceGroup(group, replacement, user) = msg;
 
-                if let Err(error) = file.flush() {
-                    eprintln!("[WARN] [Group]: Could not fully write groups");
-                    eprintln!("{}", error);
-                }
+        for index in 0..self.dashboards.len() {
+            if !self.dashboards[index].has_group(&group) {
+                continue;
             }
-            Err(error) => {
-                eprintln!("[ERROR] [Group]: Could not open/create file");
-                eprintln!("{}", error);
+
+            let mut dashboard = self.dashboards[index].clone();
+
+            dashboard.groups = dashboard
+                .groups
+                .iter()
+                .filter_map(|name| match (name.eq(&group), &replacement) {
+                    (true, Some(replacement)) if dashboard.has_group(replacement) => None,
+                    (true, replacement) => replacement.clone(),
+                    (false, _) => Some(name.clone()),
+                })
+                .collect();
+
+            if let Err(error) = put_entry(&*self.storage, &DASHBOARDS, &dashboard.name, &dashboard) {
+                return MessageResult(Err(error.into()));
             }
+
+            dashboard.revision = next_revision();
+
+            self.events.do_send(EventMessage::Publish(
+                ChangeEvent::new(ChangeKind::Dashboard, ChangeAction::Set, &dashboard.name).visible_to(&dashboard.visible_to),
+            ));
+            record(&self.history, ChangeKind::Dashboard, &dashboard.name, user.clone(), Some(&self.dashboards[index]), Some(&dashboard));
+            self.dashboards[index] = dashboard;
         }
+
+        MessageResult(Ok(()))
+    }
+}
+
+impl DataReadWrite for Vec<GroupData> {
+    fn load(storage: &dyn Storage) -> Self {
+        let mut groups: Self = load_list(storage, &GROUPS);
+
+        for group in &mut groups {
+            group.revision = next_revision();
+        }
+
+        groups
     }
 
     fn single(&self, which: String) -> Self {
@@ -106,81 +129,367 @@ impl IndexOf<usize, String> for Vec<GroupData> {
 }
 
 impl GroupService {
-    pub fn new(dashboard: Addr<DashboardService>) -> Self {
+    pub fn new(
+        storage: SharedStorage,
+        dashboard: Addr<DashboardService>,
+        events: Addr<EventService>,
+        history: Addr<HistoryService>,
+    ) -> Self {
         Self {
-            groups: Vec::<GroupData>::load(),
-            dashboard
+            groups: Vec::<GroupData>::load(&*storage),
+            storage,
+            dashboard,
+            events,
+            history,
         }
     }
 }
 
-impl Actor for GroupService {
-    type Context = Context<Self>;
+impl GroupData {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 
-    fn stopped(&mut self, _: &mut Self::Context) {
-        self.groups.save();
+    pub fn item(&self, name: &str) -> Option<&GroupItemData> {
+        self.items.iter().find(|item| item.name.eq(name))
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.revision
     }
 }
 
-impl Handler<GroupMessage> for GroupService {
-    type Result = MessageResult<GroupMessage>;
+impl GroupMessage {
+    /// The group the message is about
+    fn target(&self) -> Option<&str> {
+        match self {
+            GroupMessage::List | GroupMessage::Reload => None,
+            GroupMessage::Get(name)
+            | GroupMessage::Set(name, _)
+            | GroupMessage::Patch(name, _)
+            | GroupMessage::SetItem(name, _, _)
+            | GroupMessage::DeleteItem(name, _)
+            | GroupMessage::MoveItem(name, _, _)
+            | GroupMessage::Delete(name)
+            | GroupMessage::ForceDelete(name) => Some(name),
+        }
+    }
+}
 
-    fn handle(&mut self, msg: GroupMessage, _: &mut Self::Context) -> Self::Result {
+impl GroupService {
+    /// The dashboards containing the group
+    fn used_by(&self, name: &str) -> Result<Vec<Reference>, ServiceError> {
+        let dashboards = futures::executor::block_on(self.dashboard.send(DashboardsUsingGroup(String::from(name))))?;
+
+        Ok(dashboards
+            .into_iter()
+            .map(|dashboard| Reference { kind: "dashboard", name: dashboard })
+            .collect())
+    }
+
+    /// Changes the group in all dashboards containing it (see [ReplaceGroup])
+    fn replace_in_dashboards(&self, name: &str, replacement: Option<&str>, user: Option<String>) -> Result<(), ServiceError> {
+        futures::executor::block_on(
+            self.dashboard
+                .send(ReplaceGroup(String::from(name), replacement.map(String::from), user)),
+        )?
+    }
+
+    /// Undoes the renaming of the group in the dashboards, after the group could not be renamed
+    fn rename_back_in_dashboards(&self, name: &str, new_name: &str, user: Option<String>) {
+        if let Err(error) = self.replace_in_dashboards(new_name, Some(name), user) {
+            eprintln!("[ERROR] [Group]: Could not rename the group {} back to {} in the dashboards", new_name, name);
+            eprintln!("{}", error);
+        }
+    }
+
+    /// Tells the [DashboardService] which groups exist (see [KnownGroups])
+    fn publish_names(&self) {
+        self.dashboard.do_send(KnownGroups(self.groups.iter().map(|group| group.name.clone()).collect()));
+    }
+
+    /// Reports dashboards containing groups which do not exist
+    fn check_references(&self) {
+        let dashboards = match futures::executor::block_on(self.dashboard.send(DashboardMessage::List)) {
+            Ok(Ok(dashboards)) => dashboards,
+            _ => return eprintln!("[ERROR] [Group]: Could not get the dashboards to check the groups"),
+        };
+
+        for dashboard in dashboards {
+            for group in dashboard.groups() {
+                if self.groups.index_of(group.clone()).is_none() {
+                    eprintln!("[WARN] [Group]: The dashboard {} contains the unknown group {}", dashboard.name, group);
+                }
+            }
+        }
+    }
+
+    /// The stored group
+    fn current(&self, name: &str) -> Result<GroupData, ServiceError> {
+        match self.groups.index_of(String::from(name)) {
+            Some(index) => Ok(self.groups[index].clone()),
+            None => Err(ServiceError::not_found("group", name)),
+        }
+    }
+
+    /// Validates and stores the group. A different name in the group renames it.
+    fn set(&mut self, name: String, mut group: GroupData, user: Option<String>) -> Result<Vec<GroupData>, ServiceError> {
+        group.validate(&name, self.groups.index_of(name.clone()).map(|index| &self.groups[index]))?;
+        check_name("group", &name, &group.name, |name| self.groups.index_of(String::from(name)).is_some())?;
+
+        let renamed = name.ne(&group.name);
+
+        // The dashboards keep pointing at the group after it was renamed. They are changed first,
+        // so the group keeps its old name if they can not be changed.
+        if renamed {
+            if let Err(error) = self.replace_in_dashboards(&name, Some(&group.name), user.clone()) {
+                eprintln!("[ERROR] [Group]: Could not rename the group {} in the dashboards", name);
+                eprintln!("{}", error);
+
+                self.rename_back_in_dashboards(&name, &group.name, user);
+
+                return Err(error);
+            }
+        }
+
+        if let Err(error) = put_entry(&*self.storage, &GROUPS, &name, &group) {
+            if renamed {
+                self.rename_back_in_dashboards(&name, &group.name, user);
+            }
+
+            return Err(error.into());
+        }
+
+        group.revision = next_revision();
+
+        // A renamed group is gone under its old name
+        if renamed {
+            self.events.do_send(EventMessage::Publish(ChangeEvent::new(ChangeKind::Group, ChangeAction::Delete, &name)));
+        }
+
+        self.events.do_send(EventMessage::Publish(ChangeEvent::new(ChangeKind::Group, ChangeAction::Set, &group.name)));
+
+        let before = match self.groups.index_of(name.clone()) {
+            Some(index) => Some(std::mem::replace(&mut self.groups[index], group.clone())),
+            None => {
+                self.groups.push(group.clone());
+
+                None
+            }
+        };
+
+        if before.as_ref().is_none_or(|before| before.name.ne(&group.name)) {
+            self.publish_names();
+        }
+
+        record(&self.history, ChangeKind::Group, &name, user, before.as_ref(), Some(&group));
+
+        Ok(vec![group])
+    }
+
+    /// Changes the items of the stored group. As the change is applied to the current items,
+    /// changes of different items do not overwrite each other.
+    fn change_items<F>(&mut self, name: String, user: Option<String>, change: F) -> Result<Vec<GroupData>, ServiceError>
+    where
+        F: FnOnce(&mut Vec<GroupItemData>) -> Result<(), ServiceError>,
+    {
+        let mut group = self.current(&name)?;
+
+        change(&mut group.items)?;
+
+        self.set(name, group, user)
+    }
+
+    /// Deletes the group. If dashboards still contain it, it is only deleted with `force`, which
+    /// removes it from the dashboards.
+    fn delete(&mut self, name: String, force: bool, user: Option<String>) -> Result<Vec<GroupData>, ServiceError> {
+        let index = match self.groups.index_of(name.clone()) {
+            Some(index) => index,
+            None => return Err(ServiceError::not_found("group", name)),
+        };
+        let used_by = self.used_by(&name)?;
+
+        if !used_by.is_empty() {
+            if !force {
+                return Err(ServiceError::InUse { kind: "group", name, used_by });
+            }
+
+            self.replace_in_dashboards(&name, None, user.clone())?;
+        }
+
+        delete_entry(&*self.storage, &GROUPS, &name)?;
+
+        let group = self.groups.remove(index);
+        self.publish_names();
+        record(&self.history, ChangeKind::Group, &name, user, Some(&group), None);
+        self.events.do_send(EventMessage::Publish(ChangeEvent::new(ChangeKind::Group, ChangeAction::Delete, name)));
+
+        Ok(vec![group])
+    }
+
+    /// Handles the message. Changes are recorded in the history with the user.
+    fn apply(&mut self, msg: GroupMessage, user: Option<String>) -> Result<Vec<GroupData>, ServiceError> {
         match msg {
+            GroupMessage::List => Ok(self.groups.clone()),
             GroupMessage::Reload => {
-                self.groups = Vec::<GroupData>::load();
+                self.groups = Vec::<GroupData>::load(&*self.storage);
+                self.publish_names();
+                self.events.do_send(EventMessage::Publish(ChangeEvent::reload(ChangeKind::Group)));
+                self.check_references();
 
-                MessageResult(None)
+                Ok(self.groups.clone())
             }
             GroupMessage::Get(name) => {
-                if let Some(index) = self.groups.index_of(name) {
-                    return MessageResult(match self.groups.get(index) {
-                        None => None,
-                        Some(group) => Some(group.clone())
-                    });
+                let group = self.groups.single(name.clone());
+
+                if group.is_empty() {
+                    return Err(ServiceError::not_found("group", name));
                 }
 
-                MessageResult(None)
+                Ok(group)
+            }
+            GroupMessage::Set(name, group) => self.set(name, group, user),
+            GroupMessage::Patch(name, change) => {
+                let group = change.apply(&self.current(&name)?)?;
+
+                self.set(name, group, user)
             }
-            GroupMessage::Set(name, group) => {
-                if let Some(index) = self.groups.index_of(name) {
-                    self.groups[index] = group.clone();
-                    self.groups.save();
+            GroupMessage::SetItem(name, item, data) => self.change_items(name, user, |items| {
+                check_name("item", &item, &data.name, |name| items.iter().any(|item| item.name.eq(name)))?;
 
-                    return MessageResult(Some(group));
+                match items.iter().position(|existing| existing.name.eq(&item)) {
+                    Some(index) => items[index] = data,
+                    None => items.push(data),
                 }
 
-                self.groups.push(group.clone());
-                self.groups.save();
+                Ok(())
+            }),
+            GroupMessage::DeleteItem(name, item) => self.change_items(name, user, |items| {
+                let index = item_index(items, &item)?;
 
-                MessageResult(Some(group))
-            }
-            GroupMessage::Delete(name) => {
-                let is_in_use = match futures::executor::block_on(self.dashboard.send(AnyDashboardUsesGroup(name.clone()))) {
-                    Ok(result) => result,
-                    Err(error) => {
-                        eprintln!("[ERROR] [Group Service] {:?}", error);
-
-                        false
-                    }
-                };
-
-                if is_in_use {
-                    return MessageResult(None);
-                }
+                items.remove(index);
 
-                if let Some(index) = self.groups.index_of(name) {
-                    self.groups.save();
+                Ok(())
+            }),
+            GroupMessage::MoveItem(name, item, position) => self.change_items(name, user, |items| {
+                let index = item_index(items, &item)?;
+                let data = items.remove(index);
 
-                    return MessageResult(Some(self.groups.remove(index)));
-                }
+                items.insert(position.min(items.len()), data);
 
-                MessageResult(None)
+                Ok(())
+            }),
+            GroupMessage::Delete(name) => self.delete(name, false, user),
+            GroupMessage::ForceDelete(name) => self.delete(name, true, user),
+        }
+    }
+}
+
+impl Actor for GroupService {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _: &mut Self::Context) {
+        self.publish_names();
+        self.check_references();
+    }
+}
+
+impl Handler<GroupMessage> for GroupService {
+    type Result = MessageResult<GroupMessage>;
+
+    fn handle(&mut self, msg: GroupMessage, _: &mut Self::Context) -> Self::Result {
+        MessageResult(self.apply(msg, None))
+    }
+}
+
+fn item_index(items: &[GroupItemData], name: &str) -> Result<usize, ServiceError> {
+    items
+        .iter()
+        .position(|item| item.name.eq(name))
+        .ok_or_else(|| ServiceError::not_found("item", name))
+}
+
+impl Handler<Edit<GroupMessage>> for GroupService {
+    type Result = MessageResult<Edit<GroupMessage>>;
+
+    fn handle(&mut self, msg: Edit<GroupMessage>, _: &mut Self::Context) -> Self::Result {
+        let Edit { user, precondition, message } = msg;
+
+        if let Some(name) = message.target() {
+            let revision = self.groups.index_of(String::from(name)).map(|index| self.groups[index].revision);
+
+            if !precondition.matches(revision) {
+                return MessageResult(Err(ServiceError::PreconditionFailed { kind: "group", name: String::from(name) }));
             }
         }
+
+        MessageResult(self.apply(message, user))
     }
 }
 
+impl Handler<GroupUsage> for GroupService {
+    type Result = MessageResult<GroupUsage>;
+
+    fn handle(&mut self, msg: GroupUsage, _: &mut Self::Context) -> Self::Result {
+        if self.groups.index_of(msg.0.clone()).is_none() {
+            return MessageResult(Err(ServiceError::not_found("group", msg.0)));
+        }
+
+        MessageResult(self.used_by(&msg.0))
+    }
+}
+
+impl Message for GroupUsage {
+    type Result = Result<Vec<Reference>, ServiceError>;
+}
+
 impl Message for GroupMessage {
-    type Result = Option<GroupData>;
+    type Result = Result<Vec<GroupData>, ServiceError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix::Arbiter;
+    use actix_web::rt::System;
+
+    use super::*;
+    use crate::services::DashboardData;
+    use crate::settings::HistorySettings;
+    use crate::storage::memory::MemoryStorage;
+
+    #[test]
+    fn keeps_the_name_if_the_dashboards_can_not_be_changed() {
+        System::new("group-test").block_on(async {
+            let storage = Arc::new(MemoryStorage::new());
+            let group: GroupData = serde_yaml::from_str("{name: Lights, size: 2, order: 1, items: []}").unwrap();
+            let dashboard: DashboardData = serde_yaml::from_str("{name: Living Room, groups: [Lights]}").unwrap();
+
+            put_entry(&*storage, &GROUPS, "Lights", &group).unwrap();
+            put_entry(&*storage, &DASHBOARDS, "Living Room", &dashboard).unwrap();
+
+            let events = EventService::new().start();
+            let history = HistoryService::new(HistorySettings::default()).start();
+            let dashboards = DashboardService::new(storage.clone(), events.clone(), history.clone()).start();
+
+            // The dashboards are loaded when the service starts, afterwards they can not be written
+            dashboards.send(DashboardMessage::List).await.unwrap().unwrap();
+            storage.mark_unparsed(&DASHBOARDS);
+
+            // The group service waits for the dashboard service, so it needs its own thread
+            let group_storage = storage.clone();
+            let groups = GroupService::start_in_arbiter(&Arbiter::new(), move |_| {
+                GroupService::new(group_storage, dashboards, events, history)
+            });
+            let renamed = GroupData {
+                name: String::from("Lamps"),
+                ..group
+            };
+
+            assert!(groups.send(GroupMessage::Set(String::from("Lights"), renamed)).await.unwrap().is_err());
+            assert!(groups.send(GroupMessage::Get(String::from("Lights"))).await.unwrap().is_ok());
+            assert_eq!(storage.load(&GROUPS)[0].0, "Lights");
+        });
+    }
 }
@@ -0,0 +1,321 @@
+//! This module implements the history service, which records every change of the dashboards,
+//! groups and shortcuts and reverts them.
+//!
+//! The history is stored as JSON Lines (one change per line) in `history.jsonl`. Changes are
+//! appended to it, so reverting a change is recorded as a new change as well.
+//!
+//! Only the newest changes (`history.keep`, not older than `history.max_age`) are kept in memory.
+//! The file is rewritten without the outdated changes on start and once there are
+//! [COMPACT_AFTER] of them.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix::{Actor, Addr, Context, Handler, Message, MessageResult};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::error::ServiceError;
+use crate::paths::data_path;
+use crate::persistence::{write_atomic, PersistenceError};
+use crate::services::{ChangeAction, ChangeKind, DashboardMessage, DashboardService, Edit, GroupMessage, GroupService, HistoryEntry, HistoryMessage, HistoryQuery, HistoryService, Precondition, ShortcutsMessage, ShortcutsService};
+use crate::settings::HistorySettings;
+
+pub const HISTORY_FILE: &str = "history.jsonl";
+
+/// The number of outdated changes in the file after which it is rewritten
+const COMPACT_AFTER: usize = 100;
+
+impl HistoryService {
+    pub fn new(settings: HistorySettings) -> Self {
+        let (entries, last_id, file_entries) = load(&settings);
+        let mut service = Self {
+            settings,
+            entries,
+            last_id,
+            file_entries,
+        };
+
+        service.prune();
+
+        if service.file_entries > service.entries.len() {
+            service.compact();
+        }
+
+        service
+    }
+
+    /// Appends the change to the file and keeps it
+    fn append(&mut self, mut entry: HistoryEntry) -> Result<HistoryEntry, ServiceError> {
+        entry.id = self.last_id + 1;
+
+        let line = serde_json::to_string(&entry).map_err(ServiceError::invalid)?;
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(data_path(HISTORY_FILE))
+            .and_then(|mut file| writeln!(file, "{}", line))
+            .map_err(PersistenceError::Io)?;
+
+        self.last_id = entry.id;
+        self.file_entries += 1;
+        self.entries.push_back(entry.clone());
+        self.prune();
+
+        if self.file_entries >= self.entries.len() + COMPACT_AFTER {
+            self.compact();
+        }
+
+        Ok(entry)
+    }
+
+    /// Drops the changes which are too many or too old
+    fn prune(&mut self) {
+        if self.settings.max_age > 0 {
+            let oldest = now().saturating_sub(self.settings.max_age);
+
+            self.entries.retain(|entry| entry.time >= oldest);
+        }
+
+        if self.settings.keep > 0 && self.entries.len() > self.settings.keep {
+            let outdated = self.entries.len() - self.settings.keep;
+
+            self.entries.drain(..outdated);
+        }
+    }
+
+    /// Rewrites the file with the kept changes only
+    fn compact(&mut self) {
+        let mut content = String::new();
+
+        for entry in &self.entries {
+            match serde_json::to_string(entry) {
+                Ok(line) => content.push_str(&line),
+                Err(error) => {
+                    eprintln!("[ERROR] [History]: Could not write the change {}", entry.id);
+                    eprintln!("{}", error);
+
+                    return;
+                }
+            }
+
+            content.push('\n');
+        }
+
+        if let Err(error) = write_atomic(&data_path(HISTORY_FILE), content.as_bytes()) {
+            eprintln!("[ERROR] [History]: Could not remove the outdated changes from {}", HISTORY_FILE);
+            eprintln!("{}", error);
+
+            return;
+        }
+
+        self.file_entries = self.entries.len();
+    }
+
+    fn list(&self, query: HistoryQuery) -> Vec<HistoryEntry> {
+        self.entries
+            .iter()
+            .rev()
+            .filter(|entry| query.kind.is_none_or(|kind| entry.kind == kind))
+            .filter(|entry| query.name.as_ref().is_none_or(|name| entry.concerns(name)))
+            .take(query.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Reads the recorded changes which are kept, the last id and the number of changes in the file.
+/// Lines which can not be read are skipped.
+fn load(settings: &HistorySettings) -> (VecDeque<HistoryEntry>, u64, usize) {
+    let mut entries = VecDeque::new();
+    let mut last_id = 0;
+    let mut file_entries = 0;
+    let file = match File::open(data_path(HISTORY_FILE)) {
+        Ok(file) => file,
+        Err(_) => return (entries, last_id, file_entries),
+    };
+    let oldest = match settings.max_age {
+        0 => 0,
+        max_age => now().saturating_sub(max_age),
+    };
+
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(error) => {
+                eprintln!("[ERROR] [History]: Could not read {}", HISTORY_FILE);
+                eprintln!("{}", error);
+
+                // The rest of the file must not be lost by rewriting it
+                file_entries = entries.len();
+
+                break;
+            }
+        };
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let entry: HistoryEntry = match serde_json::from_str(&line) {
+            Ok(entry) => entry,
+            Err(error) => {
+                eprintln!("[WARN] [History]: Skipping line {} of {}", number + 1, HISTORY_FILE);
+                eprintln!("{}", error);
+
+                continue;
+            }
+        };
+
+        file_entries += 1;
+        last_id = last_id.max(entry.id);
+
+        if entry.time < oldest {
+            continue;
+        }
+
+        entries.push_back(entry);
+
+        if settings.keep > 0 && entries.len() > settings.keep {
+            entries.pop_front();
+        }
+    }
+
+    (entries, last_id, file_entries)
+}
+
+impl HistoryEntry {
+    /// The name of the entry after the change (it may have been renamed)
+    fn new_name(&self) -> &str {
+        self.after
+            .as_ref()
+            .and_then(|after| after.get("name"))
+            .and_then(Value::as_str)
+            .unwrap_or(&self.name)
+    }
+
+    /// Checks if the change is about the entry with the name (before or after it)
+    fn concerns(&self, name: &str) -> bool {
+        self.name.eq(name) || self.new_name().eq(name)
+    }
+}
+
+/// Records the change of an entry in the history. Without `after` the entry was deleted, without
+/// `before` it was added.
+pub fn record<T: Serialize>(
+    history: &Addr<HistoryService>,
+    kind: ChangeKind,
+    name: &str,
+    user: Option<String>,
+    before: Option<&T>,
+    after: Option<&T>,
+) {
+    let to_value = |data: &T| match serde_json::to_value(data) {
+        Ok(value) => Some(value),
+        Err(error) => {
+            eprintln!("[ERROR] [History]: Could not serialize the change of {}", name);
+            eprintln!("{}", error);
+
+            None
+        }
+    };
+
+    history.do_send(HistoryMessage::Record(HistoryEntry {
+        id: 0,
+        time: now(),
+        user,
+        kind,
+        action: if after.is_some() { ChangeAction::Set } else { ChangeAction::Delete },
+        name: String::from(name),
+        before: before.and_then(to_value),
+        after: after.and_then(to_value),
+    }));
+}
+
+/// The current time in seconds since the epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Restores the entry as it was before the change. An added entry is deleted again. The revert is
+/// done as change of the user, so it is recorded as well.
+pub async fn revert(
+    entry: HistoryEntry,
+    user: Option<String>,
+    dashboard: &Addr<DashboardService>,
+    group: &Addr<GroupService>,
+    shortcuts: &Addr<ShortcutsService>,
+) -> Result<(), ServiceError> {
+    let name = String::from(entry.new_name());
+    let invalid = |error: serde_json::Error| ServiceError::invalid(format!("The change can not be reverted: {}", error));
+
+    match (entry.kind, entry.before) {
+        (ChangeKind::Dashboard, None) => dashboard.send(edit(user, DashboardMessage::Delete(name))).await?.map(drop),
+        (ChangeKind::Dashboard, Some(before)) => {
+            let data = serde_json::from_value(before).map_err(invalid)?;
+
+            dashboard.send(edit(user, DashboardMessage::Set(name, data))).await?.map(drop)
+        }
+        (ChangeKind::Group, None) => group.send(edit(user, GroupMessage::Delete(name))).await?.map(drop),
+        (ChangeKind::Group, Some(before)) => {
+            let data = serde_json::from_value(before).map_err(invalid)?;
+
+            group.send(edit(user, GroupMessage::Set(name, data))).await?.map(drop)
+        }
+        (ChangeKind::Shortcut, None) => shortcuts.send(edit(user, ShortcutsMessage::Delete(name))).await?.map(drop),
+        (ChangeKind::Shortcut, Some(before)) => {
+            let data = serde_json::from_value(before).map_err(invalid)?;
+
+            shortcuts.send(edit(user, ShortcutsMessage::Add(name, data))).await?.map(drop)
+        }
+    }
+}
+
+fn edit<M>(user: Option<String>, message: M) -> Edit<M> {
+    Edit {
+        user,
+        precondition: Precondition::Always,
+        message,
+    }
+}
+
+impl Actor for HistoryService {
+    type Context = Context<Self>;
+}
+
+impl Handler<HistoryMessage> for HistoryService {
+    type Result = MessageResult<HistoryMessage>;
+
+    fn handle(&mut self, msg: HistoryMessage, _: &mut Self::Context) -> Self::Result {
+        match msg {
+            HistoryMessage::Record(entry) => {
+                let result = self.append(entry);
+
+                if let Err(error) = &result {
+                    eprintln!("[ERROR] [History]: Could not record the change");
+                    eprintln!("{}", error);
+                }
+
+                MessageResult(result.map(|entry| vec![entry]))
+            }
+            HistoryMessage::List(query) => MessageResult(Ok(self.list(query))),
+            HistoryMessage::Get(id) => MessageResult(
+                self.entries
+                    .iter()
+                    .find(|entry| entry.id == id)
+                    .map(|entry| vec![entry.clone()])
+                    .ok_or_else(|| ServiceError::not_found("history entry", id)),
+            ),
+        }
+    }
+}
+
+impl Message for HistoryMessage {
+    type Result = Result<Vec<HistoryEntry>, ServiceError>;
+}
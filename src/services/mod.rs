@@ -1,17 +1,41 @@
 //! Here are all the Service related structs and traits
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
-use actix::Addr;
+use std::sync::mpsc::Sender;
+
+use actix::{Addr, Recipient};
+use actix_web::web::Bytes;
+use futures::channel::mpsc::UnboundedSender;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::settings::WebSettings;
+use crate::auth::{AuthenticatedUser, Role};
+use crate::auth::password::CredentialCache;
+use crate::auth::session::SessionSigner;
+use crate::mqtt::client::{ClientCommand, ConnectionState};
+use crate::mqtt::broker::Broker;
+use crate::services::chain::Chains;
+use crate::settings::{BrokerSettings, HistorySettings, MqttSettings, WebSettings};
+use crate::storage::{SharedStorage, Storage};
+use crate::thread_helper::StopFn;
 
 pub mod shortcuts;
 pub mod web_settings;
 pub mod dashboard;
 pub mod group;
+pub mod mqtt;
+pub mod rules;
+pub mod broker;
+pub mod users;
+pub mod events;
+pub mod backup;
+pub mod validation;
+pub mod patch;
+pub mod revision;
+pub mod history;
+pub mod chain;
 
 /// The WebSettings service gives the option to get and load the web settings.
 /// It can give them as a struct or as a "compiled" JavaScript object.
@@ -37,9 +61,42 @@ pub enum WebSettingsCompiledMessage {
 }
 
 /// The shortcuts are used in the frontend to give easier access to all the available functions in the frontend.
-/// TODO: They can also be triggered by incoming MQTT events
+/// They can also be run by the server, which publishes all steps through the [MqttService].
+/// Shortcuts with triggers are run when a matching MQTT message arrives.
 pub struct ShortcutsService {
-    shortcuts: HashMap<String, Vec<ShortcutData>>,
+    shortcuts: HashMap<String, ShortcutEntry>,
+    storage: SharedStorage,
+    mqtt: Addr<MqttService>,
+    mqtt_state: ConnectionState,
+    /// Shared with the [RuleService] (see [chain])
+    chains: Chains,
+    events: Addr<EventService>,
+    history: Addr<HistoryService>,
+}
+
+/// A single shortcut with all of its steps and the messages triggering it.
+///
+/// Shortcuts without triggers (and visibility list) are stored (and sent) as plain list of steps,
+/// as the frontend knows them. Otherwise it is stored as
+/// `{steps: [...], triggers: [...], visible_to: [...]}`. Both formats are accepted.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(from = "shortcuts::ShortcutEntryFormat", into = "shortcuts::ShortcutEntryFormat")]
+pub struct ShortcutEntry {
+    steps: Vec<ShortcutData>,
+    triggers: Vec<ShortcutTrigger>,
+    /// The users (with the viewer role) who can see and run the shortcut. Empty means everyone.
+    visible_to: Vec<String>,
+    /// Not stored, see [revision]
+    revision: u64,
+}
+
+/// Runs the shortcut when a message arrives on a topic matching `topic` and all conditions match
+/// the payload
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ShortcutTrigger {
+    topic: String,
+    #[serde(default)]
+    conditions: Vec<PayloadCondition>,
 }
 
 /// ShortcutData describes the data that is stored in a single shortcut "Task". As a shortcut can
@@ -54,6 +111,11 @@ pub struct ShortcutData {
 /// As an actor it takes care of dashboard actions
 pub struct DashboardService {
     dashboards: Vec<DashboardData>,
+    /// The names of all groups (sent by the [GroupService])
+    groups: Vec<String>,
+    storage: SharedStorage,
+    events: Addr<EventService>,
+    history: Addr<HistoryService>,
 }
 
 /// Contains all dashboard relevant data
@@ -61,12 +123,21 @@ pub struct DashboardService {
 pub struct DashboardData {
     name: String,
     groups: Vec<String>,
+    /// The users (with the viewer role) who can see the dashboard. Empty means everyone.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    visible_to: Vec<String>,
+    /// Not stored, see [revision]
+    #[serde(skip)]
+    revision: u64,
 }
 
 /// This actor takes care of all Group and Group item transactions
 pub struct GroupService {
     groups: Vec<GroupData>,
+    storage: SharedStorage,
     dashboard: Addr<DashboardService>,
+    events: Addr<EventService>,
+    history: Addr<HistoryService>,
 }
 
 /// Contains all dashboard group data
@@ -76,6 +147,9 @@ pub struct GroupData {
     size: i32,
     order: i32,
     items: Vec<GroupItemData>,
+    /// Not stored, see [revision]
+    #[serde(skip)]
+    revision: u64,
 }
 
 /// Contains information
@@ -87,12 +161,266 @@ pub struct GroupItemData {
     data: Value,
 }
 
-/// This trait gives data structs a way to load and save its data from/to a file and also extracts
-/// a named entity as a list as the API needs it
-pub trait DataReadWrite {
-    fn load() -> Self;
+/// The MQTT service holds the connection to the broker. It publishes messages for other services
+/// and distributes incoming messages to the registered listeners.
+pub struct MqttService {
+    settings: MqttSettings,
+    state: ConnectionState,
+    client: Option<Sender<ClientCommand>>,
+    listeners: HashMap<String, (Vec<String>, Recipient<MqttIncoming>)>,
+    on_stop: Option<StopFn>,
+}
+
+/// Runs the embedded MQTT broker (if enabled) for as long as the actor lives
+pub struct BrokerService {
+    settings: BrokerSettings,
+    broker: Option<Broker>,
+    on_stop: Vec<StopFn>,
+}
+
+/// A message which should be published on the broker
+#[derive(Clone, Debug)]
+pub struct MqttPublish {
+    pub topic: String,
+    pub payload: String,
+    pub qos: u8,
+    pub retain: bool,
+}
+
+/// A message which was received from the broker
+#[derive(Clone, Debug)]
+pub struct MqttIncoming {
+    pub topic: String,
+    pub payload: String,
+}
+
+/// The rules service publishes MQTT messages when other messages (matching the rules) arrive
+pub struct RuleService {
+    rules: HashMap<String, RuleData>,
+    storage: SharedStorage,
+    mqtt: Addr<MqttService>,
+    /// Shared with the [ShortcutsService] (see [chain])
+    chains: Chains,
+}
+
+/// A single rule. When a message arrives on a topic matching `topic` and all conditions match the
+/// payload, all actions are published.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RuleData {
+    #[serde(default = "RuleData::default_enabled")]
+    enabled: bool,
+    topic: String,
+    #[serde(default)]
+    conditions: Vec<PayloadCondition>,
+    actions: Vec<RuleAction>,
+}
+
+/// Checks the payload of an incoming message
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PayloadCondition {
+    /// The payload is exactly the given value
+    Equals { value: String },
+
+    /// The payload matches the regular expression
+    Regex { pattern: Pattern },
+
+    /// The payload is JSON and the value at the given path (e.g. `$.state.brightness`) compares
+    /// to the value with the operator
+    Json {
+        path: String,
+        #[serde(default)]
+        operator: JsonOperator,
+        #[serde(default)]
+        value: Value,
+    },
+}
+
+/// A regular expression, which is compiled once when it is read. Invalid ones are kept (with
+/// their error), so they are reported by the validation and never match.
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    source: String,
+    regex: Result<Regex, regex::Error>,
+}
+
+/// The comparison used by [PayloadCondition::Json]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum JsonOperator {
+    #[default]
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    Exists,
+}
+
+/// A message published by a rule.
+/// `{{topic}}` and `{{payload}}` in the payload are replaced with the incoming message.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RuleAction {
+    topic: String,
+    #[serde(default)]
+    payload: String,
+    #[serde(default)]
+    qos: u8,
+    #[serde(default)]
+    retain: bool,
+}
+
+/// The user service holds all users (from the users.yaml) and their API tokens. It creates and
+/// verifies the sessions and authenticates requests.
+pub struct UserService {
+    users: HashMap<String, UserData>,
+    storage: SharedStorage,
+    signer: SessionSigner,
+    session_lifetime: u64,
+    /// Ids of logged out sessions with their expiry
+    revoked: HashMap<String, u64>,
+    credentials: CredentialCache,
+}
+
+/// A single user. A plain text password is replaced by its hash when loaded.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UserData {
+    password: String,
+    #[serde(default)]
+    role: Role,
+    /// The hashes of the API tokens by their name
+    #[serde(default)]
+    tokens: HashMap<String, String>,
+}
+
+/// The credentials a request was sent with
+#[derive(Clone, Debug)]
+pub enum Credentials {
+    /// `Authorization: Basic ...` with user and password
+    Basic(String, String),
+
+    /// `Authorization: Bearer ...` with an API token
+    Token(String),
+
+    /// The session cookie
+    Session(String),
+
+    None,
+}
+
+/// The result of the [Authenticate] message
+#[derive(Clone, Debug)]
+pub enum Authentication {
+    /// There are no users, so authentication is disabled
+    Disabled,
+
+    /// The credentials belong to the user
+    User(AuthenticatedUser),
+
+    /// The credentials are missing or wrong
+    Denied,
+}
+
+/// Authenticates the credentials with the [UserService]
+pub struct Authenticate(pub Credentials);
+
+/// Sends change events to all connected WebSocket clients (`/ws`), so open frontends can refresh
+/// when dashboards, groups or shortcuts change.
+pub struct EventService {
+    subscribers: Vec<(AuthenticatedUser, UnboundedSender<Bytes>)>,
+}
+
+/// A change of the stored data. It is sent to the clients as JSON
+/// (`{"type": "dashboard", "action": "set", "name": "Living Room"}`). The data itself has to be
+/// fetched through the API.
+#[derive(Serialize, Clone, Debug)]
+pub struct ChangeEvent {
+    #[serde(rename = "type")]
+    pub kind: ChangeKind,
+    pub action: ChangeAction,
+    /// The changed entry. Not set when everything was reloaded.
+    pub name: Option<String>,
+    /// Only users who can see this list get the event (see [AuthenticatedUser::can_see])
+    #[serde(skip)]
+    pub visible_to: Vec<String>,
+}
+
+/// What kind of data changed
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Dashboard,
+    Group,
+    Shortcut,
+}
+
+/// How the data changed
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeAction {
+    Set,
+    Delete,
+    Reload,
+}
+
+/// Keeps the history of the changes to dashboards, groups and shortcuts. It is appended to
+/// `history.jsonl` in the data directory, which is rewritten once enough changes are outdated (see
+/// [HistorySettings]).
+pub struct HistoryService {
+    settings: HistorySettings,
+    entries: VecDeque<HistoryEntry>,
+    last_id: u64,
+    /// The number of changes in the file, including the outdated ones
+    file_entries: usize,
+}
+
+/// A change of a dashboard, group or shortcut with the entry before and after it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryEntry {
+    /// Given by the [HistoryService] when the change is recorded
+    #[serde(default)]
+    pub id: u64,
+    /// Unix time (in seconds)
+    pub time: u64,
+    /// The user who changed it (None if the authentication is disabled or it was the console)
+    pub user: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: ChangeKind,
+    pub action: ChangeAction,
+    /// The name of the entry before the change
+    pub name: String,
+    /// The entry before the change (None if it was added)
+    pub before: Option<Value>,
+    /// The entry after the change (None if it was deleted)
+    pub after: Option<Value>,
+}
 
-    fn save(&self);
+/// Filters the history (`/api/history?type=group&name=Lights&limit=10`)
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct HistoryQuery {
+    #[serde(rename = "type")]
+    pub kind: Option<ChangeKind>,
+    pub name: Option<String>,
+    pub limit: Option<usize>,
+}
+
+/// Lists and restores the snapshots of the data files. Restored files are reloaded by the
+/// services using them.
+pub struct BackupService {
+    storage: SharedStorage,
+    web_settings: Addr<WebSettingsService>,
+    dashboard: Addr<DashboardService>,
+    group: Addr<GroupService>,
+    shortcuts: Addr<ShortcutsService>,
+    rules: Addr<RuleService>,
+    users: Addr<UserService>,
+}
+
+/// This trait gives data structs a way to load its data from the [Storage] and also
+/// extracts a named entity as a list as the API needs it
+pub trait DataReadWrite {
+    fn load(storage: &dyn Storage) -> Self;
 
     fn single(&self, which: String) -> Self;
 }
@@ -104,7 +432,8 @@ trait IndexOf<I, T> {
 
 
 /// This enum provides all the options that the [ShortcutsService] is responding to.
-/// All methods return a list of all results (except the Get)
+/// All methods return a list of all results (except the Get). Unknown shortcuts are answered with
+/// [ServiceError::NotFound](crate::error::ServiceError::NotFound).
 pub enum ShortcutsMessage {
     /// Gets a list of all shortcuts
     List,
@@ -116,14 +445,18 @@ pub enum ShortcutsMessage {
     Get(String),
 
     /// Adds a new shortcut with the given name as key in the yaml file
-    Add(String, Vec<ShortcutData>),
+    Add(String, ShortcutEntry),
 
     /// Deletes the given key from the yaml file
     Delete(String),
+
+    /// Publishes all steps of the given shortcut in order. Returns the single shortcut (like Get)
+    Run(String),
 }
 
 /// All the available Dashboard related actions are here
-/// All methods return all (remaining/created) dashboards (except the Get)
+/// All methods return all (remaining/created) dashboards (except the Get). Unknown dashboards are
+/// answered with [ServiceError::NotFound](crate::error::ServiceError::NotFound).
 pub enum DashboardMessage {
     /// Lists all available dashboards
     List,
@@ -137,23 +470,184 @@ pub enum DashboardMessage {
     /// Sets the given dashboard key to the given dashboard
     Set(String, DashboardData),
 
+    /// Applies the change to the stored dashboard, then sets it like [DashboardMessage::Set]
+    Patch(String, Change),
+
     /// Deletes the given dashboard from the yaml file
     Delete(String),
+
+    /// Checks that the dashboards (e.g. of a backup) only contain existing groups, without
+    /// storing them
+    Check(Vec<DashboardData>),
 }
 
 
 /// All the available group related actions are here
+/// The List and Reload return all groups, the other methods the single group. Unknown groups are
+/// answered with [ServiceError::NotFound](crate::error::ServiceError::NotFound).
 pub enum GroupMessage {
+    /// Lists all groups
+    List,
+
     /// Reloads the groups from the yaml file
-    /// Returns an empty group
     Reload,
 
     /// Gets a single group
     Get(String),
 
-    /// Sets the given group key to the given group
+    /// Sets the given group key to the given group. The group is validated first, a different
+    /// name in the body renames the group (also in all dashboards containing it).
     Set(String, GroupData),
 
-    /// Deletes the given group from the yaml file
+    /// Applies the change to the stored group, then sets it like [GroupMessage::Set]
+    Patch(String, Change),
+
+    /// Adds the item to the group or replaces the item with the given name
+    SetItem(String, String, GroupItemData),
+
+    /// Deletes the item from the group
+    DeleteItem(String, String),
+
+    /// Moves the item to the position (starting at 0) in the group
+    MoveItem(String, String, usize),
+
+    /// Deletes the given group from the yaml file. Fails with [ServiceError::InUse](crate::error::ServiceError::InUse) while a
+    /// dashboard contains it.
+    Delete(String),
+
+    /// Deletes the given group and removes it from all dashboards containing it
+    ForceDelete(String),
+}
+
+/// Returns the dashboards containing the group
+pub struct GroupUsage(pub String);
+
+/// A change by a user (None if the authentication is disabled). It is only applied if the entry it
+/// changes matches the precondition, otherwise it is answered with
+/// [ServiceError::PreconditionFailed](crate::error::ServiceError::PreconditionFailed). Applied
+/// changes are recorded in the history.
+pub struct Edit<M> {
+    pub user: Option<String>,
+    pub precondition: Precondition,
+    pub message: M,
+}
+
+/// The `If-Match` of a change
+#[derive(Clone, Debug)]
+pub enum Precondition {
+    /// There is no `If-Match`
+    Always,
+
+    /// `If-Match: *`, the entry has to exist
+    Exists,
+
+    /// The entry has to have one of the revisions
+    Revisions(Vec<u64>),
+}
+
+/// A partial change of a dashboard or group. It is applied to the stored entry as JSON.
+pub enum Change {
+    /// A JSON Merge Patch (RFC 7396): `{"size": 3}` only changes the size
+    Merge(Value),
+
+    /// A JSON Patch (RFC 6902): `[{"op": "replace", "path": "/items/0/data", "value": ...}]`
+    Patch(json_patch::Patch),
+}
+
+/// All actions of the [MqttService]
+pub enum MqttMessage {
+    /// Publishes the message on the broker
+    Publish(MqttPublish),
+
+    /// Registers a listener with the given name. All incoming messages matching one of the topic
+    /// filters are sent to the recipient. Registering the same name again replaces its filters.
+    Listen(String, Vec<String>, Recipient<MqttIncoming>),
+}
+
+/// All rule related actions. All of them return all rules (except the Get). Unknown rules are
+/// answered with [ServiceError::NotFound](crate::error::ServiceError::NotFound).
+pub enum RuleMessage {
+    /// Lists all rules
+    List,
+
+    /// Reloads the rules from the rules.yaml
+    Reload,
+
+    /// Gets a single rule
+    Get(String),
+
+    /// Sets the rule with the given name
+    Set(String, RuleData),
+
+    /// Deletes the given rule from the yaml file
+    Delete(String),
+}
+
+/// All user related actions. All of them return all users (except the Get). Unknown users are
+/// answered with [ServiceError::NotFound](crate::error::ServiceError::NotFound).
+pub enum UserMessage {
+    /// Lists all users
+    List,
+
+    /// Reloads the users from the users.yaml
+    Reload,
+
+    /// Gets a single user
+    Get(String),
+
+    /// Sets the user with the given name. A plain text password is hashed.
+    Set(String, UserData),
+
+    /// Deletes the given user from the yaml file
     Delete(String),
 }
+
+/// Session and token related actions of the [UserService]
+pub enum SessionMessage {
+    /// Checks user and password and returns a new session cookie value. Fails with
+    /// [ServiceError::Unauthorized](crate::error::ServiceError::Unauthorized) for wrong credentials.
+    Login(String, String),
+
+    /// Ends the session of the given cookie value. Returns an empty string.
+    Logout(String),
+
+    /// Creates an API token with the given name (second) for the user (first) and returns it.
+    /// A token with the same name is replaced.
+    CreateToken(String, String),
+
+    /// Deletes the API token with the given name (second) of the user (first). Returns the name.
+    DeleteToken(String, String),
+}
+
+/// All actions of the [EventService]
+pub enum EventMessage {
+    /// Sends all events visible to the user as encoded WebSocket frames through the channel.
+    /// The subscription ends when the channel is closed.
+    Subscribe(AuthenticatedUser, UnboundedSender<Bytes>),
+
+    /// Sends the event to all subscribers
+    Publish(ChangeEvent),
+}
+
+/// All actions of the [HistoryService]. All of them return a list of history entries (newest
+/// first)
+pub enum HistoryMessage {
+    /// Appends the change to the history and returns it with its id
+    Record(HistoryEntry),
+
+    /// Lists the changes matching the query
+    List(HistoryQuery),
+
+    /// Returns the change with the given id
+    Get(u64),
+}
+
+/// All backup related actions. All of them return a list of snapshots (newest first)
+pub enum BackupMessage {
+    /// Lists all snapshots
+    List,
+
+    /// Restores the snapshot with the given name and returns it. The current file (or collection
+    /// of the database) is backed up before.
+    Restore(String),
+}
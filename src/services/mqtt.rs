@@ -0,0 +1,161 @@
+//! This module implements the MQTT service which connects the actors with the broker.
+
+use std::collections::HashMap;
+use std::sync::mpsc::channel;
+
+use actix::{Actor, AsyncContext, Context, Handler, Message};
+
+use crate::error::ServiceError;
+use crate::mqtt::client::{start_client, ClientCommand, ConnectionState};
+use crate::mqtt::packet::Publish;
+use crate::mqtt::topic_matches;
+use crate::services::{MqttIncoming, MqttMessage, MqttPublish, MqttService};
+use crate::settings::MqttSettings;
+
+impl MqttService {
+    pub fn new(settings: MqttSettings, state: ConnectionState) -> Self {
+        Self {
+            settings,
+            state,
+            client: None,
+            listeners: HashMap::new(),
+            on_stop: None,
+        }
+    }
+
+    /// All topic filters of all listeners (without duplicates)
+    fn filters(&self) -> Vec<String> {
+        let mut filters: Vec<String> = Vec::new();
+
+        for (listener_filters, _) in self.listeners.values() {
+            for filter in listener_filters {
+                if !filters.contains(filter) {
+                    filters.push(filter.clone());
+                }
+            }
+        }
+
+        filters
+    }
+
+    fn send(&self, command: ClientCommand) {
+        if let Some(client) = &self.client {
+            if let Err(error) = client.send(command) {
+                eprintln!("[ERROR] [MQTT]: Could not send command to the client");
+                eprintln!("{}", error);
+            }
+        }
+    }
+}
+
+impl Actor for MqttService {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if !self.settings.enabled {
+            println!("MQTT client is disabled");
+
+            return;
+        }
+
+        let addr = ctx.address();
+        let (sender, receiver) = channel();
+        let (stop, _) = start_client(self.settings.clone(), receiver, self.state.clone(), move |publish| {
+            addr.do_send(MqttIncoming {
+                topic: publish.topic,
+                payload: String::from_utf8_lossy(&publish.payload).to_string(),
+            });
+        });
+
+        self.client = Some(sender);
+        self.on_stop = Some(stop);
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        if let Some(fun) = &self.on_stop {
+            fun();
+        }
+    }
+}
+
+impl Handler<MqttMessage> for MqttService {
+    type Result = Result<(), ServiceError>;
+
+    fn handle(&mut self, msg: MqttMessage, _: &mut Self::Context) -> Self::Result {
+        match msg {
+            MqttMessage::Publish(publish) => {
+                if self.client.is_none() {
+                    eprintln!("[WARN] [MQTT]: Client is disabled. Can not publish to {}", publish.topic);
+
+                    return Err(ServiceError::MqttUnavailable);
+                }
+
+                if !self.state.is_connected() {
+                    eprintln!("[WARN] [MQTT]: Client is not connected. Can not publish to {}", publish.topic);
+
+                    return Err(ServiceError::MqttUnavailable);
+                }
+
+                self.send(ClientCommand::Publish(Publish::from(publish)));
+            }
+            MqttMessage::Listen(name, filters, recipient) => {
+                let previous = self.filters();
+
+                self.listeners.insert(name, (filters, recipient));
+
+                let current = self.filters();
+
+                for filter in &previous {
+                    if !current.contains(filter) {
+                        self.send(ClientCommand::Unsubscribe(filter.clone()));
+                    }
+                }
+
+                for filter in current {
+                    if !previous.contains(&filter) {
+                        self.send(ClientCommand::Subscribe(filter));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Handler<MqttIncoming> for MqttService {
+    type Result = ();
+
+    fn handle(&mut self, msg: MqttIncoming, _: &mut Self::Context) -> Self::Result {
+        for (name, (filters, recipient)) in &self.listeners {
+            if !filters.iter().any(|filter| topic_matches(filter, &msg.topic)) {
+                continue;
+            }
+
+            if let Err(error) = recipient.do_send(msg.clone()) {
+                eprintln!("[ERROR] [MQTT]: Could not pass message to listener {}", name);
+                eprintln!("{}", error);
+            }
+        }
+    }
+}
+
+impl From<MqttPublish> for Publish {
+    fn from(publish: MqttPublish) -> Self {
+        Publish {
+            topic: publish.topic,
+            payload: publish.payload.into_bytes(),
+            qos: publish.qos,
+            retain: publish.retain,
+            ..Default::default()
+        }
+    }
+}
+
+impl Message for MqttMessage {
+    type Result = Result<(), ServiceError>;
+}
+
+impl Message for MqttIncoming {
+    type Result = ();
+}
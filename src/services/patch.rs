@@ -0,0 +1,46 @@
+//! Applies [Change]s to dashboards and groups
+
+use json_patch::{merge, patch, Patch};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::error::ServiceError;
+use crate::services::Change;
+
+/// The content type of a JSON Patch. All other JSON bodies are taken as JSON Merge Patch.
+const JSON_PATCH_TYPE: &str = "application/json-patch+json";
+
+impl Change {
+    /// Parses the body of a PATCH request by its content type
+    pub fn parse(content_type: &str, body: &[u8]) -> Result<Self, ServiceError> {
+        let invalid = |error: serde_json::Error| ServiceError::Invalid {
+            message: String::from("The request body is invalid."),
+            details: json!({ "error": error.to_string() }),
+        };
+
+        if content_type.eq(JSON_PATCH_TYPE) {
+            return serde_json::from_slice::<Patch>(body).map(Change::Patch).map_err(invalid);
+        }
+
+        serde_json::from_slice::<Value>(body).map(Change::Merge).map_err(invalid)
+    }
+
+    /// Applies the change to a copy of the entry
+    pub fn apply<T: Serialize + DeserializeOwned>(&self, entry: &T) -> Result<T, ServiceError> {
+        let mut value = serde_json::to_value(entry).map_err(|error| ServiceError::invalid(error.to_string()))?;
+
+        match self {
+            Change::Merge(change) => merge(&mut value, change),
+            Change::Patch(operations) => patch(&mut value, operations).map_err(|error| ServiceError::Invalid {
+                message: String::from("The patch can not be applied."),
+                details: json!({ "error": error.to_string() }),
+            })?,
+        }
+
+        serde_json::from_value(value).map_err(|error| ServiceError::Invalid {
+            message: String::from("The patched entry is invalid."),
+            details: json!({ "error": error.to_string() }),
+        })
+    }
+}
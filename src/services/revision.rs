@@ -0,0 +1,95 @@
+//! Revisions of dashboards, groups and shortcuts for optimistic concurrency
+//!
+//! Every loaded or changed entry gets a new revision. The API sends it as `ETag`
+//! (`"<start of the server>-<revision>"`), so tags from before a restart never match. Changes sent
+//! with `If-Match` are sent as [Edit] and rejected by the service, if the entry was changed in the
+//! meantime.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix::Message;
+
+use crate::services::{Edit, Precondition};
+
+/// The last given revision
+static REVISION: AtomicU64 = AtomicU64::new(0);
+
+/// The start of the server (in seconds), which is part of every ETag
+static EPOCH: OnceLock<u64> = OnceLock::new();
+
+/// A new revision, which is higher than all given before
+pub fn next_revision() -> u64 {
+    REVISION.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+fn epoch() -> u64 {
+    *EPOCH.get_or_init(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default()
+    })
+}
+
+/// The (strong) ETag of the revision, including the quotes
+pub fn etag(revision: u64) -> String {
+    format!("\"{}-{}\"", epoch(), revision)
+}
+
+/// The revisions of the (comma separated) entity tags. Tags of other servers or starts and weak
+/// tags are left out, as they never match.
+fn revisions(tags: &str) -> Vec<u64> {
+    let prefix = format!("{}-", epoch());
+
+    tags.split(',')
+        .filter_map(|tag| {
+            tag.trim()
+                .strip_prefix('"')?
+                .strip_suffix('"')?
+                .strip_prefix(prefix.as_str())?
+                .parse()
+                .ok()
+        })
+        .collect()
+}
+
+impl Precondition {
+    /// Parses the `If-Match` header
+    pub fn parse(if_match: Option<&str>) -> Self {
+        match if_match.map(str::trim) {
+            None => Precondition::Always,
+            Some("*") => Precondition::Exists,
+            Some(tags) => Precondition::Revisions(revisions(tags)),
+        }
+    }
+
+    /// Checks the precondition against the revision of the stored entry (None if there is none)
+    pub fn matches(&self, revision: Option<u64>) -> bool {
+        match self {
+            Precondition::Always => true,
+            Precondition::Exists => revision.is_some(),
+            Precondition::Revisions(revisions) => revision.is_some_and(|revision| revisions.contains(&revision)),
+        }
+    }
+}
+
+/// Checks the `If-None-Match` header of a GET. Weak tags match as well.
+pub fn none_match(if_none_match: &str, revision: u64) -> bool {
+    if if_none_match.trim().eq("*") {
+        return false;
+    }
+
+    let tags = if_none_match
+        .split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .collect::<Vec<&str>>()
+        .join(",");
+
+    !revisions(&tags).contains(&revision)
+}
+
+impl<M: Message> Message for Edit<M> {
+    type Result = M::Result;
+}
@@ -0,0 +1,300 @@
+//! This module implements the rules service, which triggers MQTT topics when other topics arrive.
+//!
+//! Rules can trigger each other and shortcuts (rule A publishes what rule B listens to). Circles
+//! (A -> B -> A) are stopped after [MAX_CHAIN] rules and shortcuts, see [crate::services::chain].
+
+use std::collections::HashMap;
+
+use actix::{Actor, Addr, AsyncContext, Context, Handler, Message, MessageResult};
+use regex::Regex;
+use serde::de::Deserializer;
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::ServiceError;
+use crate::mqtt::topic_matches;
+use crate::services::chain::{Chains, MAX_CHAIN};
+use crate::services::validation::Validate;
+use crate::services::{DataReadWrite, JsonOperator, MqttIncoming, MqttMessage, MqttPublish, MqttService, PayloadCondition, Pattern, RuleAction, RuleData, RuleMessage, RuleService};
+use crate::storage::{delete_entry, load_map, put_entry, SharedStorage, Storage, RULES};
+
+impl RuleService {
+    pub fn new(storage: SharedStorage, mqtt: Addr<MqttService>, chains: Chains) -> Self {
+        Self {
+            rules: HashMap::<String, RuleData>::load(&*storage),
+            storage,
+            mqtt,
+            chains,
+        }
+    }
+
+    /// Registers the topics of all enabled rules with the [MqttService]
+    fn listen(&self, ctx: &mut Context<Self>) {
+        let mut filters: Vec<String> = Vec::new();
+
+        for rule in self.rules.values() {
+            if rule.enabled && !filters.contains(&rule.topic) {
+                filters.push(rule.topic.clone());
+            }
+        }
+
+        self.mqtt.do_send(MqttMessage::Listen(
+            String::from("rules"),
+            filters,
+            ctx.address().recipient(),
+        ));
+    }
+}
+
+impl Actor for RuleService {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.listen(ctx);
+    }
+}
+
+impl Handler<RuleMessage> for RuleService {
+    type Result = MessageResult<RuleMessage>;
+
+    fn handle(&mut self, msg: RuleMessage, ctx: &mut Self::Context) -> Self::Result {
+        match msg {
+            RuleMessage::List => MessageResult(Ok(self.rules.clone())),
+            RuleMessage::Get(name) => {
+                let rule = self.rules.single(name.clone());
+
+                if rule.is_empty() {
+                    return MessageResult(Err(ServiceError::not_found("rule", name)));
+                }
+
+                MessageResult(Ok(rule))
+            }
+            RuleMessage::Set(name, data) => {
+                if let Err(error) = data.validate(&name, self.rules.get(&name)) {
+                    return MessageResult(Err(error));
+                }
+
+                if let Err(error) = put_entry(&*self.storage, &RULES, &name, &data) {
+                    return MessageResult(Err(error.into()));
+                }
+
+                self.rules.insert(name, data);
+                self.listen(ctx);
+
+                MessageResult(Ok(self.rules.clone()))
+            }
+            RuleMessage::Delete(name) => {
+                if !self.rules.contains_key(&name) {
+                    return MessageResult(Err(ServiceError::not_found("rule", name)));
+                }
+
+                if let Err(error) = delete_entry(&*self.storage, &RULES, &name) {
+                    return MessageResult(Err(error.into()));
+                }
+
+                self.rules.remove(&name);
+                self.listen(ctx);
+
+                MessageResult(Ok(self.rules.clone()))
+            }
+            RuleMessage::Reload => {
+                self.rules = HashMap::<String, RuleData>::load(&*self.storage);
+                self.listen(ctx);
+
+                MessageResult(Ok(self.rules.clone()))
+            }
+        }
+    }
+}
+
+impl Handler<MqttIncoming> for RuleService {
+    type Result = ();
+
+    fn handle(&mut self, msg: MqttIncoming, _: &mut Self::Context) -> Self::Result {
+        let length = self.chains.length("rules", &msg);
+
+        for (name, rule) in &self.rules {
+            if !rule.matches(&msg) {
+                continue;
+            }
+
+            if length >= MAX_CHAIN {
+                eprintln!(
+                    "[WARN] [Rules]: {} rules and shortcuts triggered each other in a row. Rule {} is not run for {}, they probably trigger each other in a circle",
+                    length, name, msg.topic
+                );
+
+                continue;
+            }
+
+            for action in &rule.actions {
+                if topic_matches(&rule.topic, &action.topic) {
+                    eprintln!("[WARN] [Rules]: Rule {} would trigger itself by publishing to {}. Skipping", name, action.topic);
+
+                    continue;
+                }
+
+                let publish = action.build(&msg);
+
+                self.chains.published(&publish, length + 1);
+                self.mqtt.do_send(MqttMessage::Publish(publish));
+            }
+        }
+    }
+}
+
+impl Message for RuleMessage {
+    type Result = Result<HashMap<String, RuleData>, ServiceError>;
+}
+
+impl RuleData {
+    pub fn default_enabled() -> bool {
+        true
+    }
+
+    /// Checks if the incoming message triggers this rule
+    pub fn matches(&self, msg: &MqttIncoming) -> bool {
+        self.enabled
+            && topic_matches(&self.topic, &msg.topic)
+            && self.conditions.iter().all(|condition| condition.matches(&msg.payload))
+    }
+}
+
+impl PayloadCondition {
+    /// Checks if the payload fulfills this condition
+    pub fn matches(&self, payload: &str) -> bool {
+        match self {
+            PayloadCondition::Equals { value } => payload.eq(value),
+            PayloadCondition::Regex { pattern } => pattern.regex.as_ref().is_ok_and(|regex| regex.is_match(payload)),
+            PayloadCondition::Json { path, operator, value } => {
+                let payload = match serde_json::from_str::<Value>(payload) {
+                    Ok(payload) => payload,
+                    Err(_) => return false,
+                };
+
+                operator.compare(json_path(&payload, path), value)
+            }
+        }
+    }
+}
+
+impl Pattern {
+    pub fn new(source: String) -> Self {
+        let regex = Regex::new(&source);
+
+        Self { source, regex }
+    }
+
+    /// Why the regular expression is invalid
+    pub fn error(&self) -> Option<&regex::Error> {
+        self.regex.as_ref().err()
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Pattern::new)
+    }
+}
+
+impl JsonOperator {
+    /// Compares the found value (if any) with the expected one
+    pub fn compare(&self, found: Option<&Value>, expected: &Value) -> bool {
+        let found = match (self, found) {
+            (JsonOperator::Exists, found) => return found.is_some(),
+            (JsonOperator::Ne, None) => return true,
+            (_, None) => return false,
+            (_, Some(found)) => found,
+        };
+
+        match self {
+            JsonOperator::Eq => json_equals(found, expected),
+            JsonOperator::Ne => !json_equals(found, expected),
+            _ => match (found.as_f64(), expected.as_f64()) {
+                (Some(found), Some(expected)) => match self {
+                    JsonOperator::Gt => found > expected,
+                    JsonOperator::Gte => found >= expected,
+                    JsonOperator::Lt => found < expected,
+                    JsonOperator::Lte => found <= expected,
+                    _ => false,
+                },
+                _ => false,
+            },
+        }
+    }
+}
+
+impl RuleAction {
+    /// Builds the message to publish for the incoming message
+    pub fn build(&self, msg: &MqttIncoming) -> MqttPublish {
+        MqttPublish {
+            topic: self.topic.clone(),
+            payload: self
+                .payload
+                .replace("{{topic}}", &msg.topic)
+                .replace("{{payload}}", &msg.payload),
+            qos: self.qos,
+            retain: self.retain,
+        }
+    }
+}
+
+/// Compares two JSON values. Numbers are compared by their value (`1` equals `1.0`)
+fn json_equals(left: &Value, right: &Value) -> bool {
+    match (left.as_f64(), right.as_f64()) {
+        (Some(left), Some(right)) => (left - right).abs() < f64::EPSILON,
+        _ => left.eq(right),
+    }
+}
+
+/// Resolves a simple JSON path like `$.state.items[0].name` (the leading `$.` is optional)
+fn json_path<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    let path = path.trim_start_matches('$').trim_start_matches('.');
+    let mut current = value;
+
+    if path.is_empty() {
+        return Some(current);
+    }
+
+    for segment in path.split('.') {
+        let mut parts = segment.split('[');
+
+        if let Some(key) = parts.next() {
+            if !key.is_empty() {
+                current = current.get(key)?;
+            }
+        }
+
+        for index in parts {
+            let index = index.trim_end_matches(']').parse::<usize>().ok()?;
+            current = current.get(index)?;
+        }
+    }
+
+    Some(current)
+}
+
+impl DataReadWrite for HashMap<String, RuleData> {
+    fn load(storage: &dyn Storage) -> Self {
+        load_map(storage, &RULES)
+    }
+
+    fn single(&self, which: String) -> Self {
+        if let Some(data) = self.get(&which) {
+            let mut map = HashMap::new();
+
+            map.insert(which, data.clone());
+
+            return map;
+        }
+
+        HashMap::new()
+    }
+}
@@ -1,109 +1,336 @@
 //! This module implements all shortcut related structs and traits.
 
 use std::collections::HashMap;
-use std::env::current_dir;
-use std::fs::OpenOptions;
-use std::io::Write;
 
-use actix::{Actor, Context, Handler, Message, MessageResult};
+use actix::{Actor, Addr, AsyncContext, Context, Handler, Message, MessageResult};
+use serde::{Deserialize, Serialize};
 
-use crate::services::{ShortcutData, DataReadWrite, ShortcutsMessage, ShortcutsService};
+use crate::error::ServiceError;
+use crate::mqtt::client::ConnectionState;
+use crate::mqtt::topic_matches;
+use crate::services::chain::{Chains, MAX_CHAIN};
+use crate::services::history::record;
+use crate::services::revision::next_revision;
+use crate::services::validation::Validate;
+use crate::services::{ChangeAction, ChangeEvent, ChangeKind, EventMessage, EventService, ShortcutData, DataReadWrite, Edit, HistoryService, MqttIncoming, MqttMessage, MqttPublish, MqttService, ShortcutEntry, ShortcutTrigger, ShortcutsMessage, ShortcutsService};
+use crate::storage::{delete_entry, load_map, put_entry, SharedStorage, Storage, SHORTCUTS};
+
+/// The (de)serialization format of a [ShortcutEntry]
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ShortcutEntryFormat {
+    Steps(Vec<ShortcutData>),
+    Full {
+        steps: Vec<ShortcutData>,
+        #[serde(default)]
+        triggers: Vec<ShortcutTrigger>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        visible_to: Vec<String>,
+    },
+}
 
 impl ShortcutsService {
-    pub fn new() -> Self {
+    pub fn new(
+        storage: SharedStorage,
+        mqtt: Addr<MqttService>,
+        mqtt_state: ConnectionState,
+        chains: Chains,
+        events: Addr<EventService>,
+        history: Addr<HistoryService>,
+    ) -> Self {
         Self {
-            shortcuts: HashMap::<String, Vec<ShortcutData>>::load(),
+            shortcuts: HashMap::<String, ShortcutEntry>::load(&*storage),
+            storage,
+            mqtt,
+            mqtt_state,
+            chains,
+            events,
+            history,
         }
     }
-}
 
-impl Actor for ShortcutsService {
-    type Context = Context<Self>;
-}
+    /// Handles the message. Changes are recorded in the history with the user.
+    fn apply(
+        &mut self,
+        msg: ShortcutsMessage,
+        user: Option<String>,
+        ctx: &mut Context<Self>,
+    ) -> Result<HashMap<String, ShortcutEntry>, ServiceError> {
+        match msg {
+            ShortcutsMessage::List => Ok(self.shortcuts.clone()),
+            ShortcutsMessage::Get(name) => self.get(name),
+            ShortcutsMessage::Add(name, mut data) => {
+                data.validate(&name, self.shortcuts.get(&name))?;
+                put_entry(&*self.storage, &SHORTCUTS, &name, &data)?;
+                data.revision = next_revision();
 
-impl Handler<ShortcutsMessage> for ShortcutsService {
-    type Result = MessageResult<ShortcutsMessage>;
+                self.events.do_send(EventMessage::Publish(
+                    ChangeEvent::new(ChangeKind::Shortcut, ChangeAction::Set, &name).visible_to(&data.visible_to),
+                ));
 
-    fn handle(&mut self, msg: ShortcutsMessage, _: &mut Self::Context) -> Self::Result {
-        match msg {
-            ShortcutsMessage::List => MessageResult(self.shortcuts.clone()),
-            ShortcutsMessage::Get(name) => MessageResult(self.shortcuts.single(name)),
-            ShortcutsMessage::Add(name, data) => {
-                self.shortcuts.insert(name, data);
-                self.shortcuts.save();
+                let before = self.shortcuts.insert(name.clone(), data.clone());
+
+                record(&self.history, ChangeKind::Shortcut, &name, user, before.as_ref(), Some(&data));
+                self.listen(ctx);
 
-                MessageResult(self.shortcuts.clone())
+                Ok(self.shortcuts.clone())
             }
             ShortcutsMessage::Delete(name) => {
-                self.shortcuts.remove(&name);
-                self.shortcuts.save();
+                if !self.shortcuts.contains_key(&name) {
+                    return Err(ServiceError::not_found("shortcut", name));
+                }
+
+                delete_entry(&*self.storage, &SHORTCUTS, &name)?;
+
+                if let Some(shortcut) = self.shortcuts.remove(&name) {
+                    record(&self.history, ChangeKind::Shortcut, &name, user, Some(&shortcut), None);
+                    self.events.do_send(EventMessage::Publish(
+                        ChangeEvent::new(ChangeKind::Shortcut, ChangeAction::Delete, name).visible_to(&shortcut.visible_to),
+                    ));
+                }
+
+                self.listen(ctx);
+
+                Ok(self.shortcuts.clone())
+            }
+            ShortcutsMessage::Run(name) => {
+                let shortcut = self.get(name.clone())?;
 
-                MessageResult(self.shortcuts.clone())
+                // Running while disconnected would silently drop the steps
+                if !self.mqtt_state.is_connected() {
+                    return Err(ServiceError::MqttUnavailable);
+                }
+
+                self.run(&name, None, 1);
+
+                Ok(shortcut)
             }
             ShortcutsMessage::Reload => {
-                self.shortcuts = HashMap::<String, Vec<ShortcutData>>::load();
+                self.shortcuts = HashMap::<String, ShortcutEntry>::load(&*self.storage);
+                self.listen(ctx);
+                self.events.do_send(EventMessage::Publish(ChangeEvent::reload(ChangeKind::Shortcut)));
 
-                MessageResult(self.shortcuts.clone())
+                Ok(self.shortcuts.clone())
             }
         }
     }
-}
 
-impl Message for ShortcutsMessage {
-    type Result = HashMap<String, Vec<ShortcutData>>;
-}
+    /// The single shortcut (as map)
+    fn get(&self, name: String) -> Result<HashMap<String, ShortcutEntry>, ServiceError> {
+        let shortcut = self.shortcuts.single(name.clone());
+
+        if shortcut.is_empty() {
+            return Err(ServiceError::not_found("shortcut", name));
+        }
+
+        Ok(shortcut)
+    }
+
+    /// Publishes all steps of the shortcut in order. `length` is the number of rules and shortcuts
+    /// (including this one) which led to the run (see [crate::services::chain]).
+    /// Steps publishing to a topic matching `skip_filter` are skipped (so a shortcut can not
+    /// trigger itself)
+    fn run(&self, name: &str, skip_filter: Option<&str>, length: usize) {
+        let shortcut = match self.shortcuts.get(name) {
+            Some(shortcut) => shortcut,
+            None => {
+                eprintln!("[WARN] [Shortcuts]: Can not run unknown shortcut {}", name);
 
-impl DataReadWrite for HashMap<String, Vec<ShortcutData>> {
-    fn load() -> Self {
-        let shortcuts_path = current_dir().unwrap().join("shortcuts.yaml");
-        let file = OpenOptions::new().read(true).open(shortcuts_path);
+                return;
+            }
+        };
 
-        match file {
-            Ok(file) => match serde_yaml::from_reader(file) {
-                Ok(shortcuts) => shortcuts,
-                Err(error) => {
-                    eprintln!("[ERROR] [Shortcuts]: Could not parse file");
-                    eprintln!("{}", error);
+        for step in &shortcut.steps {
+            if let Some(filter) = skip_filter {
+                if topic_matches(filter, &step.topic) {
+                    eprintln!("[WARN] [Shortcuts]: Shortcut {} would trigger itself by publishing to {}. Skipping", name, step.topic);
 
-                    HashMap::new()
+                    continue;
                 }
-            },
-            Err(error) => {
-                eprintln!("[WARN] [Shortcuts]: Could not open file");
-                eprintln!("{}", error);
+            }
+
+            let publish = step.to_publish();
+
+            self.chains.published(&publish, length);
+            self.mqtt.do_send(MqttMessage::Publish(publish));
+        }
+    }
 
-                HashMap::new()
+    /// Registers the topics of all triggers with the [MqttService]
+    fn listen(&self, ctx: &mut Context<Self>) {
+        let mut filters: Vec<String> = Vec::new();
+
+        for shortcut in self.shortcuts.values() {
+            for trigger in &shortcut.triggers {
+                if !filters.contains(&trigger.topic) {
+                    filters.push(trigger.topic.clone());
+                }
             }
         }
+
+        self.mqtt.do_send(MqttMessage::Listen(
+            String::from("shortcuts"),
+            filters,
+            ctx.address().recipient(),
+        ));
+    }
+}
+
+impl From<ShortcutEntryFormat> for ShortcutEntry {
+    fn from(format: ShortcutEntryFormat) -> Self {
+        match format {
+            ShortcutEntryFormat::Steps(steps) => ShortcutEntry {
+                steps,
+                triggers: Vec::new(),
+                visible_to: Vec::new(),
+                revision: 0,
+            },
+            ShortcutEntryFormat::Full { steps, triggers, visible_to } => ShortcutEntry {
+                steps,
+                triggers,
+                visible_to,
+                revision: 0,
+            },
+        }
     }
+}
 
-    fn save(&self) {
-        let shortcuts_path = current_dir().unwrap().join("shortcuts.yaml");
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(shortcuts_path);
+impl From<ShortcutEntry> for ShortcutEntryFormat {
+    fn from(entry: ShortcutEntry) -> Self {
+        if entry.triggers.is_empty() && entry.visible_to.is_empty() {
+            return ShortcutEntryFormat::Steps(entry.steps);
+        }
 
-        match file {
-            Ok(mut file) => {
-                if let Err(error) = serde_yaml::to_writer(&file, &self) {
-                    eprintln!("[ERROR] [Shortcuts]: Could not write shortcuts");
-                    eprintln!("{}", error);
+        ShortcutEntryFormat::Full {
+            steps: entry.steps,
+            triggers: entry.triggers,
+            visible_to: entry.visible_to,
+        }
+    }
+}
 
-                    return;
-                }
+impl ShortcutEntry {
+    pub fn visible_to(&self) -> &[String] {
+        &self.visible_to
+    }
 
-                if let Err(error) = file.flush() {
-                    eprintln!("[WARN] [Shortcuts]: Could not fully write shortcuts");
-                    eprintln!("{}", error);
-                }
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+}
+
+impl ShortcutsMessage {
+    /// The shortcut the message is about
+    fn target(&self) -> Option<&str> {
+        match self {
+            ShortcutsMessage::List | ShortcutsMessage::Reload => None,
+            ShortcutsMessage::Get(name)
+            | ShortcutsMessage::Add(name, _)
+            | ShortcutsMessage::Delete(name)
+            | ShortcutsMessage::Run(name) => Some(name),
+        }
+    }
+}
+
+impl ShortcutTrigger {
+    /// Checks if the incoming message fires this trigger
+    pub fn matches(&self, msg: &MqttIncoming) -> bool {
+        topic_matches(&self.topic, &msg.topic)
+            && self.conditions.iter().all(|condition| condition.matches(&msg.payload))
+    }
+}
+
+impl ShortcutData {
+    /// Builds the MQTT message for this step. The QoS and retain flag are taken from the options
+    /// (`{"qos": 1, "retain": true}`).
+    pub fn to_publish(&self) -> MqttPublish {
+        MqttPublish {
+            topic: self.topic.clone(),
+            payload: self.payload.clone(),
+            qos: self.qos().unwrap_or(0).min(2) as u8,
+            retain: self.options.get("retain").and_then(|retain| retain.as_bool()).unwrap_or(false),
+        }
+    }
+
+    /// The QoS of the options (None if there is none or it is no number)
+    pub fn qos(&self) -> Option<u64> {
+        self.options.get("qos").and_then(|qos| qos.as_u64())
+    }
+}
+
+impl Actor for ShortcutsService {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.listen(ctx);
+    }
+}
+
+impl Handler<ShortcutsMessage> for ShortcutsService {
+    type Result = MessageResult<ShortcutsMessage>;
+
+    fn handle(&mut self, msg: ShortcutsMessage, ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.apply(msg, None, ctx))
+    }
+}
+
+impl Handler<Edit<ShortcutsMessage>> for ShortcutsService {
+    type Result = MessageResult<Edit<ShortcutsMessage>>;
+
+    fn handle(&mut self, msg: Edit<ShortcutsMessage>, ctx: &mut Self::Context) -> Self::Result {
+        let Edit { user, precondition, message } = msg;
+
+        if let Some(name) = message.target() {
+            let revision = self.shortcuts.get(name).map(|shortcut| shortcut.revision);
+
+            if !precondition.matches(revision) {
+                return MessageResult(Err(ServiceError::PreconditionFailed { kind: "shortcut", name: String::from(name) }));
             }
-            Err(error) => {
-                eprintln!("[ERROR] [Shortcuts]: Could not open/create file");
-                eprintln!("{}", error);
+        }
+
+        MessageResult(self.apply(message, user, ctx))
+    }
+}
+
+impl Handler<MqttIncoming> for ShortcutsService {
+    type Result = ();
+
+    fn handle(&mut self, msg: MqttIncoming, _: &mut Self::Context) -> Self::Result {
+        let length = self.chains.length("shortcuts", &msg);
+
+        for (name, shortcut) in &self.shortcuts {
+            let trigger = match shortcut.triggers.iter().find(|trigger| trigger.matches(&msg)) {
+                Some(trigger) => trigger,
+                None => continue,
+            };
+
+            if length >= MAX_CHAIN {
+                eprintln!(
+                    "[WARN] [Shortcuts]: {} rules and shortcuts triggered each other in a row. Shortcut {} is not run for {}, they probably trigger each other in a circle",
+                    length, name, msg.topic
+                );
+
+                continue;
             }
+
+            self.run(name, Some(&trigger.topic), length + 1);
+        }
+    }
+}
+
+impl Message for ShortcutsMessage {
+    type Result = Result<HashMap<String, ShortcutEntry>, ServiceError>;
+}
+
+impl DataReadWrite for HashMap<String, ShortcutEntry> {
+    fn load(storage: &dyn Storage) -> Self {
+        let mut shortcuts: Self = load_map(storage, &SHORTCUTS);
+
+        for shortcut in shortcuts.values_mut() {
+            shortcut.revision = next_revision();
         }
+
+        shortcuts
     }
 
     fn single(&self, which: String) -> Self {
@@ -118,3 +345,145 @@ impl DataReadWrite for HashMap<String, Vec<ShortcutData>> {
         HashMap::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use actix_web::rt::time::delay_for;
+    use actix_web::rt::System;
+
+    use super::*;
+    use crate::mqtt::broker::Broker;
+    use crate::services::{RuleData, RuleService};
+    use crate::settings::{BrokerSettings, HistorySettings, MqttSettings};
+    use crate::storage::memory::MemoryStorage;
+    use crate::storage::RULES;
+
+    /// Counts the received messages
+    struct Counter(Arc<AtomicUsize>);
+
+    impl Actor for Counter {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<MqttIncoming> for Counter {
+        type Result = ();
+
+        fn handle(&mut self, _: MqttIncoming, _: &mut Self::Context) -> Self::Result {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Runs the shortcut `start` with the shortcuts and rules (as YAML) connected to an embedded
+    /// broker. Returns the number of messages published on `loop/#` until nothing happens anymore.
+    fn published_messages(shortcuts: &'static str, rules: &'static str) -> usize {
+        System::new("shortcuts-test").block_on(async move {
+            let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+            let broker = Broker::new(BrokerSettings::default());
+            let stop_broker = broker.listen_tcp(&format!("127.0.0.1:{}", port)).unwrap();
+
+            let storage = Arc::new(MemoryStorage::new());
+
+            for (name, shortcut) in serde_yaml::from_str::<HashMap<String, ShortcutEntry>>(shortcuts).unwrap() {
+                put_entry(&*storage, &SHORTCUTS, &name, &shortcut).unwrap();
+            }
+
+            for (name, rule) in serde_yaml::from_str::<HashMap<String, RuleData>>(rules).unwrap() {
+                put_entry(&*storage, &RULES, &name, &rule).unwrap();
+            }
+
+            let state = ConnectionState::default();
+            let settings = MqttSettings {
+                host: String::from("127.0.0.1"),
+                port,
+                client_id: format!("shortcuts-test-{}", port),
+                ..Default::default()
+            };
+            let mqtt = MqttService::new(settings, state.clone()).start();
+            let chains = Chains::default();
+            let shortcuts = ShortcutsService::new(
+                storage.clone(),
+                mqtt.clone(),
+                state.clone(),
+                chains.clone(),
+                EventService::new().start(),
+                HistoryService::new(HistorySettings::default()).start(),
+            )
+            .start();
+            let _rules = RuleService::new(storage, mqtt.clone(), chains).start();
+            let received = Arc::new(AtomicUsize::new(0));
+            let counter = Counter(received.clone()).start();
+
+            mqtt.do_send(MqttMessage::Listen(String::from("test"), vec![String::from("loop/#")], counter.recipient()));
+
+            let start = Instant::now();
+
+            while !state.is_connected() {
+                assert!(start.elapsed() < Duration::from_secs(5), "The client did not connect");
+                delay_for(Duration::from_millis(10)).await;
+            }
+
+            // Waits for the subscriptions
+            delay_for(Duration::from_millis(300)).await;
+            shortcuts.send(ShortcutsMessage::Run(String::from("start"))).await.unwrap().unwrap();
+
+            let mut count = 0;
+
+            loop {
+                delay_for(Duration::from_millis(300)).await;
+
+                let current = received.load(Ordering::SeqCst);
+
+                if (current > 0 && current == count) || start.elapsed() > Duration::from_secs(10) {
+                    break;
+                }
+
+                count = current;
+            }
+
+            stop_broker();
+            broker.shutdown();
+
+            count
+        })
+    }
+
+    #[test]
+    fn stops_shortcuts_triggering_each_other() {
+        let shortcuts = r#"
+start:
+  steps: [{topic: loop/a, payload: "1", options: {}}]
+a:
+  steps: [{topic: loop/b, payload: "1", options: {}}]
+  triggers: [{topic: loop/a}]
+b:
+  steps: [{topic: loop/a, payload: "1", options: {}}]
+  triggers: [{topic: loop/b}]
+"#;
+
+        // start -> a -> b -> a -> ... until the chain is too long
+        assert_eq!(published_messages(shortcuts, "{}"), MAX_CHAIN);
+    }
+
+    #[test]
+    fn stops_rules_and_shortcuts_triggering_each_other() {
+        let shortcuts = r#"
+start:
+  steps: [{topic: loop/a, payload: "1", options: {}}]
+a:
+  steps: [{topic: loop/b, payload: "1", options: {}}]
+  triggers: [{topic: loop/a}]
+"#;
+        let rules = r#"
+b:
+  topic: loop/b
+  actions: [{topic: loop/a, payload: "1"}]
+"#;
+
+        assert_eq!(published_messages(shortcuts, rules), MAX_CHAIN);
+    }
+}
@@ -0,0 +1,319 @@
+//! This module implements the user service, which manages the users, sessions and API tokens.
+
+use std::collections::HashMap;
+
+use actix::{Actor, Context, Handler, Message, MessageResult};
+
+use crate::auth::{AuthenticatedUser, Role};
+use crate::auth::password::{hash_password, is_password_hash, CredentialCache};
+use crate::auth::session::{generate_token, now, token_hash, SessionSigner};
+use crate::error::ServiceError;
+use crate::persistence::load_yaml;
+use crate::services::{Authenticate, Authentication, Credentials, DataReadWrite, SessionMessage, UserData, UserMessage, UserService, WebSettingsService};
+use crate::settings::{AuthSettings, WebSettings};
+use crate::storage::{delete_entry, load_map, put_entry, SharedStorage, Storage, USERS};
+
+impl UserService {
+    pub fn new(storage: SharedStorage, settings: AuthSettings) -> Self {
+        let mut service = Self {
+            users: HashMap::<String, UserData>::load(&*storage),
+            storage,
+            signer: SessionSigner::new(&settings.session_secret),
+            session_lifetime: settings.session_lifetime,
+            revoked: HashMap::new(),
+            credentials: CredentialCache::default(),
+        };
+
+        if service.users.is_empty() {
+            service.import_backend_user();
+        }
+
+        service.hash_passwords();
+        service.warn_if_open();
+
+        service
+    }
+
+    /// Warns that everybody can use the API while there are no users
+    fn warn_if_open(&self) {
+        if !self.users.is_empty() || self.storage.is_unparsed(&USERS) {
+            return;
+        }
+
+        eprintln!("[WARN] [Users]: ********************************************************************");
+        eprintln!("[WARN] [Users]: There are no users, so the authentication is DISABLED.");
+        eprintln!("[WARN] [Users]: Everybody who can reach the server can use and change everything.");
+        eprintln!("[WARN] [Users]: Add a user with /set_user <name> <password> to enable it.");
+        eprintln!("[WARN] [Users]: ********************************************************************");
+    }
+
+    /// Takes over the `backend_user` of the web settings as first user
+    fn import_backend_user(&mut self) {
+        let settings: WebSettings = load_yaml(&WebSettingsService::settings_path(), "Web Settings");
+
+        if settings.backend_user.is_empty() {
+            return;
+        }
+
+        println!("[Users]: Importing backend user {} from the web settings", settings.backend_user);
+
+        let user = UserData::new(settings.backend_pass, Role::Admin);
+
+        if let Err(error) = self.store(settings.backend_user, user) {
+            eprintln!("[ERROR] [Users]: Could not write the imported user");
+            eprintln!("{}", error);
+        }
+    }
+
+    /// Replaces all plain text passwords with their hashes
+    fn hash_passwords(&mut self) {
+        for (name, user) in self.users.iter_mut() {
+            if !user.hash_password() {
+                continue;
+            }
+
+            if let Err(error) = put_entry(&*self.storage, &USERS, name, user) {
+                eprintln!("[ERROR] [Users]: Could not write the hashed password of {}", name);
+                eprintln!("{}", error);
+            }
+        }
+    }
+
+    /// Writes the changed user and takes it over
+    fn store(&mut self, name: String, user: UserData) -> Result<(), ServiceError> {
+        put_entry(&*self.storage, &USERS, &name, &user)?;
+        self.users.insert(name, user);
+
+        Ok(())
+    }
+
+    fn authenticate(&mut self, credentials: Credentials) -> Authentication {
+        if self.users.is_empty() {
+            // A broken users.yaml must not disable the authentication
+            if self.storage.is_unparsed(&USERS) {
+                return Authentication::Denied;
+            }
+
+            return Authentication::Disabled;
+        }
+
+        let user = match credentials {
+            Credentials::Basic(name, password) => self
+                .users
+                .get(&name)
+                .filter(|user| self.credentials.verify(&password, &user.password))
+                .map(|_| name),
+            Credentials::Token(token) => {
+                let hash = token_hash(&token);
+
+                self.users
+                    .iter()
+                    .find(|(_, user)| user.tokens.values().any(|token| token.eq(&hash)))
+                    .map(|(name, _)| name.clone())
+            }
+            Credentials::Session(value) => self
+                .signer
+                .verify(&value)
+                .filter(|session| !self.revoked.contains_key(&session.id))
+                .filter(|session| self.users.contains_key(&session.user))
+                .map(|session| session.user),
+            Credentials::None => None,
+        };
+
+        match user.and_then(|name| self.users.get(&name).map(|user| (name, user.role))) {
+            Some((name, role)) => Authentication::User(AuthenticatedUser { name, role }),
+            None => Authentication::Denied,
+        }
+    }
+}
+
+impl Actor for UserService {
+    type Context = Context<Self>;
+}
+
+impl Handler<UserMessage> for UserService {
+    type Result = MessageResult<UserMessage>;
+
+    fn handle(&mut self, msg: UserMessage, _: &mut Self::Context) -> Self::Result {
+        match msg {
+            UserMessage::List => MessageResult(Ok(self.users.clone())),
+            UserMessage::Get(name) => {
+                let user = self.users.single(name.clone());
+
+                if user.is_empty() {
+                    return MessageResult(Err(ServiceError::not_found("user", name)));
+                }
+
+                MessageResult(Ok(user))
+            }
+            UserMessage::Set(name, mut data) => {
+                data.hash_password();
+
+                if let Err(error) = put_entry(&*self.storage, &USERS, &name, &data) {
+                    return MessageResult(Err(error.into()));
+                }
+
+                self.users.insert(name, data);
+
+                MessageResult(Ok(self.users.clone()))
+            }
+            UserMessage::Delete(name) => {
+                if !self.users.contains_key(&name) {
+                    return MessageResult(Err(ServiceError::not_found("user", name)));
+                }
+
+                if let Err(error) = delete_entry(&*self.storage, &USERS, &name) {
+                    return MessageResult(Err(error.into()));
+                }
+
+                self.users.remove(&name);
+                self.warn_if_open();
+
+                MessageResult(Ok(self.users.clone()))
+            }
+            UserMessage::Reload => {
+                self.users = HashMap::<String, UserData>::load(&*self.storage);
+                self.hash_passwords();
+                self.warn_if_open();
+
+                MessageResult(Ok(self.users.clone()))
+            }
+        }
+    }
+}
+
+impl Handler<SessionMessage> for UserService {
+    type Result = MessageResult<SessionMessage>;
+
+    fn handle(&mut self, msg: SessionMessage, _: &mut Self::Context) -> Self::Result {
+        match msg {
+            SessionMessage::Login(name, password) => {
+                if !matches!(self.authenticate(Credentials::Basic(name.clone(), password)), Authentication::User(_)) {
+                    return MessageResult(Err(ServiceError::Unauthorized));
+                }
+
+                let (_, value) = self.signer.create(&name, self.session_lifetime);
+
+                MessageResult(Ok(value))
+            }
+            SessionMessage::Logout(value) => {
+                let now = now();
+                self.revoked.retain(|_, expires| *expires > now);
+
+                if let Some(session) = self.signer.verify(&value) {
+                    self.revoked.insert(session.id, session.expires);
+                }
+
+                MessageResult(Ok(String::new()))
+            }
+            SessionMessage::CreateToken(name, token_name) => {
+                let mut user = match self.users.get(&name) {
+                    Some(user) => user.clone(),
+                    None => return MessageResult(Err(ServiceError::not_found("user", name))),
+                };
+                let token = generate_token();
+
+                user.tokens.insert(token_name, token_hash(&token));
+
+                MessageResult(self.store(name, user).map(|_| token))
+            }
+            SessionMessage::DeleteToken(name, token_name) => {
+                let mut user = match self.users.get(&name) {
+                    Some(user) => user.clone(),
+                    None => return MessageResult(Err(ServiceError::not_found("user", name))),
+                };
+
+                if user.tokens.remove(&token_name).is_none() {
+                    return MessageResult(Err(ServiceError::not_found("token", token_name)));
+                }
+
+                MessageResult(self.store(name, user).map(|_| token_name))
+            }
+        }
+    }
+}
+
+impl Handler<Authenticate> for UserService {
+    type Result = MessageResult<Authenticate>;
+
+    fn handle(&mut self, msg: Authenticate, _: &mut Self::Context) -> Self::Result {
+        MessageResult(self.authenticate(msg.0))
+    }
+}
+
+impl Message for UserMessage {
+    type Result = Result<HashMap<String, UserData>, ServiceError>;
+}
+
+impl Message for SessionMessage {
+    type Result = Result<String, ServiceError>;
+}
+
+impl Message for Authenticate {
+    type Result = Authentication;
+}
+
+impl UserData {
+    pub fn new(password: String, role: Role) -> Self {
+        Self {
+            password,
+            role,
+            tokens: HashMap::new(),
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Replaces the role, keeping everything else
+    pub fn with_role(mut self, role: Role) -> Self {
+        self.role = role;
+
+        self
+    }
+
+    /// Replaces the password, keeping the API tokens
+    pub fn with_password(mut self, password: String) -> Self {
+        self.password = password;
+
+        self
+    }
+
+    /// The names of all API tokens
+    pub fn token_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.tokens.keys().cloned().collect();
+        names.sort();
+
+        names
+    }
+
+    /// Replaces a plain text password with its hash. Returns true if the password was changed.
+    fn hash_password(&mut self) -> bool {
+        if self.password.is_empty() || is_password_hash(&self.password) {
+            return false;
+        }
+
+        self.password = hash_password(&self.password);
+
+        true
+    }
+}
+
+impl DataReadWrite for HashMap<String, UserData> {
+    fn load(storage: &dyn Storage) -> Self {
+        load_map(storage, &USERS)
+    }
+
+    fn single(&self, which: String) -> Self {
+        if let Some(data) = self.get(&which) {
+            let mut map = HashMap::new();
+
+            map.insert(which, data.clone());
+
+            return map;
+        }
+
+        HashMap::new()
+    }
+}
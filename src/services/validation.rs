@@ -0,0 +1,368 @@
+//! Checks dashboards, groups, shortcuts and rules before they are stored.
+//!
+//! All problems of an entry are collected, so the API can answer them at once:
+//! `{"code": "invalid", ..., "details": {"errors": [{"field": "items[1].type", "message": "..."}]}}`
+//!
+//! Names and item types are only checked when they are new, so entries stored before the rules
+//! existed (or the settings were changed) can still be edited.
+
+use std::sync::OnceLock;
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::error::ServiceError;
+use crate::services::{DashboardData, GroupData, GroupItemData, PayloadCondition, RuleAction, RuleData, ShortcutData, ShortcutEntry, ShortcutTrigger};
+use crate::settings::ValidationSettings;
+
+/// The longest name of a dashboard, group, item or shortcut
+const MAX_NAME_LENGTH: usize = 64;
+
+/// Characters allowed in names besides letters and digits
+const NAME_SYMBOLS: &str = " -_.()";
+
+/// The longest topic MQTT allows (in bytes)
+const MAX_TOPIC_LENGTH: usize = 65535;
+
+/// The validation settings, set once on startup
+static VALIDATION: OnceLock<ValidationSettings> = OnceLock::new();
+
+pub fn configure_validation(settings: ValidationSettings) {
+    if VALIDATION.set(settings).is_err() {
+        eprintln!("[WARN] [Validation]: The validation is already configured");
+    }
+}
+
+fn validation_settings() -> &'static ValidationSettings {
+    VALIDATION.get_or_init(ValidationSettings::default)
+}
+
+/// An entry which is checked before it is stored under the name. `previous` is the entry
+/// currently stored under the name.
+pub trait Validate {
+    fn validate(&self, name: &str, previous: Option<&Self>) -> Result<(), ServiceError>;
+}
+
+/// A single problem of an entry
+#[derive(Serialize)]
+struct Problem {
+    field: String,
+    message: String,
+}
+
+/// Collects the problems of an entry
+#[derive(Default)]
+struct Problems(Vec<Problem>);
+
+impl Problems {
+    fn add(&mut self, field: impl ToString, message: impl ToString) {
+        self.0.push(Problem {
+            field: field.to_string(),
+            message: message.to_string(),
+        });
+    }
+
+    fn name(&mut self, field: impl ToString, name: &str) {
+        if let Some(message) = name_error(name) {
+            self.add(field, message);
+        }
+    }
+
+    fn into_result(self, kind: &str, name: &str) -> Result<(), ServiceError> {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+
+        Err(ServiceError::Invalid {
+            message: format!("The {} {} is invalid.", kind, name),
+            details: json!({ "errors": self.0 }),
+        })
+    }
+}
+
+fn name_error(name: &str) -> Option<String> {
+    if name.trim().is_empty() {
+        return Some(String::from("The name is empty."));
+    }
+
+    if name.trim().len() != name.len() {
+        return Some(String::from("The name starts or ends with whitespace."));
+    }
+
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Some(format!("The name is longer than {} characters.", MAX_NAME_LENGTH));
+    }
+
+    if let Some(character) = name
+        .chars()
+        .find(|character| !character.is_alphanumeric() && !NAME_SYMBOLS.contains(*character))
+    {
+        return Some(format!("The name contains '{}'. Only letters, digits and \"{}\" are allowed.", character, NAME_SYMBOLS));
+    }
+
+    None
+}
+
+/// Checks a topic messages are published to
+fn publish_topic_error(topic: &str) -> Option<String> {
+    if let Some(error) = topic_error(topic) {
+        return Some(error);
+    }
+
+    if topic.contains(['+', '#']) {
+        return Some(String::from("Messages can not be published to topics with wildcards (+ or #)."));
+    }
+
+    None
+}
+
+/// Checks a topic filter which is subscribed to
+fn filter_error(filter: &str) -> Option<String> {
+    if let Some(error) = topic_error(filter) {
+        return Some(error);
+    }
+
+    let levels: Vec<&str> = filter.split('/').collect();
+
+    for (index, level) in levels.iter().enumerate() {
+        if level.contains('#') && (level.len() > 1 || index + 1 != levels.len()) {
+            return Some(String::from("The wildcard # has to be the whole last level of the topic."));
+        }
+
+        if level.contains('+') && level.len() > 1 {
+            return Some(String::from("The wildcard + has to be a whole level of the topic."));
+        }
+    }
+
+    None
+}
+
+/// Checks a JSON path like `$.state.items[0].name`
+fn json_path_error(path: &str) -> Option<String> {
+    let path = path.trim_start_matches('$').trim_start_matches('.');
+
+    if path.is_empty() {
+        return None;
+    }
+
+    for segment in path.split('.') {
+        let mut parts = segment.split('[');
+
+        if parts.next().is_some_and(str::is_empty) && !segment.starts_with('[') {
+            return Some(format!("The path {} contains an empty key.", path));
+        }
+
+        for index in parts {
+            if index.strip_suffix(']').and_then(|index| index.parse::<usize>().ok()).is_none() {
+                return Some(format!("The index [{} of the path is no number in brackets.", index));
+            }
+        }
+    }
+
+    None
+}
+
+fn topic_error(topic: &str) -> Option<String> {
+    if topic.is_empty() {
+        return Some(String::from("The topic is empty."));
+    }
+
+    if topic.len() > MAX_TOPIC_LENGTH {
+        return Some(format!("The topic is longer than {} bytes.", MAX_TOPIC_LENGTH));
+    }
+
+    if topic.contains('\0') {
+        return Some(String::from("The topic contains a null character."));
+    }
+
+    None
+}
+
+/// Checks that the entry stored under `name` keeps the name, or is renamed to a name which is not
+/// used by another entry. Only existing entries can be renamed.
+pub fn check_name(kind: &'static str, name: &str, new_name: &str, exists: impl Fn(&str) -> bool) -> Result<(), ServiceError> {
+    if name.eq(new_name) {
+        return Ok(());
+    }
+
+    if !exists(name) {
+        return Err(ServiceError::Invalid {
+            message: format!("The name {} does not match the {} {}.", new_name, kind, name),
+            details: json!({ "errors": [{ "field": "name", "message": "The name has to match the name in the path." }] }),
+        });
+    }
+
+    if exists(new_name) {
+        return Err(ServiceError::Invalid {
+            message: format!("The {} {} can not be renamed, {} already exists.", kind, name, new_name),
+            details: json!({ "errors": [{ "field": "name", "message": "There already is an entry with this name." }] }),
+        });
+    }
+
+    Ok(())
+}
+
+impl Validate for DashboardData {
+    fn validate(&self, name: &str, previous: Option<&Self>) -> Result<(), ServiceError> {
+        let mut problems = Problems::default();
+
+        if previous.is_none_or(|previous| previous.name.ne(&self.name)) {
+            problems.name("name", &self.name);
+        }
+
+        for (index, group) in self.groups.iter().enumerate() {
+            if self.groups[..index].contains(group) {
+                problems.add(format!("groups[{}]", index), format!("The group {} is contained twice.", group));
+            }
+        }
+
+        problems.into_result("dashboard", name)
+    }
+}
+
+impl Validate for GroupData {
+    fn validate(&self, name: &str, previous: Option<&Self>) -> Result<(), ServiceError> {
+        let settings = validation_settings();
+        let mut problems = Problems::default();
+
+        if previous.is_none_or(|previous| previous.name.ne(&self.name)) {
+            problems.name("name", &self.name);
+        }
+
+        if self.size < 1 || self.size > settings.max_group_size {
+            problems.add("size", format!("The size has to be between 1 and {}.", settings.max_group_size));
+        }
+
+        for (index, item) in self.items.iter().enumerate() {
+            let previous = previous.and_then(|previous| previous.items.iter().find(|other| other.name.eq(&item.name)));
+
+            item.check(&format!("items[{}]", index), previous, &mut problems);
+
+            if self.items[..index].iter().any(|other| other.name.eq(&item.name)) {
+                problems.add(format!("items[{}].name", index), format!("The item {} is contained twice.", item.name));
+            }
+        }
+
+        problems.into_result("group", name)
+    }
+}
+
+impl GroupItemData {
+    /// Checks the item. `previous` is the item with the same name in the stored group.
+    fn check(&self, field: &str, previous: Option<&Self>, problems: &mut Problems) {
+        let item_types = &validation_settings().item_types;
+
+        if previous.is_none() {
+            problems.name(format!("{}.name", field), &self.name);
+        }
+
+        let known_type = previous.is_some_and(|previous| previous.item_type.eq(&self.item_type));
+
+        if !known_type && !item_types.is_empty() && !item_types.contains(&self.item_type) {
+            problems.add(
+                format!("{}.type", field),
+                format!("The type {} is unknown. Known types are {}.", self.item_type, item_types.join(", ")),
+            );
+        }
+    }
+}
+
+impl Validate for ShortcutEntry {
+    fn validate(&self, name: &str, previous: Option<&Self>) -> Result<(), ServiceError> {
+        let mut problems = Problems::default();
+
+        if previous.is_none() {
+            problems.name("name", name);
+        }
+
+        for (index, step) in self.steps.iter().enumerate() {
+            step.check(&format!("steps[{}]", index), &mut problems);
+        }
+
+        for (index, trigger) in self.triggers.iter().enumerate() {
+            trigger.check(&format!("triggers[{}]", index), &mut problems);
+        }
+
+        problems.into_result("shortcut", name)
+    }
+}
+
+impl ShortcutData {
+    fn check(&self, field: &str, problems: &mut Problems) {
+        if let Some(message) = publish_topic_error(&self.topic) {
+            problems.add(format!("{}.topic", field), message);
+        }
+
+        let has_qos = self.options.get("qos").is_some_and(|qos| !qos.is_null());
+
+        if has_qos && self.qos().is_none_or(|qos| qos > 2) {
+            problems.add(format!("{}.options.qos", field), "The QoS has to be 0, 1 or 2.");
+        }
+    }
+}
+
+impl ShortcutTrigger {
+    fn check(&self, field: &str, problems: &mut Problems) {
+        if let Some(message) = filter_error(&self.topic) {
+            problems.add(format!("{}.topic", field), message);
+        }
+
+        for (index, condition) in self.conditions.iter().enumerate() {
+            condition.check(&format!("{}.conditions[{}]", field, index), problems);
+        }
+    }
+}
+
+impl Validate for RuleData {
+    fn validate(&self, name: &str, previous: Option<&Self>) -> Result<(), ServiceError> {
+        let mut problems = Problems::default();
+
+        if previous.is_none() {
+            problems.name("name", name);
+        }
+
+        if let Some(message) = filter_error(&self.topic) {
+            problems.add("topic", message);
+        }
+
+        for (index, condition) in self.conditions.iter().enumerate() {
+            condition.check(&format!("conditions[{}]", index), &mut problems);
+        }
+
+        for (index, action) in self.actions.iter().enumerate() {
+            action.check(&format!("actions[{}]", index), &mut problems);
+        }
+
+        problems.into_result("rule", name)
+    }
+}
+
+impl PayloadCondition {
+    fn check(&self, field: &str, problems: &mut Problems) {
+        match self {
+            PayloadCondition::Equals { .. } => {}
+            PayloadCondition::Regex { pattern } => {
+                if let Some(error) = pattern.error() {
+                    problems.add(format!("{}.pattern", field), format!("The regular expression is invalid: {}", error));
+                }
+            }
+            PayloadCondition::Json { path, .. } => {
+                if let Some(message) = json_path_error(path) {
+                    problems.add(format!("{}.path", field), message);
+                }
+            }
+        }
+    }
+}
+
+impl RuleAction {
+    fn check(&self, field: &str, problems: &mut Problems) {
+        if let Some(message) = publish_topic_error(&self.topic) {
+            problems.add(format!("{}.topic", field), message);
+        }
+
+        if self.qos > 2 {
+            problems.add(format!("{}.qos", field), "The QoS has to be 0, 1 or 2.");
+        }
+    }
+}
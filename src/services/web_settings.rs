@@ -1,33 +1,65 @@
 //! Here are all web-settings related structs implemented.
 
-use std::env;
-use std::fs::{File, OpenOptions};
+use std::path::PathBuf;
 
 use actix::{Actor, Context, Handler, Message, MessageResult};
+use serde_json::Value;
 
+use crate::auth::password::{hash_password, is_password_hash};
+use crate::paths::config_path;
+use crate::persistence::{load_yaml, save_yaml};
 use crate::services::{WebSettingsCompiledMessage, WebSettingsMessage, WebSettingsService};
 use crate::settings::WebSettings;
 
 impl WebSettingsService {
-    pub fn get_settings_file() -> std::io::Result<File> {
-        let cwd = env::current_dir().unwrap();
-        OpenOptions::new()
-            .read(true)
-            .open(cwd.join("web_settings.yaml"))
+    pub fn settings_path() -> PathBuf {
+        config_path("web_settings.yaml")
     }
 
     pub fn new() -> Self {
         WebSettingsService {
-            settings: match Self::get_settings_file() {
-                Ok(file) => WebSettings::from(file),
-                _ => serde_yaml::from_str::<WebSettings>("{}").unwrap(),
-            },
+            settings: Self::load_settings(),
             compiled_settings: String::new(),
         }
     }
 
+    /// Loads the settings from disk. A plain text `backend_pass` is replaced by its hash and
+    /// written back, so the password is not kept readable in the file.
+    fn load_settings() -> WebSettings {
+        let mut settings: WebSettings = load_yaml(&Self::settings_path(), "Web Settings");
+
+        if !settings.backend_pass.is_empty() && !is_password_hash(&settings.backend_pass) {
+            settings.backend_pass = hash_password(&settings.backend_pass);
+            Self::save_settings(&settings);
+        }
+
+        settings
+    }
+
+    fn save_settings(settings: &WebSettings) {
+        if let Err(error) = save_yaml(&Self::settings_path(), settings) {
+            eprintln!("[ERROR] [Web Settings]: Could not write settings");
+            eprintln!("{}", error);
+        }
+    }
+
+    /// Compiles the settings for the frontend. As the `settings.js` is publicly served, the
+    /// backend password is not part of it.
     fn compile_settings(&mut self) {
-        match serde_json::to_string(&self.settings) {
+        let mut settings = match serde_json::to_value(&self.settings) {
+            Ok(settings) => settings,
+            Err(error) => {
+                eprintln!("Settings compile error: {:?}", error);
+
+                return;
+            }
+        };
+
+        if let Value::Object(settings) = &mut settings {
+            settings.remove("settings.backend_pass");
+        }
+
+        match serde_json::to_string(&settings) {
             Ok(value) => {
                 self.compiled_settings = format!("export default {}", value);
             }
@@ -48,10 +80,7 @@ impl Handler<WebSettingsMessage> for WebSettingsService {
     fn handle(&mut self, msg: WebSettingsMessage, _ctx: &mut Self::Context) -> Self::Result {
         match msg {
             WebSettingsMessage::Reload => {
-                self.settings = match Self::get_settings_file() {
-                    Ok(file) => WebSettings::from(file),
-                    _ => serde_yaml::from_str::<WebSettings>("{}").unwrap(),
-                };
+                self.settings = Self::load_settings();
             }
             _ => {}
         }
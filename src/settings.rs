@@ -1,9 +1,9 @@
-use std::env::current_dir;
-use std::fs::{File, OpenOptions};
-use std::io::Write;
-
 use serde::{Deserialize, Serialize};
 
+use crate::auth::session::random_string;
+use crate::paths::config_path;
+use crate::persistence::{load_yaml, save_yaml};
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct WebSettings {
     #[serde(
@@ -41,6 +41,189 @@ pub struct AppSettings {
 
     #[serde(default)]
     pub server_type: ServerType,
+
+    #[serde(default)]
+    pub static_files: StaticSettings,
+
+    #[serde(default)]
+    pub mqtt: MqttSettings,
+
+    #[serde(default)]
+    pub broker: BrokerSettings,
+
+    #[serde(default)]
+    pub auth: AuthSettings,
+
+    #[serde(default)]
+    pub backup: BackupSettings,
+
+    #[serde(default)]
+    pub history: HistorySettings,
+
+    #[serde(default)]
+    pub storage: StorageSettings,
+
+    #[serde(default)]
+    pub validation: ValidationSettings,
+}
+
+/// Connection settings for the MQTT broker the server publishes to and subscribes on
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MqttSettings {
+    #[serde(default = "MqttSettings::default_enabled")]
+    pub enabled: bool,
+
+    #[serde(default = "MqttSettings::default_host")]
+    pub host: String,
+
+    #[serde(default = "MqttSettings::default_port")]
+    pub port: u16,
+
+    #[serde(default = "MqttSettings::default_client_id")]
+    pub client_id: String,
+
+    #[serde(default)]
+    pub username: String,
+
+    #[serde(default)]
+    pub password: String,
+
+    /// Keep alive in seconds
+    #[serde(default = "MqttSettings::default_keep_alive")]
+    pub keep_alive: u16,
+
+    /// Seconds to wait before reconnecting after the connection was lost
+    #[serde(default = "MqttSettings::default_reconnect_interval")]
+    pub reconnect_interval: u64,
+}
+
+/// Settings for the embedded MQTT broker. Listeners set to `~` are disabled.
+/// If a username is set, clients have to authenticate with it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BrokerSettings {
+    #[serde(default)]
+    pub enabled: bool,
+
+    #[serde(default = "BrokerSettings::default_tcp_bind")]
+    pub tcp_bind: Option<String>,
+
+    #[serde(default = "BrokerSettings::default_websocket_bind")]
+    pub websocket_bind: Option<String>,
+
+    #[serde(default)]
+    pub username: String,
+
+    #[serde(default)]
+    pub password: String,
+
+    /// The largest packet (in bytes) a client may send. Clients sending larger ones are
+    /// disconnected.
+    #[serde(default = "BrokerSettings::default_max_packet_size")]
+    pub max_packet_size: usize,
+}
+
+/// Settings for the sessions of the users. The secret is generated on the first start.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuthSettings {
+    #[serde(default = "AuthSettings::default_session_secret")]
+    pub session_secret: String,
+
+    /// Seconds until a session expires
+    #[serde(default = "AuthSettings::default_session_lifetime")]
+    pub session_lifetime: u64,
+
+    /// Origins (like `https://home.example.com`) of frontends on other hosts which may use the API
+    /// with the credentials of the user
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+}
+
+/// Settings for the snapshots taken before a data file is changed
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BackupSettings {
+    /// The directory the snapshots are stored in (relative to the data directory)
+    #[serde(default = "BackupSettings::default_directory")]
+    pub directory: String,
+
+    /// How many snapshots are kept per file. `0` disables the backups.
+    #[serde(default = "BackupSettings::default_keep")]
+    pub keep: usize,
+}
+
+/// How much of the history of changes is kept
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistorySettings {
+    /// How many changes are kept. `0` keeps all of them.
+    #[serde(default = "HistorySettings::default_keep")]
+    pub keep: usize,
+
+    /// Seconds after which a change is dropped. `0` keeps them regardless of their age.
+    #[serde(default)]
+    pub max_age: u64,
+}
+
+/// Where the dashboards, groups, shortcuts, rules and users are stored
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StorageSettings {
+    #[serde(default)]
+    pub backend: StorageBackend,
+
+    /// The database file (relative to the data directory), used by the `sqlite` backend
+    #[serde(default = "StorageSettings::default_sqlite_path")]
+    pub sqlite_path: String,
+}
+
+/// The limits for dashboards, groups and shortcuts changed through the API
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ValidationSettings {
+    /// The item types the frontend knows. Empty (the default) allows every type.
+    #[serde(default)]
+    pub item_types: Vec<String>,
+
+    /// The largest size of a group (in grid columns)
+    #[serde(default = "ValidationSettings::default_max_group_size")]
+    pub max_group_size: i32,
+}
+
+/// How the frontend is served from the public directory (`server_type: File`)
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StaticSettings {
+    /// Answers unknown pages outside of `/api/` with the `index.html`, so the routes of the
+    /// frontend can be opened directly
+    #[serde(default = "StaticSettings::default_spa_fallback")]
+    pub spa_fallback: bool,
+
+    /// Seconds browsers may use files without asking again. 0 lets them check every time
+    /// (`no-cache`), which is always done for `index.html`.
+    #[serde(default)]
+    pub max_age: u64,
+
+    /// Files with a name matching this regex contain a hash of their content (`app.3f2a9c1b.js`),
+    /// so they never change and are kept for `hashed_max_age` seconds
+    #[serde(default = "StaticSettings::default_hashed_names")]
+    pub hashed_names: String,
+
+    #[serde(default = "StaticSettings::default_hashed_max_age")]
+    pub hashed_max_age: u64,
+
+    /// Files up to this size (in bytes) are kept in memory. 0 disables the cache.
+    #[serde(default)]
+    pub memory_cache_file_size: u64,
+
+    /// The most bytes kept in memory
+    #[serde(default = "StaticSettings::default_memory_cache_size")]
+    pub memory_cache_size: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    /// One YAML file per collection (`dashboard.yaml`, `group.yaml`, ...)
+    #[default]
+    Yaml,
+
+    /// A single SQLite database
+    Sqlite,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -59,20 +242,6 @@ impl WebSettings {
     }
 }
 
-impl From<File> for WebSettings {
-    fn from(file: File) -> Self {
-        match serde_yaml::from_reader(file) {
-            Ok(settings) => settings,
-            Err(error) => {
-                eprintln!("[ERROR] [Web Settings]: Could not build settings from file");
-                eprintln!("{}", error);
-
-                WebSettings::default()
-            }
-        }
-    }
-}
-
 impl Default for WebSettings {
     fn default() -> Self {
         serde_yaml::from_str("{}").unwrap()
@@ -89,53 +258,17 @@ impl AppSettings {
     }
 
     pub fn load() -> Self {
-        let shortcuts_path = current_dir().unwrap().join("settings.yaml");
-        let file = OpenOptions::new().read(true).open(shortcuts_path);
-
-        match file {
-            Ok(file) => match serde_yaml::from_reader(file) {
-                Ok(settings) => settings,
-                Err(error) => {
-                    eprintln!("[ERROR] [App Settings]: Could not parse file");
-                    eprintln!("{}", error);
-
-                    Default::default()
-                }
-            },
-            Err(error) => {
-                eprintln!("[WARN] [App Settings]: Could not open file");
-                eprintln!("{}", error);
-
-                Default::default()
-            }
-        }
+        let settings_path = config_path("settings.yaml");
+
+        load_yaml(&settings_path, "App Settings")
     }
 
     pub fn save(&self) {
-        let shortcuts_path = current_dir().unwrap().join("settings.yaml");
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(shortcuts_path);
-
-        match file {
-            Ok(mut file) => {
-                if let Err(error) = serde_yaml::to_writer(&file, &self) {
-                    eprintln!("[ERROR] [App Settings]: Could not write shortcuts");
-                    eprintln!("{}", error);
-
-                    return;
-                }
-
-                if let Err(error) = file.flush() {
-                    eprintln!("[WARN] [App Settings]: Could not fully write shortcuts");
-                    eprintln!("{}", error);
-                }
-            }
-            Err(error) => {
-                eprintln!("[ERROR] [App Settings]: Could not open/create file");
-                eprintln!("{}", error);
-            }
+        let settings_path = config_path("settings.yaml");
+
+        if let Err(error) = save_yaml(&settings_path, self) {
+            eprintln!("[ERROR] [App Settings]: Could not write settings");
+            eprintln!("{}", error);
         }
     }
 }
@@ -146,8 +279,152 @@ impl Default for AppSettings {
     }
 }
 
+impl MqttSettings {
+    pub fn default_enabled() -> bool {
+        true
+    }
+
+    pub fn default_host() -> String {
+        String::from("127.0.0.1")
+    }
+
+    pub fn default_port() -> u16 {
+        1883
+    }
+
+    pub fn default_client_id() -> String {
+        String::from("new-home-mqtt-server")
+    }
+
+    pub fn default_keep_alive() -> u16 {
+        30
+    }
+
+    pub fn default_reconnect_interval() -> u64 {
+        5
+    }
+}
+
+impl Default for MqttSettings {
+    fn default() -> Self {
+        serde_yaml::from_str("{}").unwrap()
+    }
+}
+
+impl BrokerSettings {
+    pub fn default_tcp_bind() -> Option<String> {
+        Some(String::from("0.0.0.0:1883"))
+    }
+
+    pub fn default_websocket_bind() -> Option<String> {
+        Some(String::from("0.0.0.0:9001"))
+    }
+
+    pub fn default_max_packet_size() -> usize {
+        1024 * 1024
+    }
+}
+
+impl Default for BrokerSettings {
+    fn default() -> Self {
+        serde_yaml::from_str("{}").unwrap()
+    }
+}
+
+impl BackupSettings {
+    pub fn default_directory() -> String {
+        String::from("backups")
+    }
+
+    pub fn default_keep() -> usize {
+        10
+    }
+}
+
+impl Default for BackupSettings {
+    fn default() -> Self {
+        serde_yaml::from_str("{}").unwrap()
+    }
+}
+
+impl HistorySettings {
+    pub fn default_keep() -> usize {
+        1000
+    }
+}
+
+impl Default for HistorySettings {
+    fn default() -> Self {
+        serde_yaml::from_str("{}").unwrap()
+    }
+}
+
+impl StorageSettings {
+    pub fn default_sqlite_path() -> String {
+        String::from("new-home.sqlite")
+    }
+}
+
+impl Default for StorageSettings {
+    fn default() -> Self {
+        serde_yaml::from_str("{}").unwrap()
+    }
+}
+
+impl ValidationSettings {
+    pub fn default_max_group_size() -> i32 {
+        12
+    }
+}
+
+impl Default for ValidationSettings {
+    fn default() -> Self {
+        serde_yaml::from_str("{}").unwrap()
+    }
+}
+
+impl StaticSettings {
+    pub fn default_spa_fallback() -> bool {
+        true
+    }
+
+    pub fn default_hashed_names() -> String {
+        String::from(r"[.-][0-9a-fA-F]{8,}\.[^.]+$")
+    }
+
+    pub fn default_hashed_max_age() -> u64 {
+        60 * 60 * 24 * 365
+    }
+
+    pub fn default_memory_cache_size() -> u64 {
+        16 * 1024 * 1024
+    }
+}
+
+impl Default for StaticSettings {
+    fn default() -> Self {
+        serde_yaml::from_str("{}").unwrap()
+    }
+}
+
 impl Default for ServerType {
     fn default() -> Self {
         ServerType::File(String::from("public"))
     }
 }
+
+impl AuthSettings {
+    pub fn default_session_secret() -> String {
+        random_string(32)
+    }
+
+    pub fn default_session_lifetime() -> u64 {
+        60 * 60 * 24 * 30
+    }
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        serde_yaml::from_str("{}").unwrap()
+    }
+}
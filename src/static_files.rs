@@ -0,0 +1,670 @@
+//! Serves the frontend from the public directory (`server_type: File`)
+//!
+//! The request path is percent-decoded and resolved inside the public directory. Paths leaving it
+//! (`/../settings.yaml`, also through symlinks) are answered like missing files. Directories are
+//! answered with their `index.html` and unknown pages of the frontend with the `index.html` of
+//! the public directory (see [StaticSettings::spa_fallback]).
+//!
+//! Files are sent with `ETag`, `Last-Modified` and a `Cache-Control` depending on their name, and
+//! conditional requests are answered with `304 Not Modified`. Small files can be kept in memory.
+//!
+//! If the client accepts it, the `.br` or `.gz` file next to the requested one is sent instead.
+//! Other files are compressed while sending, if their type is compressible. Their ETag is weak
+//! then, as the compressed bytes are not always the same.
+//!
+//! Files larger than the memory cache allows are streamed. Single byte ranges (`Range`) are
+//! answered with `206 Partial Content`, so media can be played from any position.
+
+use std::collections::HashMap;
+use std::fs::{File, Metadata};
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::body::{Body, SizedStream};
+use actix_web::dev::BodyEncoding;
+use actix_web::http::header::{ContentEncoding, HttpDate, ACCEPT, ACCEPT_ENCODING, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_RANGE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE};
+use actix_web::http::{HeaderMap, Method, StatusCode};
+use actix_web::web::{self, Bytes, Data};
+use actix_web::{Error, HttpRequest, HttpResponse};
+use futures::Stream;
+use regex::Regex;
+
+use crate::mime_type_mapper::MimeTypeMapper;
+use crate::settings::StaticSettings;
+
+const INDEX_FILE: &str = "index.html";
+
+/// Large files are read and sent in chunks of this size
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Smaller responses are sent uncompressed, as compressing them saves (almost) nothing
+pub const MIN_COMPRESS_SIZE: u64 = 1024;
+
+/// The settings and the memory cache of the static files, shared by all workers
+pub struct StaticFiles {
+    settings: StaticSettings,
+    hashed_names: Option<Regex>,
+    cache: Mutex<FileCache>,
+}
+
+/// The small files kept in memory. An entry is only used while the file has the same
+/// modification time and size.
+#[derive(Default)]
+struct FileCache {
+    files: HashMap<PathBuf, (SystemTime, Bytes)>,
+    size: u64,
+}
+
+impl StaticFiles {
+    pub fn new(settings: StaticSettings) -> Self {
+        let hashed_names = match Regex::new(&settings.hashed_names) {
+            Ok(regex) => Some(regex),
+            Err(error) => {
+                eprintln!("[ERROR] [WebServer]: Invalid regex for hashed file names {}", settings.hashed_names);
+                eprintln!("{}", error);
+
+                None
+            }
+        };
+
+        Self {
+            settings,
+            hashed_names,
+            cache: Mutex::new(FileCache::default()),
+        }
+    }
+
+    /// The `Cache-Control` of the file
+    fn cache_control(&self, file: &Path) -> String {
+        let name = file.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+
+        if name.eq(INDEX_FILE) {
+            return String::from("no-cache");
+        }
+
+        if self.hashed_names.as_ref().is_some_and(|regex| regex.is_match(&name)) {
+            return format!("public, max-age={}, immutable", self.settings.hashed_max_age);
+        }
+
+        match self.settings.max_age {
+            0 => String::from("no-cache"),
+            max_age => format!("public, max-age={}", max_age),
+        }
+    }
+
+    /// The content of a small file, from memory if it is unchanged since it was read. None if the
+    /// file is too large for the cache, so it has to be streamed.
+    fn cached(&self, file: &Path, metadata: &Metadata) -> Option<std::io::Result<Bytes>> {
+        if metadata.len() > self.settings.memory_cache_file_size {
+            return None;
+        }
+
+        let modified = match metadata.modified() {
+            Ok(modified) => modified,
+            Err(error) => return Some(Err(error)),
+        };
+
+        if let Some((cached_modified, content)) = self.lock().files.get(file) {
+            if cached_modified.eq(&modified) && content.len() as u64 == metadata.len() {
+                return Some(Ok(content.clone()));
+            }
+        }
+
+        let content = match std::fs::read(file) {
+            Ok(content) => Bytes::from(content),
+            Err(error) => return Some(Err(error)),
+        };
+        let mut cache = self.lock();
+        let replaced = cache.files.remove(file).map(|(_, content)| content.len() as u64).unwrap_or(0);
+
+        cache.size -= replaced;
+
+        if cache.size + content.len() as u64 <= self.settings.memory_cache_size {
+            cache.size += content.len() as u64;
+            cache.files.insert(file.to_path_buf(), (modified, content.clone()));
+        }
+
+        Some(Ok(content))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, FileCache> {
+        // The cache stays usable even if a thread panicked while holding it
+        self.cache.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+pub async fn serve(
+    request: HttpRequest,
+    public_path: PathBuf,
+    mime_type_mapper: Data<MimeTypeMapper>,
+    static_files: Data<StaticFiles>,
+) -> Result<HttpResponse, Error> {
+    let path = percent_encoding::percent_decode_str(request.path()).decode_utf8_lossy().to_string();
+    let file = match resolve(&public_path, &path) {
+        Some(file) => Some(file),
+        None if static_files.settings.spa_fallback && is_page(&request, &path) => resolve(&public_path, INDEX_FILE),
+        None => None,
+    };
+    let (file, metadata) = match file.map(|file| (file.metadata(), file)) {
+        Some((Ok(metadata), file)) => (file, metadata),
+        _ => return Ok(HttpResponse::NotFound().body("File not found.")),
+    };
+    let mime_type = mime_type_mapper.match_file(file.to_string_lossy());
+    let cache_control = static_files.cache_control(&file);
+    // Ranges are always of the uncompressed file
+    let compressed = if request.headers().contains_key(RANGE) {
+        None
+    } else {
+        precompressed(&request, &file)
+    };
+    let (encoding, file, metadata) = match compressed {
+        Some((encoding, compressed, compressed_metadata)) => (Some(encoding), compressed, compressed_metadata),
+        None => (None, file, metadata),
+    };
+    let modified = metadata.modified().ok().map(whole_seconds);
+    let tag = etag(&metadata, modified);
+    let size = metadata.len();
+    let range = byte_range(&request, &tag, modified, size);
+    let on_the_fly = match (&encoding, &range) {
+        (None, ByteRange::Full) if size >= MIN_COMPRESS_SIZE && MimeTypeMapper::is_compressible(&mime_type) => {
+            preferred_encoding(request.headers())
+        }
+        _ => ContentEncoding::Identity,
+    };
+    let mut response = HttpResponse::Ok();
+
+    if on_the_fly == ContentEncoding::Identity {
+        response.header(ETAG, tag.as_str());
+    } else {
+        response.encoding(on_the_fly).header(ETAG, format!("W/{}", tag));
+    }
+
+    response.header(CACHE_CONTROL, cache_control);
+    response.header(ACCEPT_RANGES, "bytes");
+
+    if let Some(encoding) = encoding {
+        response.header(CONTENT_ENCODING, encoding.as_str());
+    }
+
+    if let Some(modified) = modified {
+        response.header(LAST_MODIFIED, HttpDate::from(modified));
+    }
+
+    if !is_modified(&request, &tag, modified) {
+        return Ok(response.status(StatusCode::NOT_MODIFIED).finish());
+    }
+
+    let (start, length) = match range {
+        ByteRange::Full => (0, size),
+        ByteRange::Part(start, length) => {
+            // The range is of the bytes in the file, so they must not be compressed
+            response.status(StatusCode::PARTIAL_CONTENT).encoding(ContentEncoding::Identity);
+            response.header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, start + length - 1, size));
+
+            (start, length)
+        }
+        ByteRange::Unsatisfiable => {
+            return Ok(response
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(CONTENT_RANGE, format!("bytes */{}", size))
+                .finish());
+        }
+    };
+
+    response.header("Content-Type", mime_type);
+
+    // Only the headers of HEAD requests are sent, so the file is not read
+    if request.method() == Method::HEAD {
+        return Ok(response.body(Body::from_message(SizedStream::new(length, futures::stream::empty()))));
+    }
+
+    let body = match static_files.cached(&file, &metadata) {
+        Some(Ok(content)) if content.len() as u64 >= start + length => {
+            Ok(Body::Bytes(content.slice(start as usize..(start + length) as usize)))
+        }
+        // The file got shorter since its size was read
+        Some(Ok(_)) => {
+            eprintln!("[WARN] [WebServer]: File {} changed while it was read", file.to_string_lossy());
+
+            return Ok(HttpResponse::InternalServerError().body("File changed while it was read."));
+        }
+        Some(Err(error)) => Err(error),
+        None => File::open(&file).map(|file| Body::from_message(SizedStream::new(length, read_chunks(file, start, length)))),
+    };
+
+    match body {
+        Ok(body) => Ok(response.body(body)),
+        Err(error) => {
+            eprintln!("[ERROR] [WebServer]: Could not open file {}", file.to_string_lossy());
+            eprintln!("{}", error);
+
+            Ok(HttpResponse::NotFound().body("File not found."))
+        }
+    }
+}
+
+/// Reads the part of the file in chunks on the thread pool, so large files are never completely in
+/// memory
+fn read_chunks(file: File, start: u64, length: u64) -> Pin<Box<dyn Stream<Item = Result<Bytes, Error>>>> {
+    Box::pin(futures::stream::try_unfold((file, start, length), |(file, position, remaining)| async move {
+        if remaining == 0 {
+            return Ok(None);
+        }
+
+        let size = remaining.min(CHUNK_SIZE);
+        let (file, chunk) = web::block(move || {
+            let mut file = file;
+            let mut chunk = Vec::with_capacity(size as usize);
+
+            file.seek(SeekFrom::Start(position))?;
+            (&mut file).take(size).read_to_end(&mut chunk)?;
+
+            // The file got shorter since it was opened
+            if chunk.is_empty() {
+                return Err(std::io::Error::from(ErrorKind::UnexpectedEof));
+            }
+
+            Ok((file, chunk))
+        })
+        .await
+        .map_err(Error::from)?;
+        let read = chunk.len() as u64;
+
+        Ok(Some((Bytes::from(chunk), (file, position + read, remaining - read))))
+    }))
+}
+
+/// The part of the file a request asks for
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    /// No (or an outdated) range, or more than one. The whole file is sent.
+    Full,
+
+    /// The start and the length of the range
+    Part(u64, u64),
+
+    /// The range starts behind the end of the file
+    Unsatisfiable,
+}
+
+/// Reads the `Range` header. Only single byte ranges (`bytes=0-99`, `bytes=100-`, `bytes=-100`)
+/// are supported. With `If-Range` the range is only used while the file has the given ETag or
+/// modification time.
+fn byte_range(request: &HttpRequest, tag: &str, modified: Option<SystemTime>, size: u64) -> ByteRange {
+    let header = |name| request.headers().get(name).and_then(|value| value.to_str().ok());
+    let range = match header(RANGE).and_then(|range| range.trim().strip_prefix("bytes=")) {
+        Some(range) if !range.contains(',') => range,
+        _ => return ByteRange::Full,
+    };
+
+    if let Some(if_range) = header(IF_RANGE) {
+        let date = HttpDate::from_str(if_range).ok().map(SystemTime::from);
+
+        if !if_range.trim().eq(tag) && (date.is_none() || date != modified) {
+            return ByteRange::Full;
+        }
+    }
+
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (start.trim(), end.trim()),
+        None => return ByteRange::Full,
+    };
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.is_empty() => (start, size.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => (size.saturating_sub(suffix), size.saturating_sub(1)),
+        (Err(_), Ok(_)) if start.is_empty() => return ByteRange::Unsatisfiable,
+        _ => return ByteRange::Full,
+    };
+
+    if start >= size {
+        return ByteRange::Unsatisfiable;
+    }
+
+    ByteRange::Part(start, end - start + 1)
+}
+
+/// The file of the (decoded) request path. None if it does not exist or is outside of the public
+/// directory.
+fn resolve(public_path: &Path, path: &str) -> Option<PathBuf> {
+    let root = public_path.canonicalize().ok()?;
+    let mut file = root.clone();
+
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => continue,
+            ".." => return None,
+            segment => file.push(segment),
+        }
+    }
+
+    // Resolves symlinks, so they can not point outside either
+    let mut file = file.canonicalize().ok()?;
+
+    if file.is_dir() {
+        file = file.join(INDEX_FILE).canonicalize().ok()?;
+    }
+
+    if !file.starts_with(&root) || !file.is_file() {
+        return None;
+    }
+
+    Some(file)
+}
+
+/// The `.br` or `.gz` version of the file, if there is one and the client accepts it
+fn precompressed(request: &HttpRequest, file: &Path) -> Option<(ContentEncoding, PathBuf, Metadata)> {
+    [(ContentEncoding::Br, "br"), (ContentEncoding::Gzip, "gz")]
+        .iter()
+        .filter(|(encoding, _)| accepts_encoding(request.headers(), encoding.as_str()))
+        .find_map(|(encoding, extension)| {
+            let mut name = file.as_os_str().to_os_string();
+
+            name.push(".");
+            name.push(extension);
+
+            // It has to be next to the file, not a symlink to somewhere else
+            let compressed = PathBuf::from(name).canonicalize().ok()?;
+            let metadata = compressed.metadata().ok()?;
+
+            if compressed.parent() != file.parent() || !metadata.is_file() {
+                return None;
+            }
+
+            Some((*encoding, compressed, metadata))
+        })
+}
+
+/// The best encoding for compressing the response which the client accepts
+pub fn preferred_encoding(headers: &HeaderMap) -> ContentEncoding {
+    [ContentEncoding::Br, ContentEncoding::Gzip, ContentEncoding::Deflate]
+        .iter()
+        .find(|encoding| accepts_encoding(headers, encoding.as_str()))
+        .copied()
+        .unwrap_or(ContentEncoding::Identity)
+}
+
+/// Checks if the `Accept-Encoding` allows the encoding (directly or with `*`, without `q=0`)
+pub fn accepts_encoding(headers: &HeaderMap, encoding: &str) -> bool {
+    let accept_encoding = match headers.get(ACCEPT_ENCODING).and_then(|value| value.to_str().ok()) {
+        Some(accept_encoding) => accept_encoding,
+        None => return false,
+    };
+    let quality = |name: &str| {
+        accept_encoding.split(',').find_map(|candidate| {
+            let mut parts = candidate.split(';').map(str::trim);
+
+            if !parts.next()?.eq_ignore_ascii_case(name) {
+                return None;
+            }
+
+            Some(
+                parts
+                    .find_map(|part| part.strip_prefix("q="))
+                    .and_then(|quality| quality.parse::<f32>().ok())
+                    .unwrap_or(1.0),
+            )
+        })
+    };
+
+    quality(encoding).or_else(|| quality("*")).is_some_and(|quality| quality > 0.0)
+}
+
+/// Checks if the request is for a page of the frontend (and not for a missing asset or API
+/// endpoint). Browsers ask for pages with `Accept: text/html`, other clients are only answered
+/// with the page if the path has no file extension.
+fn is_page(request: &HttpRequest, path: &str) -> bool {
+    if path.starts_with("/api/") {
+        return false;
+    }
+
+    let accepts_html = request
+        .headers()
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("text/html"));
+    let has_extension = path.rsplit('/').next().is_some_and(|name| name.contains('.'));
+
+    accepts_html || !has_extension
+}
+
+/// HTTP dates have no fractions of seconds, so they are cut off before comparing
+fn whole_seconds(time: SystemTime) -> SystemTime {
+    let seconds = time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+
+    UNIX_EPOCH + Duration::from_secs(seconds)
+}
+
+/// The ETag of the file (size and modification time)
+fn etag(metadata: &Metadata, modified: Option<SystemTime>) -> String {
+    let modified = modified
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+
+    format!("\"{:x}-{:x}\"", metadata.len(), modified)
+}
+
+/// Checks the `If-None-Match` (or, without it, the `If-Modified-Since`) of the request
+fn is_modified(request: &HttpRequest, tag: &str, modified: Option<SystemTime>) -> bool {
+    let header = |name| request.headers().get(name).and_then(|value| value.to_str().ok());
+
+    if let Some(if_none_match) = header(IF_NONE_MATCH) {
+        return !if_none_match
+            .split(',')
+            .map(|candidate| candidate.trim().trim_start_matches("W/"))
+            .any(|candidate| candidate.eq("*") || candidate.eq(tag));
+    }
+
+    match (header(IF_MODIFIED_SINCE).and_then(|since| HttpDate::from_str(since).ok()), modified) {
+        (Some(since), Some(modified)) => modified > SystemTime::from(since),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    use actix_web::dev::MessageBody;
+    use actix_web::rt::System;
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    /// A public directory with a file outside of it:
+    ///
+    /// ```text
+    /// secret.txt
+    /// public/index.html
+    /// public/app.js
+    /// public/sub/index.html
+    /// ```
+    fn public_dir(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("new-home-static-{}-{}", std::process::id(), name));
+
+        remove_dir_all(&root).unwrap_or_default();
+        create_dir_all(root.join("public/sub")).unwrap();
+        write(root.join("secret.txt"), "secret").unwrap();
+        write(root.join("public/index.html"), "<html></html>").unwrap();
+        write(root.join("public/app.js"), "0123456789").unwrap();
+        write(root.join("public/sub/index.html"), "<html>sub</html>").unwrap();
+
+        root
+    }
+
+    #[test]
+    fn resolves_files_and_directories() {
+        let root = public_dir("resolve");
+        let public = root.join("public");
+        let canonical = |name: &str| public.join(name).canonicalize().ok();
+
+        assert_eq!(resolve(&public, "/app.js"), canonical("app.js"));
+        assert_eq!(resolve(&public, "//./app.js"), canonical("app.js"));
+        assert_eq!(resolve(&public, "/"), canonical("index.html"));
+        assert_eq!(resolve(&public, "/sub"), canonical("sub/index.html"));
+        assert_eq!(resolve(&public, "/sub/"), canonical("sub/index.html"));
+        assert_eq!(resolve(&public, "/missing.js"), None);
+
+        remove_dir_all(root).unwrap_or_default();
+    }
+
+    #[test]
+    fn rejects_paths_leaving_the_public_directory() {
+        let root = public_dir("traversal");
+        let public = root.join("public");
+
+        assert_eq!(resolve(&public, "/../secret.txt"), None);
+        assert_eq!(resolve(&public, "../secret.txt"), None);
+        assert_eq!(resolve(&public, "/sub/../../secret.txt"), None);
+        assert_eq!(resolve(&public, "/sub/../app.js"), None);
+        assert_eq!(resolve(&public, "/..%2fsecret.txt"), None);
+        assert_eq!(resolve(&public, &root.join("secret.txt").to_string_lossy()), None);
+
+        remove_dir_all(root).unwrap_or_default();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_symlinks_leaving_the_public_directory() {
+        use std::os::unix::fs::symlink;
+
+        let root = public_dir("symlink");
+        let public = root.join("public");
+
+        symlink(root.join("secret.txt"), public.join("secret.txt")).unwrap();
+        symlink(&root, public.join("parent")).unwrap();
+        symlink(public.join("app.js"), public.join("inner.js")).unwrap();
+
+        assert_eq!(resolve(&public, "/secret.txt"), None);
+        assert_eq!(resolve(&public, "/parent/secret.txt"), None);
+        assert_eq!(resolve(&public, "/parent/public/app.js"), public.join("app.js").canonicalize().ok());
+        assert_eq!(resolve(&public, "/inner.js"), public.join("app.js").canonicalize().ok());
+
+        remove_dir_all(root).unwrap_or_default();
+    }
+
+    fn range(range: &str, size: u64) -> ByteRange {
+        byte_range(&TestRequest::default().header(RANGE, range).to_http_request(), "\"tag\"", None, size)
+    }
+
+    #[test]
+    fn parses_byte_ranges() {
+        assert_eq!(range("bytes=0-99", 1000), ByteRange::Part(0, 100));
+        assert_eq!(range("bytes=100-", 1000), ByteRange::Part(100, 900));
+        assert_eq!(range("bytes=-100", 1000), ByteRange::Part(900, 100));
+        assert_eq!(range(" bytes= 5 - 5 ", 1000), ByteRange::Part(5, 1));
+        assert_eq!(range("bytes=999-999", 1000), ByteRange::Part(999, 1));
+    }
+
+    #[test]
+    fn limits_ranges_to_the_file() {
+        assert_eq!(range("bytes=500-5000", 1000), ByteRange::Part(500, 500));
+        assert_eq!(range("bytes=-5000", 1000), ByteRange::Part(0, 1000));
+    }
+
+    #[test]
+    fn ranges_behind_the_end_are_unsatisfiable() {
+        assert_eq!(range("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=1000-2000", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=-0", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=0-", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn sends_the_full_file_for_unsupported_ranges() {
+        let request = TestRequest::default().to_http_request();
+
+        assert_eq!(byte_range(&request, "\"tag\"", None, 1000), ByteRange::Full);
+        assert_eq!(range("bytes=0-1,5-6", 1000), ByteRange::Full);
+        assert_eq!(range("items=0-1", 1000), ByteRange::Full);
+        assert_eq!(range("bytes=5-1", 1000), ByteRange::Full);
+        assert_eq!(range("bytes=a-b", 1000), ByteRange::Full);
+        assert_eq!(range("bytes=", 1000), ByteRange::Full);
+    }
+
+    #[test]
+    fn uses_ranges_only_while_if_range_matches() {
+        let modified = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let request = |if_range: &str| {
+            TestRequest::default()
+                .header(RANGE, "bytes=0-9")
+                .header(IF_RANGE, if_range)
+                .to_http_request()
+        };
+        let date = HttpDate::from(modified).to_string();
+        let other_date = HttpDate::from(modified + Duration::from_secs(1)).to_string();
+
+        assert_eq!(byte_range(&request("\"tag\""), "\"tag\"", Some(modified), 100), ByteRange::Part(0, 10));
+        assert_eq!(byte_range(&request("\"old\""), "\"tag\"", Some(modified), 100), ByteRange::Full);
+        assert_eq!(byte_range(&request(&date), "\"tag\"", Some(modified), 100), ByteRange::Part(0, 10));
+        assert_eq!(byte_range(&request(&other_date), "\"tag\"", Some(modified), 100), ByteRange::Full);
+        assert_eq!(byte_range(&request(&date), "\"tag\"", None, 100), ByteRange::Full);
+    }
+
+    /// Serves the request from the public directory of [public_dir] and reads the whole body
+    fn serve_request(root: &Path, request: TestRequest, memory_cache_file_size: u64) -> (HttpResponse, Vec<u8>) {
+        let static_files = Data::new(StaticFiles::new(StaticSettings {
+            memory_cache_file_size,
+            ..StaticSettings::default()
+        }));
+        let request = request.to_http_request();
+        let public = root.join("public");
+
+        System::new("static-files-test").block_on(async move {
+            let mut response = serve(request, public, Data::new(MimeTypeMapper::default()), static_files)
+                .await
+                .unwrap();
+            let mut body = response.take_body();
+            let mut bytes = Vec::new();
+
+            while let Some(chunk) = futures::future::poll_fn(|cx| MessageBody::poll_next(Pin::new(&mut body), cx)).await {
+                bytes.extend_from_slice(&chunk.unwrap());
+            }
+
+            (response, bytes)
+        })
+    }
+
+    fn header<'a>(response: &'a HttpResponse, name: &str) -> Option<&'a str> {
+        response.headers().get(name).and_then(|value| value.to_str().ok())
+    }
+
+    #[test]
+    fn answers_ranges_with_partial_content() {
+        let root = public_dir("partial");
+
+        // From the memory cache and streamed from the file
+        for memory_cache_file_size in [100, 0] {
+            let request = TestRequest::with_uri("/app.js").header(RANGE, "bytes=2-4");
+            let (response, body) = serve_request(&root, request, memory_cache_file_size);
+
+            assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+            assert_eq!(header(&response, "content-range"), Some("bytes 2-4/10"));
+            assert_eq!(body, b"234");
+        }
+
+        remove_dir_all(root).unwrap_or_default();
+    }
+
+    #[test]
+    fn answers_unsatisfiable_ranges_with_416() {
+        let root = public_dir("unsatisfiable");
+        let request = TestRequest::with_uri("/app.js").header(RANGE, "bytes=10-");
+        let (response, body) = serve_request(&root, request, 0);
+
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(header(&response, "content-range"), Some("bytes */10"));
+        assert!(body.is_empty());
+
+        let request = TestRequest::with_uri("/app.js").header(RANGE, "bytes=-0");
+
+        assert_eq!(serve_request(&root, request, 0).0.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+
+        remove_dir_all(root).unwrap_or_default();
+    }
+}
@@ -0,0 +1,84 @@
+//! A storage which keeps the collections in memory. It is only used by the tests, so they do not
+//! touch the data directory.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use serde_yaml::Value;
+
+use crate::storage::{Collection, Storage, StorageError};
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    entries: Mutex<HashMap<&'static str, Vec<(String, Value)>>>,
+    unparsed: Mutex<HashSet<&'static str>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn change<F>(&self, collection: &Collection, change: F) -> Result<(), StorageError>
+    where
+        F: FnOnce(&mut Vec<(String, Value)>),
+    {
+        if self.is_unparsed(collection) {
+            return Err(StorageError::Unparsed(collection.name));
+        }
+
+        change(self.entries.lock().unwrap().entry(collection.name).or_default());
+
+        Ok(())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn load(&self, collection: &Collection) -> Vec<(String, Value)> {
+        self.unparsed.lock().unwrap().remove(collection.name);
+        self.entries.lock().unwrap().get(collection.name).cloned().unwrap_or_default()
+    }
+
+    fn put(&self, collection: &Collection, key: &str, value: Value) -> Result<(), StorageError> {
+        let new_key = collection.key_of(key, &value);
+
+        self.change(collection, |entries| {
+            if new_key.ne(key) {
+                entries.retain(|(existing, _)| existing.ne(&new_key));
+            }
+
+            match entries.iter_mut().find(|(existing, _)| existing.eq(key)) {
+                Some(entry) => *entry = (new_key, value),
+                None => entries.push((new_key, value)),
+            }
+        })
+    }
+
+    fn delete(&self, collection: &Collection, key: &str) -> Result<(), StorageError> {
+        self.change(collection, |entries| entries.retain(|(existing, _)| existing.ne(key)))
+    }
+
+    fn replace(&self, collection: &Collection, entries: Vec<(String, Value)>) -> Result<(), StorageError> {
+        self.change(collection, |existing| *existing = entries)
+    }
+
+    fn restore(&self, collection: &Collection, content: &[u8]) -> Result<(), StorageError> {
+        let entries = serde_yaml::from_slice(content)
+            .ok()
+            .and_then(|content| collection.entries(content))
+            .ok_or_else(|| StorageError::Serialize(format!("The backup is no {} file", collection.file_name())))?;
+
+        self.unparsed.lock().unwrap().remove(collection.name);
+        self.entries.lock().unwrap().insert(collection.name, entries);
+
+        Ok(())
+    }
+
+    fn is_unparsed(&self, collection: &Collection) -> bool {
+        self.unparsed.lock().unwrap().contains(collection.name)
+    }
+
+    fn mark_unparsed(&self, collection: &Collection) {
+        self.unparsed.lock().unwrap().insert(collection.name);
+    }
+}
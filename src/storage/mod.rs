@@ -0,0 +1,267 @@
+//! The storage backends of the services
+//!
+//! The data of the services is stored in collections of named entries. By default every
+//! collection is a YAML file (see [yaml::YamlStorage]). Alternatively all collections are stored
+//! in a SQLite database (see [sqlite::SqliteStorage]), which only writes the changed entries.
+//!
+//! The services receive the storage (see [open_storage]) at construction.
+//!
+//! Both backends snapshot a collection before it is changed, so the backups (see
+//! [crate::persistence]) cover the database as well. A snapshot always is the YAML file of the
+//! collection.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_yaml::{Mapping, Value};
+
+use crate::paths::data_path;
+use crate::persistence::PersistenceError;
+use crate::settings::{StorageBackend, StorageSettings};
+use crate::storage::sqlite::SqliteStorage;
+use crate::storage::yaml::YamlStorage;
+
+pub mod sqlite;
+pub mod yaml;
+#[cfg(test)]
+pub mod memory;
+
+/// The storage shared by all services
+pub type SharedStorage = Arc<dyn Storage>;
+
+/// A backend storing the collections of the services.
+/// The entries of a collection keep their order.
+pub trait Storage: Send + Sync {
+    /// Loads all entries of the collection. Errors are logged and an empty list is returned.
+    fn load(&self, collection: &Collection) -> Vec<(String, Value)>;
+
+    /// Replaces the entry stored with `key` (keeping its position) or appends it. For list
+    /// collections the entry is stored with its (possibly changed) name.
+    fn put(&self, collection: &Collection, key: &str, value: Value) -> Result<(), StorageError>;
+
+    /// Deletes the entry stored with `key`
+    fn delete(&self, collection: &Collection, key: &str) -> Result<(), StorageError>;
+
+    /// Replaces all entries of the collection
+    fn replace(&self, collection: &Collection, entries: Vec<(String, Value)>) -> Result<(), StorageError>;
+
+    /// Replaces the collection with the content of a backup (the YAML of the collection file).
+    /// The current entries are backed up before, also if they could not be read.
+    fn restore(&self, collection: &Collection, content: &[u8]) -> Result<(), StorageError>;
+
+    /// Checks if the collection could not be read when it was loaded the last time. It is not
+    /// changed until it was loaded successfully or restored.
+    fn is_unparsed(&self, collection: &Collection) -> bool;
+
+    /// Marks the collection as not readable (like [Storage::is_unparsed]), e.g. because one of its
+    /// entries does not match the data of the service
+    fn mark_unparsed(&self, collection: &Collection);
+}
+
+/// A collection of entries, e.g. all dashboards
+pub struct Collection {
+    /// The name of the collection (and its YAML file)
+    pub name: &'static str,
+
+    /// Used in the log
+    pub label: &'static str,
+
+    pub layout: Layout,
+}
+
+/// How the entries are identified
+pub enum Layout {
+    /// A list of entries with a `name` field
+    List,
+
+    /// A map of entries by their name
+    Map,
+}
+
+pub const DASHBOARDS: Collection = Collection { name: "dashboard", label: "Dashboard", layout: Layout::List };
+pub const GROUPS: Collection = Collection { name: "group", label: "Group", layout: Layout::List };
+pub const SHORTCUTS: Collection = Collection { name: "shortcuts", label: "Shortcuts", layout: Layout::Map };
+pub const RULES: Collection = Collection { name: "rules", label: "Rules", layout: Layout::Map };
+pub const USERS: Collection = Collection { name: "users", label: "Users", layout: Layout::Map };
+
+pub const COLLECTIONS: [&Collection; 5] = [&DASHBOARDS, &GROUPS, &SHORTCUTS, &RULES, &USERS];
+
+/// Why storing data failed
+#[derive(Debug)]
+pub enum StorageError {
+    Persistence(PersistenceError),
+    Sqlite(rusqlite::Error),
+    Serialize(String),
+
+    /// Entries of the collection could not be read from the database, so it is not changed
+    Unparsed(&'static str),
+}
+
+/// Opens the storage selected in the settings. There is no fallback to the YAML files if the
+/// database can not be opened, as they would be outdated.
+pub fn open_storage(settings: &StorageSettings) -> Result<SharedStorage, StorageError> {
+    Ok(match settings.backend {
+        StorageBackend::Yaml => Arc::new(YamlStorage::new()),
+        StorageBackend::Sqlite => Arc::new(SqliteStorage::open(data_path(&settings.sqlite_path))?),
+    })
+}
+
+impl Collection {
+    /// The name of the YAML file
+    pub fn file_name(&self) -> String {
+        format!("{}.yaml", self.name)
+    }
+
+    /// Splits the YAML content of the collection into its entries. Returns `None` if it is neither
+    /// empty nor has the layout of the collection.
+    pub fn entries(&self, content: Value) -> Option<Vec<(String, Value)>> {
+        match (&self.layout, content) {
+            (_, Value::Null) => Some(Vec::new()),
+            (Layout::List, Value::Sequence(list)) => Some(
+                list.into_iter()
+                    .map(|value| (self.key_of("", &value), value))
+                    .collect(),
+            ),
+            (Layout::Map, Value::Mapping(map)) => Some(
+                map.into_iter()
+                    .filter_map(|(key, value)| key.as_str().map(|key| (String::from(key), value)))
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Joins the entries to the YAML content of the collection
+    pub fn to_value(&self, entries: &[(String, Value)]) -> Value {
+        match self.layout {
+            Layout::List => Value::Sequence(entries.iter().map(|(_, value)| value.clone()).collect()),
+            Layout::Map => {
+                let mut map = Mapping::new();
+
+                for (key, value) in entries {
+                    map.insert(Value::String(key.clone()), value.clone());
+                }
+
+                Value::Mapping(map)
+            }
+        }
+    }
+
+    /// The key an entry is stored with
+    pub fn key_of(&self, key: &str, value: &Value) -> String {
+        match self.layout {
+            Layout::List => value
+                .get("name")
+                .and_then(|name| name.as_str())
+                .map(String::from)
+                .unwrap_or_else(|| String::from(key)),
+            Layout::Map => String::from(key),
+        }
+    }
+}
+
+/// Loads a list collection. Entries which can not be read are left out, the collection is not
+/// changed then (see [Storage::mark_unparsed]), so they are not lost.
+pub fn load_list<T: DeserializeOwned>(storage: &dyn Storage, collection: &Collection) -> Vec<T> {
+    storage
+        .load(collection)
+        .into_iter()
+        .filter_map(|(key, value)| deserialize(storage, collection, &key, value))
+        .collect()
+}
+
+/// Loads a map collection. Entries which can not be read are left out like in [load_list].
+pub fn load_map<T: DeserializeOwned>(storage: &dyn Storage, collection: &Collection) -> HashMap<String, T> {
+    storage
+        .load(collection)
+        .into_iter()
+        .filter_map(|(key, value)| deserialize(storage, collection, &key, value).map(|data| (key, data)))
+        .collect()
+}
+
+fn deserialize<T: DeserializeOwned>(storage: &dyn Storage, collection: &Collection, key: &str, value: Value) -> Option<T> {
+    match serde_yaml::from_value(value) {
+        Ok(data) => Some(data),
+        Err(error) => {
+            eprintln!("[ERROR] [{}]: Could not read entry {}. It will not be changed until it is fixed and reloaded", collection.label, key);
+            eprintln!("{}", error);
+
+            storage.mark_unparsed(collection);
+
+            None
+        }
+    }
+}
+
+/// Stores a single entry
+pub fn put_entry<T: Serialize>(storage: &dyn Storage, collection: &Collection, key: &str, data: &T) -> Result<(), StorageError> {
+    serialize(data).and_then(|value| storage.put(collection, key, value))
+}
+
+/// Deletes a single entry
+pub fn delete_entry(storage: &dyn Storage, collection: &Collection, key: &str) -> Result<(), StorageError> {
+    storage.delete(collection, key)
+}
+
+fn serialize<T: Serialize>(data: &T) -> Result<Value, StorageError> {
+    serde_yaml::to_value(data).map_err(|error| StorageError::Serialize(error.to_string()))
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::Persistence(error) => write!(f, "{}", error),
+            StorageError::Sqlite(error) => write!(f, "Database error: {}", error),
+            StorageError::Serialize(error) => write!(f, "Could not serialize: {}", error),
+            StorageError::Unparsed(name) => write!(
+                f,
+                "Entries of {} could not be read from the database. Changes are not saved until they are fixed and reloaded (or a backup is restored)",
+                name
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::services::DashboardData;
+    use crate::storage::memory::MemoryStorage;
+
+    use super::*;
+
+    fn dashboard(name: &str, groups: &str) -> Value {
+        serde_yaml::from_str(&format!("{{name: {}, groups: {}}}", name, groups)).unwrap()
+    }
+
+    /// Loads a collection with an entry which is no dashboard, then tries to change it
+    fn keeps_unreadable_entries(storage: &dyn Storage) {
+        storage.put(&DASHBOARDS, "Living Room", dashboard("Living Room", "[lights]")).unwrap();
+        storage.put(&DASHBOARDS, "Kitchen", dashboard("Kitchen", "no list")).unwrap();
+
+        let dashboards: Vec<DashboardData> = load_list(storage, &DASHBOARDS);
+
+        assert_eq!(dashboards.len(), 1);
+        assert!(storage.is_unparsed(&DASHBOARDS));
+
+        assert!(storage.replace(&DASHBOARDS, Vec::new()).is_err());
+        assert!(put_entry(storage, &DASHBOARDS, "Living Room", &dashboards[0]).is_err());
+        assert_eq!(storage.load(&DASHBOARDS).len(), 2);
+        assert!(!storage.is_unparsed(&DASHBOARDS));
+    }
+
+    #[test]
+    fn keeps_unreadable_entries_in_memory() {
+        keeps_unreadable_entries(&MemoryStorage::new());
+    }
+
+    #[test]
+    fn keeps_unreadable_entries_in_the_database() {
+        let path = data_path("unreadable-entries.sqlite");
+
+        std::fs::remove_file(&path).unwrap_or_default();
+        keeps_unreadable_entries(&SqliteStorage::open(&path).unwrap());
+    }
+}
@@ -0,0 +1,297 @@
+//! Stores all collections in a single SQLite database. Changes only write the affected entries,
+//! so large collections and frequent changes do not rewrite everything.
+//!
+//! When a collection is used the first time, its YAML file is imported (if there is one).
+//!
+//! Before a collection is changed, it is exported as YAML into the backup directory (see
+//! [crate::persistence::snapshot]), so it can be restored like the YAML files.
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Mutex;
+
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Transaction};
+use serde_yaml::Value;
+
+use crate::persistence::{snapshot, PersistenceError};
+use crate::storage::yaml::YamlStorage;
+use crate::storage::{Collection, Storage, StorageError};
+
+pub struct SqliteStorage {
+    connection: Mutex<Connection>,
+
+    /// The collections with entries which could not be read when they were loaded
+    unparsed: Mutex<HashSet<&'static str>>,
+}
+
+impl SqliteStorage {
+    /// Opens (or creates) the database
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StorageError> {
+        let connection = Connection::open(path)?;
+
+        // The journal mode pragma returns the new mode, so it can not be run with execute
+        connection.query_row("PRAGMA journal_mode = WAL", params![], |_| Ok(()))?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS collections (
+                name TEXT PRIMARY KEY
+            );
+            CREATE TABLE IF NOT EXISTS entries (
+                collection TEXT NOT NULL,
+                key TEXT NOT NULL,
+                position INTEGER NOT NULL,
+                data TEXT NOT NULL,
+                PRIMARY KEY (collection, key)
+            );",
+        )?;
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+            unparsed: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Opens an existing database without changing it
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Self, StorageError> {
+        Ok(Self {
+            connection: Mutex::new(Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?),
+            unparsed: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// The stored entries of the collection (with their YAML), without importing its YAML file.
+    /// None if the collection was not imported yet.
+    pub fn stored(&self, collection: &Collection) -> Result<Option<Vec<(String, String)>>, StorageError> {
+        let connection = self.connection.lock().unwrap();
+        let imported = connection
+            .query_row("SELECT name FROM collections WHERE name = ?1", params![collection.name], |_| Ok(()))
+            .optional()?
+            .is_some();
+
+        if !imported {
+            return Ok(None);
+        }
+
+        rows(&connection, collection).map(Some)
+    }
+
+    /// Runs the change in a transaction, after the collection was imported
+    fn transaction<T, F>(&self, collection: &Collection, change: F) -> Result<T, StorageError>
+    where
+        F: FnOnce(&Transaction) -> Result<T, StorageError>,
+    {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+
+        import(&transaction, collection)?;
+
+        let result = change(&transaction)?;
+        transaction.commit()?;
+
+        Ok(result)
+    }
+
+    /// Runs the change in a transaction and takes a snapshot of the collection before. A
+    /// collection which could not be read is only changed by restoring a backup.
+    fn change<F>(&self, collection: &Collection, restore: bool, change: F) -> Result<(), StorageError>
+    where
+        F: FnOnce(&Transaction) -> Result<(), StorageError>,
+    {
+        if !restore && self.is_unparsed(collection) {
+            return Err(StorageError::Unparsed(collection.name));
+        }
+
+        self.transaction(collection, |transaction| {
+            let before = rows(transaction, collection)?;
+
+            change(transaction)?;
+
+            if !before.is_empty() {
+                let after = rows(transaction, collection)?;
+
+                snapshot(&collection.file_name(), &export(collection, before)?, &export(collection, after)?)
+                    .map_err(|error| StorageError::Persistence(PersistenceError::Io(error)))?;
+            }
+
+            Ok(())
+        })?;
+
+        if restore {
+            self.set_unparsed(collection, false);
+        }
+
+        Ok(())
+    }
+
+    fn set_unparsed(&self, collection: &Collection, unparsed: bool) {
+        let mut list = self.unparsed.lock().unwrap();
+
+        if unparsed {
+            list.insert(collection.name);
+        } else {
+            list.remove(collection.name);
+        }
+    }
+}
+
+/// The stored entries of the collection (with their YAML)
+fn rows(connection: &Connection, collection: &Collection) -> Result<Vec<(String, String)>, StorageError> {
+    let mut statement = connection.prepare("SELECT key, data FROM entries WHERE collection = ?1 ORDER BY position, key")?;
+    let rows = statement
+        .query_map(params![collection.name], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+        .collect::<Result<Vec<(String, String)>, rusqlite::Error>>()?;
+
+    Ok(rows)
+}
+
+/// The YAML file of the collection with the entries. Entries which can not be parsed are left out.
+fn export(collection: &Collection, rows: Vec<(String, String)>) -> Result<Vec<u8>, StorageError> {
+    let entries: Vec<(String, Value)> = rows
+        .into_iter()
+        .filter_map(|(key, data)| serde_yaml::from_str(&data).ok().map(|value| (key, value)))
+        .collect();
+
+    encode(&collection.to_value(&entries)).map(String::into_bytes)
+}
+
+/// Imports the YAML file of the collection, unless that already happened
+fn import(transaction: &Transaction, collection: &Collection) -> Result<(), StorageError> {
+    let imported = transaction
+        .query_row("SELECT name FROM collections WHERE name = ?1", params![collection.name], |_| Ok(()))
+        .optional()?
+        .is_some();
+
+    if imported {
+        return Ok(());
+    }
+
+    let path = YamlStorage::path(collection);
+
+    if path.exists() {
+        println!("[Storage]: Importing {} into the database", path.display());
+
+        insert_all(transaction, collection, YamlStorage::new().load(collection))?;
+    }
+
+    transaction.execute("INSERT INTO collections (name) VALUES (?1)", params![collection.name])?;
+
+    Ok(())
+}
+
+fn insert_all(transaction: &Transaction, collection: &Collection, entries: Vec<(String, Value)>) -> Result<(), StorageError> {
+    transaction.execute("DELETE FROM entries WHERE collection = ?1", params![collection.name])?;
+
+    for (position, (key, value)) in entries.iter().enumerate() {
+        transaction.execute(
+            "INSERT OR REPLACE INTO entries (collection, key, position, data) VALUES (?1, ?2, ?3, ?4)",
+            params![collection.name, key, position as i64, encode(value)?],
+        )?;
+    }
+
+    Ok(())
+}
+
+fn encode(value: &Value) -> Result<String, StorageError> {
+    serde_yaml::to_string(value).map_err(|error| StorageError::Serialize(error.to_string()))
+}
+
+impl Storage for SqliteStorage {
+    fn load(&self, collection: &Collection) -> Vec<(String, Value)> {
+        let rows = match self.transaction(collection, |transaction| rows(transaction, collection)) {
+            Ok(rows) => rows,
+            Err(error) => {
+                eprintln!("[ERROR] [{}]: Could not read from the database", collection.label);
+                eprintln!("{}", error);
+
+                self.set_unparsed(collection, true);
+
+                return Vec::new();
+            }
+        };
+        let mut unparsed = false;
+        let entries = rows
+            .into_iter()
+            .filter_map(|(key, data)| match serde_yaml::from_str(&data) {
+                Ok(value) => Some((key, value)),
+                Err(error) => {
+                    eprintln!("[ERROR] [{}]: Could not parse entry {} from the database. It will not be changed until it is fixed and reloaded", collection.label, key);
+                    eprintln!("{}", error);
+
+                    unparsed = true;
+
+                    None
+                }
+            })
+            .collect();
+
+        self.set_unparsed(collection, unparsed);
+
+        entries
+    }
+
+    fn put(&self, collection: &Collection, key: &str, value: Value) -> Result<(), StorageError> {
+        let new_key = collection.key_of(key, &value);
+        let data = encode(&value)?;
+
+        self.change(collection, false, |transaction| {
+            if new_key.ne(key) {
+                transaction.execute(
+                    "DELETE FROM entries WHERE collection = ?1 AND key = ?2",
+                    params![collection.name, new_key],
+                )?;
+            }
+
+            let updated = transaction.execute(
+                "UPDATE entries SET key = ?3, data = ?4 WHERE collection = ?1 AND key = ?2",
+                params![collection.name, key, new_key, data],
+            )?;
+
+            if updated == 0 {
+                transaction.execute(
+                    "INSERT INTO entries (collection, key, position, data)
+                     SELECT ?1, ?2, COALESCE(MAX(position) + 1, 0), ?3 FROM entries WHERE collection = ?1",
+                    params![collection.name, new_key, data],
+                )?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn delete(&self, collection: &Collection, key: &str) -> Result<(), StorageError> {
+        self.change(collection, false, |transaction| {
+            transaction.execute(
+                "DELETE FROM entries WHERE collection = ?1 AND key = ?2",
+                params![collection.name, key],
+            )?;
+
+            Ok(())
+        })
+    }
+
+    fn replace(&self, collection: &Collection, entries: Vec<(String, Value)>) -> Result<(), StorageError> {
+        self.change(collection, false, |transaction| insert_all(transaction, collection, entries))
+    }
+
+    fn restore(&self, collection: &Collection, content: &[u8]) -> Result<(), StorageError> {
+        let entries = serde_yaml::from_slice(content)
+            .ok()
+            .and_then(|content| collection.entries(content))
+            .ok_or_else(|| StorageError::Serialize(format!("The backup is no {} file", collection.file_name())))?;
+
+        self.change(collection, true, |transaction| insert_all(transaction, collection, entries))
+    }
+
+    fn is_unparsed(&self, collection: &Collection) -> bool {
+        self.unparsed.lock().unwrap().contains(collection.name)
+    }
+
+    fn mark_unparsed(&self, collection: &Collection) {
+        self.set_unparsed(collection, true);
+    }
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(error: rusqlite::Error) -> Self {
+        StorageError::Sqlite(error)
+    }
+}
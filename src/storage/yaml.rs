@@ -0,0 +1,112 @@
+//! The default storage, which keeps every collection in its own YAML file in the data directory.
+//! Every change rewrites the whole file (see [crate::persistence::save_yaml]).
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde_yaml::Value;
+
+use crate::paths::data_path;
+use crate::persistence::{is_unparsed, load_yaml, mark_unparsed, restore_file, save_yaml};
+use crate::storage::{Collection, Storage, StorageError};
+
+/// Stores the collections in YAML files. The entries are cached, so single changes do not have
+/// to read the file again.
+#[derive(Default)]
+pub struct YamlStorage {
+    entries: Mutex<HashMap<&'static str, Vec<(String, Value)>>>,
+}
+
+impl YamlStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The file the collection is stored in
+    pub fn path(collection: &Collection) -> PathBuf {
+        data_path(collection.file_name())
+    }
+
+    fn read(collection: &Collection) -> Vec<(String, Value)> {
+        let content: Value = load_yaml(&Self::path(collection), collection.label);
+
+        collection.entries(content).unwrap_or_default()
+    }
+
+    fn write(collection: &Collection, entries: &[(String, Value)]) -> Result<(), StorageError> {
+        let content = collection.to_value(entries);
+
+        save_yaml(&Self::path(collection), &content).map_err(StorageError::Persistence)
+    }
+
+    /// Changes the cached entries of the collection and writes them to the file
+    fn change<F>(&self, collection: &Collection, change: F) -> Result<(), StorageError>
+    where
+        F: FnOnce(&mut Vec<(String, Value)>),
+    {
+        let mut cache = self.entries.lock().unwrap();
+        let mut entries = cache
+            .get(collection.name)
+            .cloned()
+            .unwrap_or_else(|| Self::read(collection));
+
+        change(&mut entries);
+        Self::write(collection, &entries)?;
+        cache.insert(collection.name, entries);
+
+        Ok(())
+    }
+}
+
+impl Storage for YamlStorage {
+    fn load(&self, collection: &Collection) -> Vec<(String, Value)> {
+        let entries = Self::read(collection);
+
+        self.entries.lock().unwrap().insert(collection.name, entries.clone());
+
+        entries
+    }
+
+    fn put(&self, collection: &Collection, key: &str, value: Value) -> Result<(), StorageError> {
+        let new_key = collection.key_of(key, &value);
+
+        self.change(collection, |entries| {
+            if new_key.ne(key) {
+                entries.retain(|(existing, _)| existing.ne(&new_key));
+            }
+
+            match entries.iter_mut().find(|(existing, _)| existing.eq(key)) {
+                Some(entry) => *entry = (new_key, value),
+                None => entries.push((new_key, value)),
+            }
+        })
+    }
+
+    fn delete(&self, collection: &Collection, key: &str) -> Result<(), StorageError> {
+        self.change(collection, |entries| entries.retain(|(existing, _)| existing.ne(key)))
+    }
+
+    fn replace(&self, collection: &Collection, entries: Vec<(String, Value)>) -> Result<(), StorageError> {
+        Self::write(collection, &entries)?;
+        self.entries.lock().unwrap().insert(collection.name, entries);
+
+        Ok(())
+    }
+
+    fn restore(&self, collection: &Collection, content: &[u8]) -> Result<(), StorageError> {
+        // The file is restored as it is, so comments and formatting are kept
+        restore_file(&Self::path(collection), content).map_err(StorageError::Persistence)?;
+        self.entries.lock().unwrap().remove(collection.name);
+
+        Ok(())
+    }
+
+    fn is_unparsed(&self, collection: &Collection) -> bool {
+        is_unparsed(&Self::path(collection))
+    }
+
+    fn mark_unparsed(&self, collection: &Collection) {
+        mark_unparsed(&Self::path(collection), true);
+    }
+}
@@ -2,66 +2,142 @@ use std::collections::HashMap;
 use std::net::ToSocketAddrs;
 
 use actix::Addr;
-use actix_web::{App, HttpRequest, HttpResponse, HttpServer, Responder, web};
+use actix_codec::Decoder;
+use actix_http::ws::{self, Codec, Frame};
+use actix_web::{App, HttpMessage, HttpRequest, HttpResponse, HttpServer, web};
 use actix_web::client::Client;
-use actix_web::dev::{Service, ServiceResponse};
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::dev::{BodyEncoding, BodySize, MessageBody, Service, ServiceResponse};
 use actix_web::Error;
 use actix_web::http::{HeaderValue, Method};
-use actix_web::http::header::{ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN};
-use actix_web::web::{Bytes, Data, Json, Path};
-use serde_json::Value;
-
+use actix_web::http::header::{ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH, ORIGIN, VARY, ContentEncoding};
+use actix_web::error::JsonPayloadError;
+use actix_web::middleware::Compress;
+use actix_web::web::{Bytes, BytesMut, Data, Json, JsonConfig, Path, Payload, Query};
+use futures::channel::mpsc::unbounded;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::auth::{AuthenticatedUser, Role};
+use crate::auth::middleware::Authentication;
+use crate::auth::session::SESSION_COOKIE;
+use crate::error::ServiceError;
 use crate::mime_type_mapper::MimeTypeMapper;
-use crate::services::{DashboardData, DashboardMessage, DashboardService, GroupData, GroupMessage, GroupService, ShortcutData, ShortcutsMessage, ShortcutsService, WebSettingsCompiledMessage, WebSettingsService};
+use crate::paths::config_path;
+use crate::proxy::proxy;
+use crate::services::events::encode_frame;
+use crate::services::history::revert;
+use crate::services::revision::{etag, none_match};
+use crate::services::{BackupMessage, BackupService, Change, DashboardData, DashboardMessage, DashboardService, Edit, EventMessage, EventService, GroupData, GroupItemData, GroupMessage, GroupService, GroupUsage, HistoryMessage, HistoryQuery, HistoryService, Precondition, RuleData, RuleMessage, RuleService, SessionMessage, ShortcutEntry, ShortcutsMessage, ShortcutsService, UserData, UserMessage, UserService, WebSettingsCompiledMessage, WebSettingsService};
 use crate::settings::{AppSettings, ServerType};
+use crate::static_files::{self, preferred_encoding, StaticFiles, MIN_COMPRESS_SIZE};
 
+#[allow(clippy::too_many_arguments)]
 pub async fn start_web_server(
     bind_addr: impl ToSocketAddrs,
     web_settings: Addr<WebSettingsService>,
     shortcuts: Addr<ShortcutsService>,
     dashboard: Addr<DashboardService>,
     group: Addr<GroupService>,
+    rules: Addr<RuleService>,
+    users: Addr<UserService>,
+    events: Addr<EventService>,
+    backup: Addr<BackupService>,
+    history: Addr<HistoryService>,
     settings: AppSettings,
 ) -> std::io::Result<()> {
+    let static_files = Data::new(StaticFiles::new(settings.static_files.clone()));
+
     HttpServer::new(move || App::new()
         .data(web_settings.clone())
         .data(shortcuts.clone())
         .data(settings.clone())
         .data(dashboard.clone())
         .data(group.clone())
+        .data(rules.clone())
+        .data(users.clone())
+        .data(events.clone())
+        .data(backup.clone())
+        .data(history.clone())
         .data(Client::new())
         .data(MimeTypeMapper::default())
+        .app_data(static_files.clone())
+        .app_data(JsonConfig::default().error_handler(|error, _| invalid_body(error).into()))
         .route("/settings.js", web::get().to(settings_js))
+        .route("/ws", web::get().to(ws_events))
         .route("/api/shortcut", web::get().to(api_shortcuts_list))
         .route("/api/shortcut/{name}", web::get().to(api_shortcut_get))
         .route("/api/shortcut/{name}", web::post().to(api_shortcut_post))
         .route("/api/dashboard", web::get().to(api_dashboard_list))
         .route("/api/dashboard/{name}", web::get().to(api_dashboard_get))
         .route("/api/dashboard/{name}", web::post().to(api_dashboard_post))
+        .route("/api/dashboard/{name}", web::patch().to(api_dashboard_patch))
         .route("/api/dashboard/{name}", web::delete().to(api_dashboard_delete))
+        .route("/api/dashboard/{name}/full", web::get().to(api_dashboard_full))
+        .route("/api/group", web::get().to(api_group_list))
         .route("/api/group/{name}", web::get().to(api_group_get))
         .route("/api/group/{name}", web::post().to(api_group_post))
+        .route("/api/group/{name}", web::patch().to(api_group_patch))
         .route("/api/group/{name}", web::delete().to(api_group_delete))
+        .route("/api/group/{name}/item/{item}", web::get().to(api_group_item_get))
+        .route("/api/group/{name}/item/{item}", web::post().to(api_group_item_post))
+        .route("/api/group/{name}/item/{item}", web::delete().to(api_group_item_delete))
+        .route("/api/group/{name}/item/{item}/move", web::post().to(api_group_item_move))
+        .route("/api/group/{name}/usage", web::get().to(api_group_usage))
+        .route("/api/rule", web::get().to(api_rule_list))
+        .route("/api/rule/{name}", web::get().to(api_rule_get))
+        .route("/api/rule/{name}", web::post().to(api_rule_post))
+        .route("/api/rule/{name}", web::delete().to(api_rule_delete))
         .route(
             "/api/shortcut/{name}",
             web::delete().to(api_shortcut_delete),
         )
-        .route("/api/{_:.*}", web::method(Method::OPTIONS).to(|| HttpResponse::Ok()))
+        .route("/api/shortcut/{name}/run", web::post().to(api_shortcut_run))
+        .route("/api/session", web::get().to(api_session_get))
+        .route("/api/session", web::post().to(api_session_post))
+        .route("/api/session", web::delete().to(api_session_delete))
+        .route("/api/token", web::get().to(api_token_list))
+        .route("/api/token/{name}", web::post().to(api_token_post))
+        .route("/api/token/{name}", web::delete().to(api_token_delete))
+        .route("/api/user", web::get().to(api_user_list))
+        .route("/api/user/{name}", web::post().to(api_user_post))
+        .route("/api/user/{name}", web::delete().to(api_user_delete))
+        .route("/api/backup", web::get().to(api_backup_list))
+        .route("/api/backup/{name}/restore", web::post().to(api_backup_restore))
+        .route("/api/history", web::get().to(api_history_list))
+        .route("/api/history/{id}/revert", web::post().to(api_history_revert))
+        .route("/api/{_:.*}", web::method(Method::OPTIONS).to(HttpResponse::Ok))
         .default_service(web::to(default_service))
+        .wrap(Authentication::new(users.clone()))
         .wrap_fn(|req, srv| {
-            let origin = match req.headers().get("Origin") {
-                Some(origin) => String::from(origin.to_str().unwrap_or_default()),
-                _ => String::new(),
-            };
+            // Only configured origins get the response, as the browser sends the credentials along
+            let origin = req
+                .headers()
+                .get(ORIGIN)
+                .and_then(|origin| origin.to_str().ok())
+                .filter(|origin| {
+                    req.app_data::<Data<AppSettings>>()
+                        .is_some_and(|settings| settings.auth.allowed_origins.iter().any(|allowed| allowed == origin))
+                })
+                .map(String::from);
             let fut = srv.call(req);
 
             async move {
                 let mut res: ServiceResponse = fut.await.unwrap();
 
+                res.headers_mut().append(VARY, HeaderValue::from_static("Origin"));
+
+                let origin = match origin {
+                    Some(origin) => origin,
+                    None => return Ok(res),
+                };
+
                 res.headers_mut().insert(
                     ACCESS_CONTROL_ALLOW_HEADERS,
-                    HeaderValue::from_str("Authorization, Content-Type, Cookie").unwrap(),
+                    HeaderValue::from_str("Authorization, Content-Type, Cookie, If-Match, If-None-Match").unwrap(),
                 );
+                res.headers_mut().insert(ACCESS_CONTROL_EXPOSE_HEADERS, HeaderValue::from_str("ETag").unwrap());
                 res.headers_mut().insert(
                     ACCESS_CONTROL_ALLOW_METHODS,
                     HeaderValue::from_str("GET, POST, PUT, PATCH, DELETE, CALL").unwrap(),
@@ -77,253 +153,832 @@ pub async fn start_web_server(
 
                 Ok(res)
             }
-        }))
+        })
+        // Only compressible responses are compressed by [Compress] (see [MimeTypeMapper::is_compressible])
+        .wrap_fn(|req, srv| {
+            let encoding = preferred_encoding(req.headers());
+            let fut = srv.call(req);
+
+            async move {
+                let mut res: ServiceResponse = fut.await?;
+                let compressible = res
+                    .headers()
+                    .get(CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .is_some_and(MimeTypeMapper::is_compressible);
+                let small = matches!(res.response().body().size(), BodySize::Sized(size) if size < MIN_COMPRESS_SIZE);
+
+                if compressible || res.headers().contains_key(CONTENT_ENCODING) {
+                    res.headers_mut().append(VARY, HeaderValue::from_static("Accept-Encoding"));
+                }
+
+                // Responses which chose their encoding (like ranges and static files) keep it
+                if res.response().get_encoding().is_none() {
+                    res.response_mut().encoding(if compressible && !small { encoding } else { ContentEncoding::Identity });
+                }
+
+                Ok(res)
+            }
+        })
+        .wrap(Compress::default()))
         .bind(bind_addr)?
         .run()
         .await
 }
 
-async fn settings_js(settings: Data<Addr<WebSettingsService>>) -> impl Responder {
-    match settings.send(WebSettingsCompiledMessage::Get).await {
-        Ok(value) => HttpResponse::Ok().header("Content-Type", "application/javascript").body(value),
-        Err(error) => {
-            eprintln!("[ERROR] [Web Server] {:?}", error);
+async fn settings_js(settings: Data<Addr<WebSettingsService>>) -> Result<HttpResponse, ServiceError> {
+    let value = settings.send(WebSettingsCompiledMessage::Get).await?;
 
-            HttpResponse::InternalServerError().body("Server error occurred. For more information ask the system administrator")
-        }
-    }
+    Ok(HttpResponse::Ok()
+        .header("Content-Type", "application/javascript")
+        .header(CACHE_CONTROL, "no-cache")
+        .body(value))
 }
 
-async fn api_shortcuts_list(shortcuts: Data<Addr<ShortcutsService>>) -> impl Responder {
-    let shortcuts = match shortcuts.send(ShortcutsMessage::List).await {
-        Ok(data) => data,
-        Err(error) => {
-            eprintln!("[ERROR] [Web Server] {:?}", error);
+/// Pushes a [ChangeEvent](crate::services::ChangeEvent) for every change of the dashboards,
+/// groups and shortcuts. Messages from the client are ignored (except pings and close).
+async fn ws_events(req: HttpRequest, mut payload: Payload, events: Data<Addr<EventService>>) -> Result<HttpResponse, Error> {
+    let caller = caller(&req)?;
+    let mut response = ws::handshake(req.head())?;
+    let (sender, receiver) = unbounded::<Bytes>();
+
+    events.do_send(EventMessage::Subscribe(caller, sender.clone()));
+
+    actix_web::rt::spawn(async move {
+        let mut codec = Codec::new();
+        let mut buffer = BytesMut::new();
+
+        'connection: while let Some(Ok(chunk)) = payload.next().await {
+            buffer.extend_from_slice(&chunk);
+
+            loop {
+                let reply = match codec.decode(&mut buffer) {
+                    Ok(Some(Frame::Ping(data))) => ws::Message::Pong(data),
+                    Ok(Some(Frame::Close(reason))) => {
+                        sender.unbounded_send(encode_frame(ws::Message::Close(reason))).unwrap_or_default();
+
+                        break 'connection;
+                    }
+                    Ok(Some(_)) => continue,
+                    Ok(None) => break,
+                    Err(error) => {
+                        eprintln!("[WARN] [Web Server]: Invalid WebSocket frame {:?}", error);
+
+                        break 'connection;
+                    }
+                };
 
-            HashMap::new()
+                sender.unbounded_send(encode_frame(reply)).unwrap_or_default();
+            }
         }
-    };
 
-    HttpResponse::Ok().json(&shortcuts)
+        // Ends the response stream, also for the clone held by the event service
+        sender.close_channel();
+    });
+
+    Ok(response.streaming(receiver.map(Ok::<Bytes, Error>)))
+}
+
+async fn api_shortcuts_list(req: HttpRequest, shortcuts: Data<Addr<ShortcutsService>>) -> Result<HttpResponse, ServiceError> {
+    let shortcuts = shortcuts.send(ShortcutsMessage::List).await??;
+
+    Ok(HttpResponse::Ok().json(visible_shortcuts(&caller(&req)?, shortcuts)))
 }
 
 async fn api_shortcut_get(
+    req: HttpRequest,
     name: Path<String>,
     shortcuts: Data<Addr<ShortcutsService>>,
-) -> impl Responder {
+) -> Result<HttpResponse, ServiceError> {
     let name = Path(percent_encoding::percent_decode_str(name.0.as_str()).decode_utf8_lossy().to_string());
+    let shortcuts = visible_shortcuts(&caller(&req)?, shortcuts.send(ShortcutsMessage::Get(name.to_string())).await??);
 
-    let shortcuts = match shortcuts
-        .send(ShortcutsMessage::Get(name.to_string()))
-        .await
-    {
-        Ok(data) => data,
-        Err(error) => {
-            eprintln!("[ERROR] [Web Server] {:?}", error);
-
-            HashMap::new()
-        }
+    // Hidden shortcuts are reported as missing, so their names are not revealed
+    let revision = match shortcuts.get(&name.0) {
+        Some(shortcut) => shortcut.revision(),
+        None => return Err(ServiceError::not_found("shortcut", name.0)),
     };
 
-    HttpResponse::Ok().json(&shortcuts)
+    Ok(entry_response(&req, revision, shortcuts))
 }
 
 async fn api_shortcut_post(
+    req: HttpRequest,
     name: Path<String>,
-    body: Json<Vec<ShortcutData>>,
+    body: Json<ShortcutEntry>,
     shortcuts: Data<Addr<ShortcutsService>>,
-) -> impl Responder {
+) -> Result<HttpResponse, ServiceError> {
     let name = Path(percent_encoding::percent_decode_str(name.0.as_str()).decode_utf8_lossy().to_string());
 
-    let shortcuts = match shortcuts
-        .send(ShortcutsMessage::Add(name.to_string(), body.0))
-        .await
-    {
-        Ok(data) => data,
-        Err(error) => {
-            eprintln!("[ERROR] [Web Server] {:?}", error);
+    if !caller(&req)?.can_edit() {
+        return Err(ServiceError::Forbidden);
+    }
 
-            HashMap::new()
-        }
-    };
+    let shortcuts = shortcuts
+        .send(edit(&req, ShortcutsMessage::Add(name.to_string(), body.0)))
+        .await??;
 
-    HttpResponse::Ok().json(&shortcuts)
+    Ok(changed_response(shortcuts.get(&name.0).map(ShortcutEntry::revision), &shortcuts))
 }
 
 async fn api_shortcut_delete(
+    req: HttpRequest,
     name: Path<String>,
     shortcuts: Data<Addr<ShortcutsService>>,
-) -> impl Responder {
+) -> Result<HttpResponse, ServiceError> {
     let name = Path(percent_encoding::percent_decode_str(name.0.as_str()).decode_utf8_lossy().to_string());
 
-    let shortcuts = match shortcuts
-        .send(ShortcutsMessage::Delete(name.to_string()))
-        .await
-    {
-        Ok(data) => data,
-        Err(error) => {
-            eprintln!("[ERROR] [Web Server] {:?}", error);
+    if !caller(&req)?.can_edit() {
+        return Err(ServiceError::Forbidden);
+    }
 
-            HashMap::new()
-        }
-    };
+    let shortcuts = shortcuts
+        .send(edit(&req, ShortcutsMessage::Delete(name.to_string())))
+        .await??;
 
-    HttpResponse::Ok().json(&shortcuts)
+    Ok(HttpResponse::Ok().json(&shortcuts))
 }
 
-async fn api_dashboard_list(dashboard: Data<Addr<DashboardService>>) -> impl Responder {
-    let dashboards = match dashboard.send(DashboardMessage::List).await {
-        Ok(dashboards) => dashboards,
-        Err(error) => {
-            eprintln!("[ERROR] [Web Server] {:?}", error);
+async fn api_shortcut_run(
+    req: HttpRequest,
+    name: Path<String>,
+    shortcuts: Data<Addr<ShortcutsService>>,
+) -> Result<HttpResponse, ServiceError> {
+    let name = Path(percent_encoding::percent_decode_str(name.0.as_str()).decode_utf8_lossy().to_string());
+    let caller = caller(&req)?;
+    let shortcut = shortcuts.send(ShortcutsMessage::Get(name.to_string())).await??;
 
-            Vec::new()
-        }
-    };
+    // Hidden shortcuts are reported as missing (like in the get), so their names are not revealed
+    if !shortcut.values().all(|shortcut| caller.can_see(shortcut.visible_to())) {
+        return Err(ServiceError::not_found("shortcut", name.0));
+    }
 
-    HttpResponse::Ok().json(dashboards)
+    let shortcuts = shortcuts.send(ShortcutsMessage::Run(name.to_string())).await??;
+
+    Ok(HttpResponse::Ok().json(&shortcuts))
 }
 
-async fn api_dashboard_get(name: Path<String>, dashboard: Data<Addr<DashboardService>>) -> impl Responder {
+async fn api_dashboard_list(req: HttpRequest, dashboard: Data<Addr<DashboardService>>) -> Result<HttpResponse, ServiceError> {
+    let dashboards = dashboard.send(DashboardMessage::List).await??;
+
+    Ok(HttpResponse::Ok().json(visible_dashboards(&caller(&req)?, dashboards)))
+}
+
+async fn api_dashboard_get(req: HttpRequest, name: Path<String>, dashboard: Data<Addr<DashboardService>>) -> Result<HttpResponse, ServiceError> {
     let name = Path(percent_encoding::percent_decode_str(name.0.as_str()).decode_utf8_lossy().to_string());
+    let dashboard = visible_dashboards(&caller(&req)?, dashboard.send(DashboardMessage::Get(name.0.clone())).await??);
 
-    let dashboard = match dashboard.send(DashboardMessage::Get(name.0)).await {
-        Ok(dashboard) => dashboard,
-        Err(error) => {
-            eprintln!("[ERROR] [Web Server] {:?}", error);
+    match dashboard.first() {
+        Some(first) => Ok(entry_response(&req, first.revision(), &dashboard)),
+        None => Err(ServiceError::not_found("dashboard", name.0)),
+    }
+}
 
-            Vec::new()
-        }
-    };
+async fn api_dashboard_post(
+    req: HttpRequest,
+    name: Path<String>,
+    body: Json<DashboardData>,
+    dashboard: Data<Addr<DashboardService>>,
+) -> Result<HttpResponse, ServiceError> {
+    let name = Path(percent_encoding::percent_decode_str(name.0.as_str()).decode_utf8_lossy().to_string());
+
+    if !caller(&req)?.can_edit() {
+        return Err(ServiceError::Forbidden);
+    }
+
+    let new_name = String::from(body.name());
+    let dashboards = dashboard
+        .send(edit(&req, DashboardMessage::Set(name.0, body.0)))
+        .await??;
 
-    HttpResponse::Ok().json(dashboard)
+    Ok(changed_response(dashboard_revision(&dashboards, &new_name), dashboards))
 }
 
-async fn api_dashboard_post(name: Path<String>, body: Json<DashboardData>, dashboard: Data<Addr<DashboardService>>) -> impl Responder {
+async fn api_dashboard_patch(
+    req: HttpRequest,
+    name: Path<String>,
+    body: Bytes,
+    dashboard: Data<Addr<DashboardService>>,
+) -> Result<HttpResponse, ServiceError> {
     let name = Path(percent_encoding::percent_decode_str(name.0.as_str()).decode_utf8_lossy().to_string());
 
-    let dashboards = match dashboard.send(DashboardMessage::Set(name.0, body.0)).await {
-        Ok(dashboard) => dashboard,
-        Err(error) => {
-            eprintln!("[ERROR] [Web Server] {:?}", error);
+    if !caller(&req)?.can_edit() {
+        return Err(ServiceError::Forbidden);
+    }
+
+    let change = Change::parse(req.content_type(), &body)?;
 
-            Vec::new()
-        }
-    };
+    let dashboards = dashboard
+        .send(edit(&req, DashboardMessage::Patch(name.0.clone(), change)))
+        .await??;
 
-    HttpResponse::Ok().json(dashboards)
+    Ok(changed_response(dashboard_revision(&dashboards, &name.0), dashboards))
 }
 
-async fn api_dashboard_delete(name: Path<String>, dashboard: Data<Addr<DashboardService>>) -> impl Responder {
+async fn api_dashboard_delete(req: HttpRequest, name: Path<String>, dashboard: Data<Addr<DashboardService>>) -> Result<HttpResponse, ServiceError> {
     let name = Path(percent_encoding::percent_decode_str(name.0.as_str()).decode_utf8_lossy().to_string());
 
-    let dashboard = match dashboard.send(DashboardMessage::Delete(name.0)).await {
-        Ok(dashboard) => dashboard,
-        Err(error) => {
-            eprintln!("[ERROR] [Web Server] {:?}", error);
+    if !caller(&req)?.can_edit() {
+        return Err(ServiceError::Forbidden);
+    }
 
-            Vec::new()
-        }
-    };
+    let dashboards = dashboard
+        .send(edit(&req, DashboardMessage::Delete(name.0)))
+        .await??;
+
+    Ok(HttpResponse::Ok().json(dashboards))
+}
 
-    HttpResponse::Ok().json(dashboard)
+/// A dashboard with all of its groups (in the order of the dashboard). Groups which do not exist
+/// are left out.
+#[derive(Serialize)]
+struct DashboardView {
+    dashboard: DashboardData,
+    groups: Vec<GroupData>,
 }
 
-async fn api_group_get(name: Path<String>, group: Data<Addr<GroupService>>) -> impl Responder {
+async fn api_dashboard_full(
+    req: HttpRequest,
+    name: Path<String>,
+    dashboard: Data<Addr<DashboardService>>,
+    group: Data<Addr<GroupService>>,
+) -> Result<HttpResponse, ServiceError> {
     let name = Path(percent_encoding::percent_decode_str(name.0.as_str()).decode_utf8_lossy().to_string());
+    let dashboard = visible_dashboards(&caller(&req)?, dashboard.send(DashboardMessage::Get(name.0.clone())).await??)
+        .pop()
+        .ok_or_else(|| ServiceError::not_found("dashboard", &name.0))?;
+    let mut groups = group.send(GroupMessage::List).await??;
+    let groups = dashboard
+        .groups()
+        .iter()
+        .filter_map(|name| {
+            let index = groups.iter().position(|group| group.name().eq(name))?;
 
-    let group = match group.send(GroupMessage::Get(name.0)).await {
-        Ok(group) => group,
-        Err(error) => {
-            eprintln!("[ERROR] [Web Selver] {:?}", error);
+            Some(groups.swap_remove(index))
+        })
+        .collect();
 
-            return HttpResponse::Ok().json(Value::Null);
-        }
-    };
+    Ok(HttpResponse::Ok().json(DashboardView { dashboard, groups }))
+}
+
+async fn api_group_list(
+    req: HttpRequest,
+    group: Data<Addr<GroupService>>,
+    dashboard: Data<Addr<DashboardService>>,
+) -> Result<HttpResponse, ServiceError> {
+    let caller = caller(&req)?;
+    let groups = group.send(GroupMessage::List).await??;
+
+    if caller.can_edit() {
+        return Ok(HttpResponse::Ok().json(groups));
+    }
+
+    let dashboards = visible_dashboards(&caller, dashboard.send(DashboardMessage::List).await??);
+    let groups: Vec<GroupData> = groups
+        .into_iter()
+        .filter(|group| dashboards.iter().any(|dashboard| dashboard.has_group(group.name())))
+        .collect();
 
-    HttpResponse::Ok().json(group)
+    Ok(HttpResponse::Ok().json(groups))
 }
 
-async fn api_group_post(name: Path<String>, body: Json<GroupData>, group: Data<Addr<GroupService>>) -> impl Responder {
+async fn api_group_get(
+    req: HttpRequest,
+    name: Path<String>,
+    group: Data<Addr<GroupService>>,
+    dashboard: Data<Addr<DashboardService>>,
+) -> Result<HttpResponse, ServiceError> {
     let name = Path(percent_encoding::percent_decode_str(name.0.as_str()).decode_utf8_lossy().to_string());
 
-    let group = match group.send(GroupMessage::Set(name.0, body.0)).await {
-        Ok(group) => group,
-        Err(error) => {
-            eprintln!("[ERROR] [Web Server] {:?}", error);
+    if !can_see_group(&caller(&req)?, &name.0, &dashboard).await? {
+        return Err(ServiceError::Forbidden);
+    }
 
-            return HttpResponse::Ok().json(Value::Null);
-        }
+    let groups = group.send(GroupMessage::Get(name.0)).await??;
+
+    Ok(group_response(&req, groups))
+}
+
+async fn api_group_post(req: HttpRequest, name: Path<String>, body: Json<GroupData>, group: Data<Addr<GroupService>>) -> Result<HttpResponse, ServiceError> {
+    let name = Path(percent_encoding::percent_decode_str(name.0.as_str()).decode_utf8_lossy().to_string());
+
+    if !caller(&req)?.can_edit() {
+        return Err(ServiceError::Forbidden);
+    }
+
+    let groups = group.send(edit(&req, GroupMessage::Set(name.0, body.0))).await??;
+
+    Ok(changed_response(groups.first().map(GroupData::revision), groups.first()))
+}
+
+async fn api_group_patch(req: HttpRequest, name: Path<String>, body: Bytes, group: Data<Addr<GroupService>>) -> Result<HttpResponse, ServiceError> {
+    let name = Path(percent_encoding::percent_decode_str(name.0.as_str()).decode_utf8_lossy().to_string());
+
+    if !caller(&req)?.can_edit() {
+        return Err(ServiceError::Forbidden);
+    }
+
+    let change = Change::parse(req.content_type(), &body)?;
+    let groups = group.send(edit(&req, GroupMessage::Patch(name.0, change))).await??;
+
+    Ok(changed_response(groups.first().map(GroupData::revision), groups.first()))
+}
+
+/// The group and item of the item routes
+fn item_path(path: Path<(String, String)>) -> (String, String) {
+    let (name, item) = path.into_inner();
+
+    (
+        percent_encoding::percent_decode_str(name.as_str()).decode_utf8_lossy().to_string(),
+        percent_encoding::percent_decode_str(item.as_str()).decode_utf8_lossy().to_string(),
+    )
+}
+
+async fn api_group_item_get(
+    req: HttpRequest,
+    path: Path<(String, String)>,
+    group: Data<Addr<GroupService>>,
+    dashboard: Data<Addr<DashboardService>>,
+) -> Result<HttpResponse, ServiceError> {
+    let (name, item) = item_path(path);
+
+    if !can_see_group(&caller(&req)?, &name, &dashboard).await? {
+        return Err(ServiceError::Forbidden);
+    }
+
+    let groups = group.send(GroupMessage::Get(name)).await??;
+
+    // The item has the revision of its group, as changes of the items are checked against it
+    match groups.first().and_then(|group| Some((group.revision(), group.item(&item)?))) {
+        Some((revision, item)) => Ok(entry_response(&req, revision, item)),
+        None => Err(ServiceError::not_found("item", item)),
+    }
+}
+
+async fn api_group_item_post(
+    req: HttpRequest,
+    path: Path<(String, String)>,
+    body: Json<GroupItemData>,
+    group: Data<Addr<GroupService>>,
+) -> Result<HttpResponse, ServiceError> {
+    let (name, item) = item_path(path);
+
+    if !caller(&req)?.can_edit() {
+        return Err(ServiceError::Forbidden);
+    }
+
+    let groups = group.send(edit(&req, GroupMessage::SetItem(name, item, body.0))).await??;
+
+    Ok(changed_response(groups.first().map(GroupData::revision), groups.first()))
+}
+
+async fn api_group_item_delete(req: HttpRequest, path: Path<(String, String)>, group: Data<Addr<GroupService>>) -> Result<HttpResponse, ServiceError> {
+    let (name, item) = item_path(path);
+
+    if !caller(&req)?.can_edit() {
+        return Err(ServiceError::Forbidden);
+    }
+
+    let groups = group.send(edit(&req, GroupMessage::DeleteItem(name, item))).await??;
+
+    Ok(changed_response(groups.first().map(GroupData::revision), groups.first()))
+}
+
+/// The body of the item move, with the new position of the item (starting at 0)
+#[derive(Deserialize)]
+struct ItemMoveBody {
+    position: usize,
+}
+
+async fn api_group_item_move(
+    req: HttpRequest,
+    path: Path<(String, String)>,
+    body: Json<ItemMoveBody>,
+    group: Data<Addr<GroupService>>,
+) -> Result<HttpResponse, ServiceError> {
+    let (name, item) = item_path(path);
+
+    if !caller(&req)?.can_edit() {
+        return Err(ServiceError::Forbidden);
+    }
+
+    let groups = group.send(edit(&req, GroupMessage::MoveItem(name, item, body.position))).await??;
+
+    Ok(changed_response(groups.first().map(GroupData::revision), groups.first()))
+}
+
+/// The query of the group deletion. With `?force=true` the group is also removed from all
+/// dashboards containing it.
+#[derive(Deserialize)]
+struct GroupDeleteQuery {
+    #[serde(default)]
+    force: bool,
+}
+
+async fn api_group_delete(
+    req: HttpRequest,
+    name: Path<String>,
+    query: Query<GroupDeleteQuery>,
+    group: Data<Addr<GroupService>>,
+) -> Result<HttpResponse, ServiceError> {
+    let name = Path(percent_encoding::percent_decode_str(name.0.as_str()).decode_utf8_lossy().to_string());
+
+    if !caller(&req)?.can_edit() {
+        return Err(ServiceError::Forbidden);
+    }
+
+    let message = if query.force {
+        GroupMessage::ForceDelete(name.0)
+    } else {
+        GroupMessage::Delete(name.0)
     };
+    let groups = group.send(edit(&req, message)).await??;
+
+    Ok(HttpResponse::Ok().json(groups.first()))
+}
+
+async fn api_group_usage(req: HttpRequest, name: Path<String>, group: Data<Addr<GroupService>>) -> Result<HttpResponse, ServiceError> {
+    let name = Path(percent_encoding::percent_decode_str(name.0.as_str()).decode_utf8_lossy().to_string());
 
-    HttpResponse::Ok().json(group)
+    if !caller(&req)?.can_edit() {
+        return Err(ServiceError::Forbidden);
+    }
+
+    let used_by = group.send(GroupUsage(name.0.clone())).await??;
+
+    Ok(HttpResponse::Ok().json(json!({ "name": name.0, "used_by": used_by })))
+}
+
+async fn api_rule_list(req: HttpRequest, rules: Data<Addr<RuleService>>) -> Result<HttpResponse, ServiceError> {
+    if !caller(&req)?.can_edit() {
+        return Err(ServiceError::Forbidden);
+    }
+
+    let rules = rules.send(RuleMessage::List).await??;
+
+    Ok(HttpResponse::Ok().json(&rules))
 }
 
-async fn api_group_delete(name: Path<String>, group: Data<Addr<GroupService>>) -> impl Responder {
+async fn api_rule_get(req: HttpRequest, name: Path<String>, rules: Data<Addr<RuleService>>) -> Result<HttpResponse, ServiceError> {
     let name = Path(percent_encoding::percent_decode_str(name.0.as_str()).decode_utf8_lossy().to_string());
 
-    let group = match group.send(GroupMessage::Delete(name.0)).await {
-        Ok(group) => group,
-        Err(error) => {
-            eprintln!("[ERROR] [Web Server] {:?}", error);
+    if !caller(&req)?.can_edit() {
+        return Err(ServiceError::Forbidden);
+    }
+
+    let rules = rules.send(RuleMessage::Get(name.0)).await??;
+
+    Ok(HttpResponse::Ok().json(&rules))
+}
+
+async fn api_rule_post(req: HttpRequest, name: Path<String>, body: Json<RuleData>, rules: Data<Addr<RuleService>>) -> Result<HttpResponse, ServiceError> {
+    let name = Path(percent_encoding::percent_decode_str(name.0.as_str()).decode_utf8_lossy().to_string());
+
+    if !caller(&req)?.can_edit() {
+        return Err(ServiceError::Forbidden);
+    }
+
+    let rules = rules.send(RuleMessage::Set(name.0, body.0)).await??;
+
+    Ok(HttpResponse::Ok().json(&rules))
+}
+
+async fn api_rule_delete(req: HttpRequest, name: Path<String>, rules: Data<Addr<RuleService>>) -> Result<HttpResponse, ServiceError> {
+    let name = Path(percent_encoding::percent_decode_str(name.0.as_str()).decode_utf8_lossy().to_string());
+
+    if !caller(&req)?.can_edit() {
+        return Err(ServiceError::Forbidden);
+    }
+
+    let rules = rules.send(RuleMessage::Delete(name.0)).await??;
+
+    Ok(HttpResponse::Ok().json(&rules))
+}
+
+/// A user as it is shown in the API (without the password and token hashes)
+#[derive(Serialize)]
+struct UserView {
+    role: Role,
+    tokens: Vec<String>,
+}
+
+/// The body to create or change a user. The password is only required for new users.
+#[derive(Deserialize)]
+struct UserBody {
+    #[serde(default)]
+    password: Option<String>,
+    role: Role,
+}
+
+fn user_views(users: HashMap<String, UserData>) -> HashMap<String, UserView> {
+    users
+        .into_iter()
+        .map(|(name, user)| (name, UserView { role: user.role(), tokens: user.token_names() }))
+        .collect()
+}
+
+async fn api_user_list(req: HttpRequest, users: Data<Addr<UserService>>) -> Result<HttpResponse, ServiceError> {
+    if !caller(&req)?.is_admin() {
+        return Err(ServiceError::Forbidden);
+    }
+
+    let users = users.send(UserMessage::List).await??;
+
+    Ok(HttpResponse::Ok().json(user_views(users)))
+}
 
-            return HttpResponse::Ok().json(Value::Null);
+async fn api_user_post(req: HttpRequest, name: Path<String>, body: Json<UserBody>, users: Data<Addr<UserService>>) -> Result<HttpResponse, ServiceError> {
+    let name = Path(percent_encoding::percent_decode_str(name.0.as_str()).decode_utf8_lossy().to_string());
+
+    if !caller(&req)?.is_admin() {
+        return Err(ServiceError::Forbidden);
+    }
+
+    let UserBody { password, role } = body.0;
+    let existing = match users.send(UserMessage::Get(name.0.clone())).await? {
+        Ok(mut users) => users.remove(&name.0),
+        Err(ServiceError::NotFound { .. }) => None,
+        Err(error) => return Err(error),
+    };
+    let user = match (existing, password) {
+        (Some(user), Some(password)) => user.with_password(password).with_role(role),
+        (Some(user), None) => user.with_role(role),
+        (None, Some(password)) => UserData::new(password, role),
+        (None, None) => {
+            return Err(ServiceError::Invalid {
+                message: String::from("A new user needs a password."),
+                details: json!({ "field": "password" }),
+            })
         }
     };
 
-    HttpResponse::Ok().json(group)
+    let users = users.send(UserMessage::Set(name.0, user)).await??;
+
+    Ok(HttpResponse::Ok().json(user_views(users)))
 }
 
-async fn default_service(
-    req: HttpRequest,
-    body: Bytes,
-    client: Data<Client>,
-    mime_type_mapper: Data<MimeTypeMapper>,
-    settings: Data<AppSettings>,
-) -> Result<HttpResponse, Error> {
-    match &settings.server_type {
-        ServerType::Proxy(base_url) => default_proxy(req, body, client, base_url.clone()).await,
-        ServerType::File(public_path) => default_serve(req, public_path.clone(), mime_type_mapper).await,
+async fn api_user_delete(req: HttpRequest, name: Path<String>, users: Data<Addr<UserService>>) -> Result<HttpResponse, ServiceError> {
+    let name = Path(percent_encoding::percent_decode_str(name.0.as_str()).decode_utf8_lossy().to_string());
+
+    if !caller(&req)?.is_admin() {
+        return Err(ServiceError::Forbidden);
+    }
+
+    let users = users.send(UserMessage::Delete(name.0)).await??;
+
+    Ok(HttpResponse::Ok().json(user_views(users)))
+}
+
+async fn api_backup_list(req: HttpRequest, backup: Data<Addr<BackupService>>) -> Result<HttpResponse, ServiceError> {
+    if !caller(&req)?.is_admin() {
+        return Err(ServiceError::Forbidden);
+    }
+
+    let snapshots = backup.send(BackupMessage::List).await??;
+
+    Ok(HttpResponse::Ok().json(snapshots))
+}
+
+async fn api_backup_restore(req: HttpRequest, name: Path<String>, backup: Data<Addr<BackupService>>) -> Result<HttpResponse, ServiceError> {
+    let name = Path(percent_encoding::percent_decode_str(name.0.as_str()).decode_utf8_lossy().to_string());
+
+    if !caller(&req)?.is_admin() {
+        return Err(ServiceError::Forbidden);
     }
+
+    let snapshots = backup.send(BackupMessage::Restore(name.0)).await??;
+
+    Ok(HttpResponse::Ok().json(snapshots))
 }
 
-async fn default_proxy(
+async fn api_history_list(req: HttpRequest, query: Query<HistoryQuery>, history: Data<Addr<HistoryService>>) -> Result<HttpResponse, ServiceError> {
+    if !caller(&req)?.can_edit() {
+        return Err(ServiceError::Forbidden);
+    }
+
+    let entries = history.send(HistoryMessage::List(query.into_inner())).await??;
+
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+/// Restores the entry as it was before the change (see [revert])
+async fn api_history_revert(
     req: HttpRequest,
-    body: web::Bytes,
-    client: web::Data<Client>,
-    base_url: String,
-) -> Result<HttpResponse, Error> {
-    let base_url = base_url.trim_end_matches('/').to_string();
-    let full_uri = base_url + req.uri().to_string().as_str();
-    let request = client.request_from(full_uri, req.head()).no_decompress();
-    let mut response = request.send_body(body).await.map_err(Error::from)?;
-    let mut client_response = HttpResponse::build(response.status());
+    id: Path<u64>,
+    history: Data<Addr<HistoryService>>,
+    dashboard: Data<Addr<DashboardService>>,
+    group: Data<Addr<GroupService>>,
+    shortcuts: Data<Addr<ShortcutsService>>,
+) -> Result<HttpResponse, ServiceError> {
+    if !caller(&req)?.can_edit() {
+        return Err(ServiceError::Forbidden);
+    }
+
+    let entry = match history.send(HistoryMessage::Get(id.0)).await??.pop() {
+        Some(entry) => entry,
+        None => return Err(ServiceError::not_found("history entry", id.0)),
+    };
+
+    revert(entry.clone(), current_user(&req), &dashboard, &group, &shortcuts).await?;
+
+    Ok(HttpResponse::Ok().json(entry))
+}
+
+/// Request bodies which can not be parsed are answered with 422 and the parser error
+fn invalid_body(error: JsonPayloadError) -> ServiceError {
+    ServiceError::Invalid {
+        message: String::from("The request body is invalid."),
+        details: json!({ "error": error.to_string() }),
+    }
+}
+
+/// The `If-Match` of the request
+fn precondition(req: &HttpRequest) -> Precondition {
+    Precondition::parse(req.headers().get(IF_MATCH).and_then(|value| value.to_str().ok()))
+}
 
-    for (name, value) in response
+/// The change as [Edit] of the user with the `If-Match` of the request
+fn edit<M>(req: &HttpRequest, message: M) -> Edit<M> {
+    Edit {
+        user: current_user(req),
+        precondition: precondition(req),
+        message,
+    }
+}
+
+/// Answers the GET of a single entry with its ETag, or with 304 if the client already has this
+/// revision (`If-None-Match`)
+fn entry_response<T: Serialize>(req: &HttpRequest, revision: u64, body: T) -> HttpResponse {
+    let not_modified = req
         .headers()
+        .get(IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| !none_match(value, revision));
+
+    if not_modified {
+        return HttpResponse::NotModified().header(ETAG, etag(revision)).finish();
+    }
+
+    HttpResponse::Ok().header(ETAG, etag(revision)).json(body)
+}
+
+/// Answers a change with the ETag of the changed entry (if it still exists)
+fn changed_response<T: Serialize>(revision: Option<u64>, body: T) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+
+    if let Some(revision) = revision {
+        response.header(ETAG, etag(revision));
+    }
+
+    response.json(body)
+}
+
+fn group_response(req: &HttpRequest, groups: Vec<GroupData>) -> HttpResponse {
+    match groups.first() {
+        Some(group) => entry_response(req, group.revision(), group),
+        None => HttpResponse::Ok().json(Value::Null),
+    }
+}
+
+fn dashboard_revision(dashboards: &[DashboardData], name: &str) -> Option<u64> {
+    dashboards
+        .iter()
+        .find(|dashboard| dashboard.name().eq(name))
+        .map(DashboardData::revision)
+}
+
+/// The user the request was authenticated as (see [Authentication]). Unrestricted if the
+/// authentication is disabled. Requests without one never passed the authentication, so they are
+/// refused.
+fn caller(req: &HttpRequest) -> Result<AuthenticatedUser, ServiceError> {
+    req.extensions()
+        .get::<AuthenticatedUser>()
+        .cloned()
+        .ok_or(ServiceError::Unauthorized)
+}
+
+fn visible_dashboards(caller: &AuthenticatedUser, dashboards: Vec<DashboardData>) -> Vec<DashboardData> {
+    dashboards
+        .into_iter()
+        .filter(|dashboard| caller.can_see(dashboard.visible_to()))
+        .collect()
+}
+
+fn visible_shortcuts(caller: &AuthenticatedUser, shortcuts: HashMap<String, ShortcutEntry>) -> HashMap<String, ShortcutEntry> {
+    shortcuts
+        .into_iter()
+        .filter(|(_, shortcut)| caller.can_see(shortcut.visible_to()))
+        .collect()
+}
+
+/// Viewers can only see groups which are part of a dashboard visible to them
+async fn can_see_group(caller: &AuthenticatedUser, group: &str, dashboard: &Addr<DashboardService>) -> Result<bool, ServiceError> {
+    if caller.can_edit() {
+        return Ok(true);
+    }
+
+    let dashboards = dashboard.send(DashboardMessage::List).await??;
+
+    Ok(visible_dashboards(caller, dashboards)
         .iter()
-        .filter(|(h, _)| *h != "connection")
-    {
-        client_response.header(name.clone(), value.clone());
+        .any(|dashboard| dashboard.has_group(group)))
+}
+
+/// The body of the login request
+#[derive(Deserialize)]
+struct LoginBody {
+    username: String,
+    password: String,
+}
+
+/// The name of the authenticated user (or null if the authentication is disabled)
+fn current_user(req: &HttpRequest) -> Option<String> {
+    caller(req)
+        .ok()
+        .filter(|user| !user.is_unrestricted())
+        .map(|user| user.name)
+}
+
+async fn api_session_get(req: HttpRequest) -> Result<HttpResponse, ServiceError> {
+    let user = Some(caller(&req)?).filter(|user| !user.is_unrestricted());
+
+    Ok(HttpResponse::Ok().json(json!({
+        "username": user.as_ref().map(|user| user.name.clone()),
+        "role": user.map(|user| user.role),
+    })))
+}
+
+async fn api_session_post(body: Json<LoginBody>, users: Data<Addr<UserService>>, settings: Data<AppSettings>) -> Result<HttpResponse, ServiceError> {
+    let LoginBody { username, password } = body.0;
+    let session = users.send(SessionMessage::Login(username.clone(), password)).await??;
+
+    Ok(HttpResponse::Ok()
+        .cookie(
+            Cookie::build(SESSION_COOKIE, session)
+                .path("/")
+                .http_only(true)
+                .same_site(SameSite::Lax)
+                .max_age(time::Duration::seconds(settings.auth.session_lifetime as i64))
+                .finish(),
+        )
+        .json(json!({ "username": username })))
+}
+
+async fn api_session_delete(req: HttpRequest, users: Data<Addr<UserService>>) -> Result<HttpResponse, ServiceError> {
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        users.send(SessionMessage::Logout(String::from(cookie.value()))).await??;
     }
 
-    Ok(client_response.body(response.body().limit(10240000).await?))
+    Ok(HttpResponse::Ok()
+        .del_cookie(&Cookie::build(SESSION_COOKIE, "").path("/").finish())
+        .json(json!({ "username": Value::Null })))
+}
+
+async fn api_token_list(req: HttpRequest, users: Data<Addr<UserService>>) -> Result<HttpResponse, ServiceError> {
+    let name = match current_user(&req) {
+        Some(name) => name,
+        None => return Ok(HttpResponse::Ok().json(Vec::<String>::new())),
+    };
+    let users = users.send(UserMessage::Get(name.clone())).await??;
+    let tokens = users.get(&name).map(|user| user.token_names()).unwrap_or_default();
+
+    Ok(HttpResponse::Ok().json(tokens))
 }
 
-async fn default_serve(request: HttpRequest, public_path: String, mime_type_mapper: Data<MimeTypeMapper>) -> Result<HttpResponse, Error> {
-    let read_path = format!(
-        "{}/{}",
-        public_path.trim_end_matches("/"),
-        String::from(request.uri().path()).trim_start_matches("/")
-    );
-    let mime_type = mime_type_mapper.match_file(&read_path);
+/// API tokens belong to a user, so they are not available while the authentication is disabled
+fn token_user(req: &HttpRequest) -> Result<String, ServiceError> {
+    current_user(req).ok_or_else(|| ServiceError::invalid("API tokens require a logged in user."))
+}
+
+async fn api_token_post(req: HttpRequest, name: Path<String>, users: Data<Addr<UserService>>) -> Result<HttpResponse, ServiceError> {
+    let name = Path(percent_encoding::percent_decode_str(name.0.as_str()).decode_utf8_lossy().to_string());
+    let user = token_user(&req)?;
+    let token = users.send(SessionMessage::CreateToken(user, name.0.clone())).await??;
+
+    Ok(HttpResponse::Ok().json(json!({ "name": name.0, "token": token })))
+}
+
+async fn api_token_delete(req: HttpRequest, name: Path<String>, users: Data<Addr<UserService>>) -> Result<HttpResponse, ServiceError> {
+    let name = Path(percent_encoding::percent_decode_str(name.0.as_str()).decode_utf8_lossy().to_string());
+    let user = token_user(&req)?;
+
+    users.send(SessionMessage::DeleteToken(user, name.0)).await??;
 
-    match std::fs::read(read_path) {
-        Ok(content) => Ok(HttpResponse::Ok().header("Content-Type", mime_type).body(content)),
-        Err(error) => {
-            eprintln!("[ERROR] [WebServer]: Could not open file {:?}", error);
+    api_token_list(req, users).await
+}
 
-            Ok(HttpResponse::NotFound().body("File not found."))
+async fn default_service(
+    req: HttpRequest,
+    payload: Payload,
+    client: Data<Client>,
+    mime_type_mapper: Data<MimeTypeMapper>,
+    static_files: Data<StaticFiles>,
+    settings: Data<AppSettings>,
+) -> Result<HttpResponse, Error> {
+    match &settings.server_type {
+        ServerType::Proxy(base_url) => proxy(req, payload, client, base_url).await,
+        ServerType::File(public_path) => {
+            static_files::serve(req, config_path(public_path), mime_type_mapper, static_files).await
         }
     }
 }